@@ -0,0 +1,145 @@
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::prelude::*;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bevy_prototype_networking_laminar::{
+    parse_server_list, LaminarConfig, MasterRegistration, MasterServer, NetworkEvent,
+    NetworkEventReader, NetworkResource, NetworkingPlugin, SendConfig, ServerFilter, ServerInfo,
+    Transport,
+};
+
+const MASTER: &str = "127.0.0.1:12400";
+const SERVER: &str = "127.0.0.1:12401";
+const CLIENT: &str = "127.0.0.1:12402";
+
+// Run `cargo run --example master_server -- master`, then `-- server` and `-- client` in other
+// terminals. The client asks the master for the server list every few seconds.
+fn main() {
+    App::build()
+        .add_plugin(bevy::type_registry::TypeRegistryPlugin)
+        .add_plugin(bevy::core::CorePlugin)
+        .add_plugin(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+            1.0 / 60.0,
+        )))
+        .add_plugin(NetworkingPlugin)
+        .init_resource::<NetworkEventReader>()
+        .init_resource::<QueryTimer>()
+        .add_resource(parse_args())
+        .add_startup_system(startup_system.system())
+        .add_system(run_role.system())
+        .run();
+}
+
+enum Role {
+    Master(MasterServer),
+    Server(MasterRegistration),
+    Client,
+}
+
+fn parse_args() -> Role {
+    let master: SocketAddr = MASTER.parse().unwrap();
+
+    match std::env::args().nth(1).as_deref() {
+        Some("master") => Role::Master(MasterServer::new(None, Duration::from_secs(30))),
+        Some("server") => Role::Server(MasterRegistration::new(
+            master,
+            ServerInfo {
+                name: "Example server".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                players: 0,
+                max_players: 8,
+                ..Default::default()
+            },
+        )),
+        Some("client") => Role::Client,
+        _ => panic!("Run with `master`, `server` or `client`"),
+    }
+}
+
+fn startup_system(mut net: ResMut<NetworkResource>, role: Res<Role>) {
+    let addr = match *role {
+        Role::Master(_) => MASTER,
+        Role::Server(_) => SERVER,
+        Role::Client => CLIENT,
+    };
+
+    // the master only answers peers that have proven they can receive at their address
+    let laminar = LaminarConfig::builder()
+        .challenge_connections(true)
+        .build()
+        .unwrap();
+    net.bind_with_transport(addr, Transport::Laminar(laminar))
+        .unwrap();
+}
+
+struct QueryTimer(Timer);
+
+impl Default for QueryTimer {
+    fn default() -> Self {
+        QueryTimer(Timer::from_seconds(3.0, true))
+    }
+}
+
+fn run_role(
+    mut role: ResMut<Role>,
+    mut reader: ResMut<NetworkEventReader>,
+    mut timer: ResMut<QueryTimer>,
+    time: Res<Time>,
+    net: Res<NetworkResource>,
+    events: Res<Events<NetworkEvent>>,
+) {
+    let now = Instant::now();
+
+    match &mut *role {
+        Role::Master(master) => {
+            for event in reader.iter(&events) {
+                if let Err(e) = master.handle_event(&net, event, now) {
+                    println!("\tFailed to answer: {}", e);
+                }
+            }
+
+            let before = master.servers().len();
+            master.expire(now);
+            let after = master.servers().len();
+            if before != after {
+                println!("{} servers expired, {} are listed", before - after, after);
+            }
+        }
+        Role::Server(registration) => {
+            if let Err(e) = registration.heartbeat(&net, now) {
+                println!("\tFailed to heartbeat: {}", e);
+            }
+        }
+        Role::Client => {
+            timer.0.tick(time.delta_seconds);
+            if timer.0.finished {
+                let master: SocketAddr = MASTER.parse().unwrap();
+                let filter = ServerFilter {
+                    hide_full: true,
+                    ..Default::default()
+                };
+                net.query_master(master, &filter, SendConfig::default())
+                    .unwrap();
+
+                timer.0.reset();
+            }
+
+            for (_, msg) in reader.messages(&events) {
+                if let Some(servers) = parse_server_list(msg) {
+                    println!("{} servers:", servers.len());
+                    for server in servers {
+                        println!(
+                            "\t{} at {} ({}/{} players)",
+                            server.info.name,
+                            server.addr,
+                            server.info.players,
+                            server.info.max_players
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use bevy::prelude::*;
 
 use bevy_prototype_networking_laminar::{
-    NetworkDelivery, NetworkEvent, NetworkResource, NetworkingPlugin, SendConfig, SocketHandle,
+    BindConfig, NetworkDelivery, NetworkEvent, NetworkResource, NetworkingPlugin, SendConfig,
 };
 
 use std::net::SocketAddr;
@@ -12,7 +12,7 @@ const CLIENT: &str = "127.0.0.1:12350";
 
 fn main() {
     App::build()
-        .add_plugin(bevy::type_registry::TypeRegistryPlugin::default())
+        .add_plugin(bevy::type_registry::TypeRegistryPlugin)
         .add_plugin(bevy::core::CorePlugin)
         .add_plugin(bevy::app::ScheduleRunnerPlugin::run_loop(
             Duration::from_secs_f64(1.0 / 60.0),
@@ -20,33 +20,34 @@ fn main() {
         .add_plugin(NetworkingPlugin)
         .init_resource::<EventListenerState>()
         .init_resource::<SendTimerState>()
-        .init_resource::<Sockets>()
         .add_startup_system(startup.system())
         .add_system(print_messages.system())
         .add_system(send_messages.system())
         .run();
 }
 
-fn startup(mut net: ResMut<NetworkResource>, mut sockets: ResMut<Sockets>) {
-    sockets.server = Some(net.bind(SERVER).unwrap());
-    sockets.client = Some(net.bind(CLIENT).unwrap());
+fn startup(mut net: ResMut<NetworkResource>) {
+    net.bind_with_config(SERVER, labeled("server")).unwrap();
+    net.bind_with_config(CLIENT, labeled("client")).unwrap();
 }
 
-fn send_messages(
-    time: Res<Time>,
-    sockets: Res<Sockets>,
-    mut state: ResMut<SendTimerState>,
-    net: ResMut<NetworkResource>,
-) {
+fn labeled(label: &str) -> BindConfig {
+    BindConfig {
+        label: Some(label.to_string()),
+        ..Default::default()
+    }
+}
+
+fn send_messages(time: Res<Time>, mut state: ResMut<SendTimerState>, net: ResMut<NetworkResource>) {
     state.message_timer.tick(time.delta_seconds);
     if state.message_timer.finished {
         let server: SocketAddr = SERVER.parse().unwrap();
         let client: SocketAddr = CLIENT.parse().unwrap();
 
-        let (to, who, message, socket, from_server) = if state.from_server {
-            (client, "SERVER", "How are things?", sockets.server, false)
+        let (to, who, message, label, from_server) = if state.from_server {
+            (client, "SERVER", "How are things?", "server", false)
         } else {
-            (server, "CLIENT", "Good. Thanks!", sockets.client, true)
+            (server, "CLIENT", "Good. Thanks!", "client", true)
         };
 
         println!("[{}] ---> {:?}", who, message);
@@ -54,7 +55,7 @@ fn send_messages(
             to,
             message.as_bytes(),
             NetworkDelivery::ReliableSequenced(Some(1)),
-            SendConfig { socket },
+            SendConfig::for_label(label),
         );
         state.from_server = from_server;
 
@@ -64,11 +65,11 @@ fn send_messages(
 
 fn print_messages(
     mut state: ResMut<EventListenerState>,
-    sockets: Res<Sockets>,
+    net: Res<NetworkResource>,
     my_events: Res<Events<NetworkEvent>>,
 ) {
-    if let Some(server) = sockets.server {
-        if let Some(client) = sockets.client {
+    if let Some(server) = net.socket_by_label("server") {
+        if let Some(client) = net.socket_by_label("client") {
             for event in state.network_events.iter(&my_events) {
                 #[allow(clippy::single_match)]
                 match event {
@@ -85,7 +86,7 @@ fn print_messages(
 
                         println!("\t ---> [{}] {:?}\n", from, msg);
                     }
-                    NetworkEvent::Connected(conn) => println!("\t {} connected", conn),
+                    NetworkEvent::Connected(conn, _) => println!("\t {} connected", conn),
                     _ => {}
                 }
             }
@@ -93,12 +94,6 @@ fn print_messages(
     }
 }
 
-#[derive(Default)]
-struct Sockets {
-    server: Option<SocketHandle>,
-    client: Option<SocketHandle>,
-}
-
 #[derive(Default)]
 struct EventListenerState {
     network_events: EventReader<NetworkEvent>,
@@ -0,0 +1,103 @@
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::prelude::*;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bevy_prototype_networking_laminar::{
+    LaminarConfig, NetworkDelivery, NetworkEvent, NetworkEventReader, NetworkResource,
+    NetworkingPlugin, SendConfig, Transport,
+};
+
+const RENDEZVOUS: &str = "127.0.0.1:12410";
+const LOBBY: &[u8] = b"example lobby";
+
+// Run `cargo run --example rendezvous -- server`, then `-- peer 127.0.0.1:12411` and
+// `-- peer 127.0.0.1:12412` in other terminals. The peers find each other through the rendezvous
+// server, and greet each other once they've punched through.
+fn main() {
+    App::build()
+        .add_plugin(bevy::type_registry::TypeRegistryPlugin)
+        .add_plugin(bevy::core::CorePlugin)
+        .add_plugin(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+            1.0 / 60.0,
+        )))
+        .add_plugin(NetworkingPlugin)
+        .init_resource::<NetworkEventReader>()
+        .add_resource(parse_args())
+        .add_startup_system(startup_system.system())
+        .add_system(print_network_events.system())
+        .run();
+}
+
+enum Role {
+    Rendezvous,
+    Peer(SocketAddr),
+}
+
+fn parse_args() -> Role {
+    let args: Vec<String> = std::env::args().collect();
+
+    match (args.get(1).map(|s| s.as_str()), args.get(2)) {
+        (Some("server"), None) => Role::Rendezvous,
+        (Some("peer"), Some(addr)) => Role::Peer(addr.parse().expect("not an address")),
+        _ => panic!("Run with `server`, or `peer <address to bind>`"),
+    }
+}
+
+fn startup_system(mut net: ResMut<NetworkResource>, role: Res<Role>) {
+    let rendezvous: SocketAddr = RENDEZVOUS.parse().unwrap();
+
+    // the rendezvous server only introduces peers that have proven they can receive at their
+    // address
+    let laminar = LaminarConfig::builder()
+        .challenge_connections(true)
+        .build()
+        .unwrap();
+    let transport = Transport::Laminar(laminar);
+
+    match *role {
+        Role::Rendezvous => {
+            let socket = net.bind_with_transport(rendezvous, transport).unwrap();
+            net.serve_rendezvous(socket).unwrap();
+        }
+        Role::Peer(addr) => {
+            net.bind_with_transport(addr, transport).unwrap();
+            net.punch(
+                rendezvous,
+                LOBBY,
+                Duration::from_secs(10),
+                SendConfig::default(),
+            )
+            .unwrap();
+        }
+    }
+}
+
+fn print_network_events(
+    mut reader: ResMut<NetworkEventReader>,
+    net: Res<NetworkResource>,
+    events: Res<Events<NetworkEvent>>,
+) {
+    for event in reader.iter(&events) {
+        match event {
+            NetworkEvent::PunchSucceeded(conn) => {
+                println!("\tPunched through to {}", conn);
+                net.send(
+                    conn.addr,
+                    b"Hello, peer!",
+                    NetworkDelivery::ReliableUnordered,
+                )
+                .unwrap();
+            }
+            NetworkEvent::PunchFailed { peer, .. } => match peer {
+                Some(peer) => println!("\tCouldn't reach {}", peer),
+                None => println!("\tThe rendezvous server didn't find a peer"),
+            },
+            NetworkEvent::Message(conn, msg) => {
+                println!("<--- {:?} from {}", String::from_utf8_lossy(msg), conn)
+            }
+            _ => {}
+        }
+    }
+}
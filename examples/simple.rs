@@ -5,7 +5,7 @@ use std::net::SocketAddr;
 use std::time::Duration;
 
 use bevy_prototype_networking_laminar::{
-    NetworkDelivery, NetworkEvent, NetworkResource, NetworkingPlugin,
+    NetworkDelivery, NetworkEvent, NetworkEventReader, NetworkResource, NetworkingPlugin,
 };
 
 const SERVER: &str = "127.0.0.1:12351";
@@ -14,7 +14,7 @@ const CLIENT: &str = "127.0.0.1:12350";
 fn main() {
     App::build()
         // minimal plugins necessary for timers + headless loop
-        .add_plugin(bevy::type_registry::TypeRegistryPlugin::default())
+        .add_plugin(bevy::type_registry::TypeRegistryPlugin)
         .add_plugin(bevy::core::CorePlugin)
         .add_plugin(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
             1.0 / 60.0,
@@ -31,21 +31,48 @@ fn main() {
         .run();
 }
 
-#[derive(Default)]
-struct NetworkEventReader {
-    network_events: EventReader<NetworkEvent>,
-}
-
 fn print_network_events(mut state: ResMut<NetworkEventReader>, events: Res<Events<NetworkEvent>>) {
-    for event in state.network_events.iter(&events) {
+    for event in state.iter(&events) {
         match event {
             NetworkEvent::Message(conn, data) => {
                 let msg = String::from_utf8_lossy(data);
                 println!("<--- {:?} from {}", msg, conn);
             }
-            NetworkEvent::Connected(conn) => println!("\tConnected: {}", conn),
+            NetworkEvent::Connected(conn, _) => println!("\tConnected: {}", conn),
             NetworkEvent::Disconnected(conn) => println!("\tDisconnected: {}", conn),
-            NetworkEvent::SendError(err) => println!("\tSend Error: {}", err),
+            NetworkEvent::Reconnected { old, new } => println!("\tReconnected: {} -> {}", old, new),
+            NetworkEvent::Reconnecting {
+                connection,
+                attempt,
+            } => println!("\tReconnecting to {}: attempt {}", connection, attempt),
+            NetworkEvent::SendFailed { id, error, .. } => {
+                println!("\tSend Failed: {:?} {}", id, error)
+            }
+            NetworkEvent::SocketError(_, err) => println!("\tSocket Error: {}", err),
+            NetworkEvent::ConfigFailed(err) => println!("\tConfig Failed: {}", err),
+            NetworkEvent::Reconfigured(_, limits) => println!("\tReconfigured: {:?}", limits),
+            NetworkEvent::SocketClosed(_) => println!("\tSocket Closed"),
+            NetworkEvent::RateLimited(conn) => println!("\tRate Limited: {}", conn),
+            NetworkEvent::TransferProgress {
+                connection,
+                received,
+                total,
+                ..
+            } => println!("\tTransfer from {}: {}/{}", connection, received, total),
+            NetworkEvent::ServerDiscovered { addr, info } => {
+                println!("\tDiscovered {}: {:?}", addr, String::from_utf8_lossy(info))
+            }
+            NetworkEvent::PunchSucceeded(conn) => println!("\tPunched through to {}", conn),
+            NetworkEvent::PunchFailed { rendezvous, .. } => {
+                println!("\tPunch-through via {} failed", rendezvous)
+            }
+            NetworkEvent::RelayFailed { relay, .. } => println!("\tRelay via {} failed", relay),
+            NetworkEvent::Delivered { id, connection } => {
+                println!("\tDelivered {:?} to {}", id, connection)
+            }
+            NetworkEvent::Lost { id, connection } => {
+                println!("\tLost {:?} to {}", id, connection)
+            }
         }
     }
 }
@@ -97,14 +124,14 @@ fn send_messages(
         println!("---> {:?}", msg);
         if ci.is_server() {
             net.broadcast(msg.as_bytes(), NetworkDelivery::ReliableSequenced(Some(1)))
-                .unwrap()
+                .unwrap();
         } else {
             net.send(
                 server,
                 msg.as_bytes(),
                 NetworkDelivery::ReliableSequenced(Some(1)),
             )
-            .unwrap()
+            .unwrap();
         }
 
         state.message_timer.reset();
@@ -118,17 +145,11 @@ pub enum ConnectionInfo {
 
 impl ConnectionInfo {
     pub fn is_server(&self) -> bool {
-        match &self {
-            ConnectionInfo::Server => true,
-            _ => false,
-        }
+        matches!(self, ConnectionInfo::Server)
     }
 
     pub fn is_client(&self) -> bool {
-        match &self {
-            ConnectionInfo::Client => true,
-            _ => false,
-        }
+        matches!(self, ConnectionInfo::Client)
     }
 }
 
@@ -112,8 +112,8 @@ fn move_cube_system(
 
     for (_cube, mut tx) in &mut cubes.iter() {
         let mut pos = tx.0 + delta;
-        pos.set_x(pos.x().max(-4.0).min(4.0));
-        pos.set_z(pos.z().max(-4.0).min(4.0));
+        pos.set_x(pos.x().clamp(-4.0, 4.0));
+        pos.set_z(pos.z().clamp(-4.0, 4.0));
         tx.0 = pos;
     }
 }
@@ -122,7 +122,7 @@ fn note_compact_system(mut notes: Query<&mut Note>) {
     let mut i = notes.iter();
     let mut sorted_displays: Vec<Mut<Note>> = i.into_iter().collect();
 
-    sorted_displays.sort_by(|a, b| a.ordinal.cmp(&b.ordinal));
+    sorted_displays.sort_by_key(|a| a.ordinal);
 
     let mut needs_compact = false;
 
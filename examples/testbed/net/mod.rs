@@ -141,17 +141,11 @@ fn handle_sync_notes_events(
 
 impl ConnectionInfo {
     pub fn is_server(&self) -> bool {
-        match &self {
-            ConnectionInfo::Server { .. } => true,
-            _ => false,
-        }
+        matches!(self, ConnectionInfo::Server { .. })
     }
 
     pub fn is_client(&self) -> bool {
-        match &self {
-            ConnectionInfo::Client { .. } => true,
-            _ => false,
-        }
+        matches!(self, ConnectionInfo::Client { .. })
     }
 }
 
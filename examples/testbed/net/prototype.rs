@@ -1,7 +1,8 @@
 use bevy::prelude::*;
 
 use bevy_prototype_networking_laminar::{
-    Connection, NetworkDelivery, NetworkEvent, NetworkResource,
+    Connection, NetworkDelivery, NetworkEvent, NetworkResource, NetworkTick, RetryPolicy,
+    SendConfig,
 };
 
 use serde::{Deserialize, Serialize};
@@ -38,7 +39,7 @@ enum TestbedMessage {
 fn initial_connection_system(ci: Res<ConnectionInfo>, net: ResMut<NetworkResource>) {
     match &(*ci) {
         ConnectionInfo::Server { addr } => start_server(*addr, net),
-        ConnectionInfo::Client { name, addr, server } => start_client(&name, *addr, *server, net),
+        ConnectionInfo::Client { name, addr, server } => start_client(name, *addr, *server, net),
     }
 }
 
@@ -57,13 +58,13 @@ fn send_note_update_system(
     }
 }
 
-// todo: separate network tick rate from game tick rate
 fn send_cube_position_system(
     ci: Res<ConnectionInfo>,
     net: Res<NetworkResource>,
+    tick: Res<NetworkTick>,
     mut query: Query<(&Cube, &Translation)>,
 ) {
-    if ci.is_client() {
+    if ci.is_client() || !tick.ticked() {
         return;
     }
 
@@ -242,10 +243,13 @@ fn start_client(
 ) {
     net.bind(addr).expect("We failed to bind to the socket.");
 
-    net.send(
+    // the introduction is sent again if the server goes away for a while
+    net.connect_with_retry(
         server_addr,
         &TestbedMessage::Introduction(name.to_string()).encode()[..],
         NetworkDelivery::ReliableSequenced(Some(1)),
+        RetryPolicy::default(),
+        SendConfig::default(),
     )
     .expect("We failed to send our introduction message");
 }
@@ -264,7 +268,7 @@ impl TestbedMessage {
     pub fn decode(bytes: &[u8]) -> TestbedMessage {
         if SERIALIZE_JSON {
             let encoded_json = std::str::from_utf8(bytes).unwrap();
-            serde_json::from_str(&encoded_json).unwrap()
+            serde_json::from_str(encoded_json).unwrap()
         } else {
             bincode::deserialize(bytes).unwrap()
         }
@@ -253,7 +253,7 @@ fn note_display_ordering_system(
         let mut sorted_displays: Vec<(Entity, Mut<NoteDisplay>)> =
             display_borrow.into_iter().collect();
 
-        sorted_displays.sort_by(|(_, a), (_, b)| b.ordinal.cmp(&a.ordinal));
+        sorted_displays.sort_by_key(|(_, a)| std::cmp::Reverse(a.ordinal));
 
         let sorted_entities: Vec<Entity> = sorted_displays.iter().map(|(e, _)| *e).collect();
         container_children.0 = SmallVec::from_slice(&sorted_entities[..]);
@@ -0,0 +1,217 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use super::protocol::{self, COOKIE_SIZE};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// how long a cookie can be answered for
+const COOKIE_LIFETIME: Duration = Duration::from_secs(10);
+// how often a client repeats its request or response until the server accepts it
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+// Proves that a peer can receive at the address it sends from, before the worker keeps any state
+// for it.
+//
+// The peer that sends first asks for a challenge, and the other end replies with a cookie: a
+// timestamp and a MAC over the timestamp and the peer's address, which the peer has to send back.
+// Only the MAC secret is needed to check a cookie, so nothing is stored for an address until it
+// has answered. A request is padded to the size of the challenge, so a spoofed request can't be
+// used to send anyone more than the attacker sent.
+pub(crate) struct Challenger {
+    secret: [u8; 32],
+    verified: HashSet<SocketAddr>,
+    // the addresses we've asked for a challenge, and the cookie they sent back
+    pending: HashMap<SocketAddr, Pending>,
+}
+
+struct Pending {
+    sent_at: Option<Instant>,
+    cookie: Option<[u8; COOKIE_SIZE]>,
+}
+
+impl Challenger {
+    pub fn new() -> Self {
+        let mut secret = [0; 32];
+        secret[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+        secret[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+
+        Challenger {
+            secret,
+            verified: HashSet::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn is_verified(&self, addr: SocketAddr) -> bool {
+        self.verified.contains(&addr)
+    }
+
+    /// The request, or the answer to the peer's challenge, if it's time to send it again
+    pub fn request(&mut self, addr: SocketAddr, now: Instant) -> Option<Vec<u8>> {
+        let pending = self.pending.entry(addr).or_insert(Pending {
+            sent_at: None,
+            cookie: None,
+        });
+
+        if let Some(sent_at) = pending.sent_at {
+            if now.saturating_duration_since(sent_at) < RETRY_INTERVAL {
+                return None;
+            }
+        }
+        pending.sent_at = Some(now);
+
+        let frame = match pending.cookie {
+            Some(cookie) => protocol::encode_challenge_response(&cookie),
+            None => protocol::encode_challenge_request(),
+        };
+
+        Some(frame.to_vec())
+    }
+
+    /// The challenge for a peer that asked for one
+    pub fn challenge(&self, addr: SocketAddr, now: SystemTime) -> Vec<u8> {
+        protocol::encode_challenge(&self.cookie(addr, timestamp(now))).to_vec()
+    }
+
+    /// Answers a challenge from a peer we asked for one
+    pub fn answer(&mut self, addr: SocketAddr, cookie: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let pending = self.pending.get_mut(&addr)?;
+        let cookie: [u8; COOKIE_SIZE] = cookie.try_into().ok()?;
+
+        pending.cookie = Some(cookie);
+        pending.sent_at = Some(now);
+
+        Some(protocol::encode_challenge_response(&cookie).to_vec())
+    }
+
+    /// Checks a peer's answer to our challenge. Returns true if the peer has just been verified.
+    pub fn verify(&mut self, addr: SocketAddr, cookie: &[u8], now: SystemTime) -> Option<bool> {
+        let cookie: [u8; COOKIE_SIZE] = cookie.try_into().ok()?;
+        let issued = u64::from_be_bytes(cookie[..8].try_into().ok()?);
+        let now = timestamp(now);
+        if issued > now || now - issued > COOKIE_LIFETIME.as_secs() {
+            return None;
+        }
+
+        // compared in constant time, so the MAC can't be guessed a byte at a time
+        let expected = self.cookie(addr, issued);
+        let difference = expected
+            .iter()
+            .zip(cookie.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if difference != 0 {
+            return None;
+        }
+
+        self.pending.remove(&addr);
+        Some(self.verified.insert(addr))
+    }
+
+    /// The peer accepted our answer. It echoes the cookie we answered with, which only the peer
+    /// and whoever can see our traffic know, so the acceptance can't be forged blind. Returns true
+    /// if the peer has just been verified.
+    pub fn accepted(&mut self, addr: SocketAddr, cookie: &[u8]) -> bool {
+        let answered = match self.pending.get(&addr).and_then(|p| p.cookie) {
+            Some(answered) => answered,
+            None => return false,
+        };
+        if answered[..] != *cookie {
+            return false;
+        }
+
+        self.pending.remove(&addr);
+        self.verified.insert(addr)
+    }
+
+    pub fn migrate(&mut self, old: SocketAddr, new: SocketAddr) {
+        if self.verified.remove(&old) {
+            self.verified.insert(new);
+        }
+    }
+
+    pub fn remove_connection(&mut self, addr: SocketAddr) {
+        self.verified.remove(&addr);
+        self.pending.remove(&addr);
+    }
+
+    fn cookie(&self, addr: SocketAddr, issued: u64) -> [u8; COOKIE_SIZE] {
+        let tag = self.mac(addr, issued).finalize().into_bytes();
+
+        let mut cookie = [0; COOKIE_SIZE];
+        cookie[..8].copy_from_slice(&issued.to_be_bytes());
+        cookie[8..].copy_from_slice(&tag[..COOKIE_SIZE - 8]);
+        cookie
+    }
+
+    fn mac(&self, addr: SocketAddr, issued: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(&self.secret).expect("HMAC accepts keys of any size");
+        match addr.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&addr.port().to_be_bytes());
+        mac.update(&issued.to_be_bytes());
+        mac
+    }
+}
+
+fn timestamp(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Frame;
+
+    #[test]
+    fn only_the_challenged_address_can_answer() {
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let spoofed = SocketAddr::from(([127, 0, 0, 1], 5001));
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let (now, wall) = (Instant::now(), SystemTime::now());
+
+        let mut client = Challenger::new();
+        let mut server = Challenger::new();
+
+        let request = client.request(server_addr, now).unwrap();
+        assert!(client.request(server_addr, now).is_none());
+
+        let challenge = server.challenge(client_addr, wall);
+        assert!(challenge.len() <= request.len());
+        let cookie = match protocol::decode(&challenge) {
+            Some(Frame::Challenge(cookie)) => cookie.to_vec(),
+            other => panic!("expected a challenge, got {:?}", other),
+        };
+
+        // a cookie only works for the address it was sent to, and only for a while
+        assert_eq!(server.verify(spoofed, &cookie, wall), None);
+        let later = wall + COOKIE_LIFETIME + Duration::from_secs(1);
+        assert_eq!(server.verify(client_addr, &cookie, later), None);
+        let mut forged = cookie.clone();
+        forged[9] ^= 1;
+        assert_eq!(server.verify(client_addr, &forged, wall), None);
+        assert!(!server.is_verified(client_addr));
+
+        // an unsolicited challenge isn't answered
+        assert!(client.answer(spoofed, &cookie, now).is_none());
+        assert!(client.answer(server_addr, &cookie, now).is_some());
+
+        assert_eq!(server.verify(client_addr, &cookie, wall), Some(true));
+        assert_eq!(server.verify(client_addr, &cookie, wall), Some(false));
+        assert!(server.is_verified(client_addr));
+
+        assert!(!client.accepted(spoofed, &cookie));
+        assert!(!client.accepted(server_addr, &[0; COOKIE_SIZE]));
+        assert!(client.accepted(server_addr, &cookie));
+        assert!(client.is_verified(server_addr));
+    }
+}
@@ -0,0 +1,227 @@
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::protocol::{self, ChunkHeader};
+
+// how many transfers a connection can have in flight at once
+const MAX_TRANSFERS_PER_CONNECTION: usize = 4;
+// how long a transfer can go without a chunk before it's dropped
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Identifies a large message sent with `NetworkResource::send_large`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TransferId(pub(crate) u32);
+
+pub(crate) fn split(transfer: TransferId, message: &[u8], chunk_size: usize) -> Vec<Bytes> {
+    let count = message.len().div_ceil(chunk_size).max(1);
+
+    (0..count)
+        .map(|index| {
+            let start = index * chunk_size;
+            let end = (start + chunk_size).min(message.len());
+
+            let header = ChunkHeader {
+                transfer: transfer.0,
+                index: index as u32,
+                count: count as u32,
+                total_len: message.len() as u32,
+            };
+
+            protocol::encode_chunk(header, &message[start..end])
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Reassembly {
+    Progress { received: usize, total: usize },
+    Complete(Bytes),
+    Rejected,
+}
+
+pub(crate) struct Reassembler {
+    max_transfer_size: usize,
+    transfers: HashMap<(SocketAddr, u32), Transfer>,
+}
+
+struct Transfer {
+    count: u32,
+    total_len: usize,
+    next_index: u32,
+    buffer: BytesMut,
+    last_chunk_at: Instant,
+}
+
+impl Reassembler {
+    pub fn new(max_transfer_size: usize) -> Self {
+        Reassembler {
+            max_transfer_size,
+            transfers: HashMap::new(),
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        addr: SocketAddr,
+        header: ChunkHeader,
+        data: &[u8],
+        now: Instant,
+    ) -> Reassembly {
+        let total_len = header.total_len as usize;
+
+        if total_len > self.max_transfer_size {
+            return Reassembly::Rejected;
+        }
+
+        let key = (addr, header.transfer);
+        if !self.transfers.contains_key(&key) {
+            let in_flight = self.transfers.keys().filter(|(a, _)| *a == addr).count();
+            if in_flight >= MAX_TRANSFERS_PER_CONNECTION {
+                return Reassembly::Rejected;
+            }
+        }
+
+        // the buffer grows as chunks arrive, so a peer only gets to use as much memory as it
+        // actually sends, whatever length it claims
+        let transfer = self.transfers.entry(key).or_insert_with(|| Transfer {
+            count: header.count,
+            total_len,
+            next_index: 0,
+            buffer: BytesMut::new(),
+            last_chunk_at: now,
+        });
+
+        // chunks are sent on a reliable ordered stream, so anything out of sequence, or anything
+        // that disagrees with the first chunk, means the peer is misbehaving
+        let out_of_sequence = header.index != transfer.next_index
+            || header.count != transfer.count
+            || total_len != transfer.total_len
+            || transfer.buffer.len() + data.len() > transfer.total_len;
+
+        if out_of_sequence {
+            self.transfers.remove(&key);
+            return Reassembly::Rejected;
+        }
+
+        transfer.buffer.extend_from_slice(data);
+        transfer.next_index += 1;
+        transfer.last_chunk_at = now;
+
+        if transfer.next_index < transfer.count {
+            return Reassembly::Progress {
+                received: transfer.buffer.len(),
+                total: transfer.total_len,
+            };
+        }
+
+        let transfer = self.transfers.remove(&key).unwrap();
+        if transfer.buffer.len() != transfer.total_len {
+            return Reassembly::Rejected;
+        }
+
+        Reassembly::Complete(transfer.buffer.freeze())
+    }
+
+    pub fn set_max_transfer_size(&mut self, max_transfer_size: usize) {
+        self.max_transfer_size = max_transfer_size;
+    }
+
+    pub fn remove_connection(&mut self, addr: SocketAddr) {
+        self.transfers.retain(|(a, _), _| *a != addr);
+    }
+
+    /// Drops the transfers that have stalled
+    pub fn expire(&mut self, now: Instant) {
+        self.transfers
+            .retain(|_, t| now.saturating_duration_since(t.last_chunk_at) < TRANSFER_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::Frame;
+
+    fn reassemble(reassembler: &mut Reassembler, chunks: &[Bytes]) -> Vec<Reassembly> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+
+        chunks
+            .iter()
+            .map(|c| match protocol::decode(c) {
+                Some(Frame::Chunk(header, data)) => {
+                    reassembler.push(addr, header, data, Instant::now())
+                }
+                _ => panic!("expected a chunk frame"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_messages_reassemble() {
+        let message: Vec<u8> = (0..250u32).map(|i| i as u8).collect();
+        let chunks = split(TransferId(1), &message, 100);
+        assert_eq!(chunks.len(), 3);
+
+        let mut reassembler = Reassembler::new(1024);
+        let results = reassemble(&mut reassembler, &chunks);
+
+        assert_eq!(
+            results[0],
+            Reassembly::Progress {
+                received: 100,
+                total: 250
+            }
+        );
+        assert_eq!(results[2], Reassembly::Complete(Bytes::from(message)));
+    }
+
+    #[test]
+    fn oversized_transfers_are_rejected() {
+        let chunks = split(TransferId(1), &[0; 200], 100);
+
+        let mut reassembler = Reassembler::new(150);
+        let results = reassemble(&mut reassembler, &chunks);
+
+        assert_eq!(results[0], Reassembly::Rejected);
+    }
+
+    #[test]
+    fn stalled_and_excess_transfers_are_dropped() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(1 << 30);
+
+        // claiming a huge transfer doesn't reserve anything up front
+        let first = |transfer| ChunkHeader {
+            transfer,
+            index: 0,
+            count: 1 << 20,
+            total_len: 1 << 30,
+        };
+        for transfer in 0..MAX_TRANSFERS_PER_CONNECTION as u32 {
+            assert!(matches!(
+                reassembler.push(addr, first(transfer), b"data", now),
+                Reassembly::Progress { received: 4, .. }
+            ));
+        }
+        assert!(reassembler
+            .transfers
+            .values()
+            .all(|t| t.buffer.capacity() < 1024));
+
+        let excess = MAX_TRANSFERS_PER_CONNECTION as u32;
+        assert_eq!(
+            reassembler.push(addr, first(excess), b"data", now),
+            Reassembly::Rejected
+        );
+
+        reassembler.expire(now + TRANSFER_TIMEOUT);
+        assert!(reassembler.transfers.is_empty());
+        assert!(matches!(
+            reassembler.push(addr, first(excess), b"data", now + TRANSFER_TIMEOUT),
+            Reassembly::Progress { .. }
+        ));
+    }
+}
@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+#[cfg(feature = "lz4")]
+use std::convert::TryInto;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::protocol::FLAG_COMPRESSED;
+
+#[cfg(feature = "lz4")]
+const CODEC_LZ4: u8 = 1;
+#[cfg(feature = "zstd")]
+const CODEC_ZSTD: u8 = 2;
+
+// the flagged kind byte, followed by the codec
+const COMPRESSED_HEADER_SIZE: usize = 2;
+
+/// The codec used to compress outgoing packets on a socket. The codecs are enabled with the `lz4`
+/// and `zstd` cargo features. A socket can decompress packets from any codec that is compiled in,
+/// whatever it's configured to send with.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4,
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    /// Parses `none`, `lz4`, `zstd`, or `zstd:<level>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+
+        match (parts.next(), parts.next()) {
+            (Some("none"), None) => Ok(Compression::None),
+            #[cfg(feature = "lz4")]
+            (Some("lz4"), None) => Ok(Compression::Lz4),
+            #[cfg(feature = "zstd")]
+            (Some("zstd"), None) => Ok(Compression::Zstd { level: 3 }),
+            #[cfg(feature = "zstd")]
+            (Some("zstd"), Some(level)) => level
+                .parse()
+                .map(|level| Compression::Zstd { level })
+                .map_err(|_| ()),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Outgoing compression on a socket since it was bound
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct CompressionStats {
+    pub packets_compressed: u64,
+    /// Packets sent raw, because they were under the threshold or didn't get any smaller
+    pub packets_uncompressed: u64,
+    /// The size of the compressed packets before compression
+    pub bytes_before: u64,
+    /// The size of the compressed packets after compression
+    pub bytes_after: u64,
+}
+
+impl CompressionStats {
+    /// The compressed size as a fraction of the original size, for the packets that were
+    /// compressed. Lower is better.
+    pub fn ratio(&self) -> Option<f64> {
+        match self.bytes_before {
+            0 => None,
+            before => Some(self.bytes_after as f64 / before as f64),
+        }
+    }
+}
+
+// written by the worker and read by `NetworkResource::compression_stats`
+#[derive(Default)]
+pub(crate) struct SharedCompressionStats {
+    packets_compressed: AtomicU64,
+    packets_uncompressed: AtomicU64,
+    bytes_before: AtomicU64,
+    bytes_after: AtomicU64,
+}
+
+impl SharedCompressionStats {
+    pub fn snapshot(&self) -> CompressionStats {
+        CompressionStats {
+            packets_compressed: self.packets_compressed.load(Ordering::Relaxed),
+            packets_uncompressed: self.packets_uncompressed.load(Ordering::Relaxed),
+            bytes_before: self.bytes_before.load(Ordering::Relaxed),
+            bytes_after: self.bytes_after.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_compressed(&self, before: usize, after: usize) {
+        self.packets_compressed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_before
+            .fetch_add(before as u64, Ordering::Relaxed);
+        self.bytes_after.fetch_add(after as u64, Ordering::Relaxed);
+    }
+
+    fn record_uncompressed(&self) {
+        self.packets_uncompressed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Compresses everything after the frame's kind byte, unless the payload is under the threshold
+/// or compressing it doesn't save anything
+pub(crate) fn compress(
+    compression: Compression,
+    threshold: usize,
+    payload: Vec<u8>,
+    stats: &SharedCompressionStats,
+) -> Vec<u8> {
+    if compression == Compression::None || payload.len() < threshold || payload.is_empty() {
+        stats.record_uncompressed();
+        return payload;
+    }
+
+    let (kind, body) = (payload[0], &payload[1..]);
+    let compressed = match codec_compress(compression, body) {
+        Some((codec, compressed)) if compressed.len() + COMPRESSED_HEADER_SIZE < payload.len() => {
+            let mut out = Vec::with_capacity(COMPRESSED_HEADER_SIZE + compressed.len());
+            out.push(kind | FLAG_COMPRESSED);
+            out.push(codec);
+            out.extend_from_slice(&compressed);
+            out
+        }
+        _ => {
+            stats.record_uncompressed();
+            return payload;
+        }
+    };
+
+    stats.record_compressed(payload.len(), compressed.len());
+    compressed
+}
+
+/// Restores a compressed payload. Uncompressed payloads are passed through, and `None` is
+/// returned for anything that can't be decompressed, or would decompress to more than `max_len`.
+pub(crate) fn decompress(payload: &[u8], max_len: usize) -> Option<Cow<'_, [u8]>> {
+    let kind = *payload.first()?;
+    if kind & FLAG_COMPRESSED == 0 {
+        return Some(Cow::Borrowed(payload));
+    }
+
+    if payload.len() < COMPRESSED_HEADER_SIZE {
+        return None;
+    }
+
+    let body = codec_decompress(payload[1], &payload[COMPRESSED_HEADER_SIZE..], max_len)?;
+
+    let mut out = Vec::with_capacity(1 + body.len());
+    out.push(kind & !FLAG_COMPRESSED);
+    out.extend_from_slice(&body);
+    Some(Cow::Owned(out))
+}
+
+#[allow(unused_variables)]
+fn codec_compress(compression: Compression, body: &[u8]) -> Option<(u8, Vec<u8>)> {
+    match compression {
+        Compression::None => None,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => lz4::block::compress(body, None, true)
+            .ok()
+            .map(|c| (CODEC_LZ4, c)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd { level } => zstd::block::compress(body, level)
+            .ok()
+            .map(|c| (CODEC_ZSTD, c)),
+    }
+}
+
+#[allow(unused_variables)]
+fn codec_decompress(codec: u8, body: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    match codec {
+        #[cfg(feature = "lz4")]
+        CODEC_LZ4 => {
+            // lz4 prefixes the decompressed size, which is checked before anything is allocated
+            let size = i32::from_le_bytes(body.get(..4)?.try_into().ok()?);
+            if size < 0 || size as usize > max_len {
+                return None;
+            }
+
+            lz4::block::decompress(body, None).ok()
+        }
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => zstd::block::decompress(body, max_len).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+
+    #[test]
+    fn small_and_uncompressed_payloads_pass_through() {
+        let stats = SharedCompressionStats::default();
+        let payload = protocol::encode_message(b"hello").to_vec();
+
+        let sent = compress(Compression::None, 0, payload.clone(), &stats);
+        assert_eq!(sent, payload);
+        assert_eq!(decompress(&sent, 1024), Some(Cow::Borrowed(&payload[..])));
+        assert_eq!(stats.snapshot().packets_uncompressed, 1);
+        assert_eq!(stats.snapshot().ratio(), None);
+
+        // an unknown codec is dropped
+        assert_eq!(decompress(&[FLAG_COMPRESSED, 200, 1, 2], 1024), None);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_round_trips() {
+        let stats = SharedCompressionStats::default();
+        let payload = protocol::encode_message(&[7; 1000]).to_vec();
+
+        let sent = compress(Compression::Lz4, 128, payload.clone(), &stats);
+        assert!(sent.len() < payload.len());
+        assert_eq!(decompress(&sent, 2048).unwrap().into_owned(), payload);
+        assert_eq!(decompress(&sent, 100), None);
+        assert!(stats.snapshot().ratio().unwrap() < 1.0);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trips() {
+        let stats = SharedCompressionStats::default();
+        let payload = protocol::encode_message(&[7; 1000]).to_vec();
+
+        let sent = compress(Compression::Zstd { level: 3 }, 128, payload.clone(), &stats);
+        assert!(sent.len() < payload.len());
+        assert_eq!(decompress(&sent, 2048).unwrap().into_owned(), payload);
+        assert_eq!(decompress(&sent, 100), None);
+    }
+}
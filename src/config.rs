@@ -0,0 +1,437 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::{Encryption, LaminarConfig, NetworkError, RateLimit};
+
+const ENV_PREFIX: &str = "BEVY_NETWORK_";
+
+/// Settings for the `NetworkingPlugin`. If this resource is added before the plugin, every socket
+/// listed here is bound when the plugin is built. If the config is invalid or a socket can't be
+/// bound, none of them are, and the error is sent as a `NetworkEvent::ConfigFailed`.
+///
+/// A config can be loaded from a RON or TOML file, and then adjusted with environment variables:
+///
+/// - `BEVY_NETWORK_SOCKET_<N>_ADDR` replaces the address of the Nth socket
+/// - `BEVY_NETWORK_<FIELD>` sets a `LaminarConfig` field on every socket, e.g.
+///   `BEVY_NETWORK_IDLE_CONNECTION_TIMEOUT_MS=10000`. Durations are given in milliseconds, and
+///   optional values can be cleared with `none`.
+/// - `BEVY_NETWORK_<LIMIT>_PACKETS_PER_SECOND` and `BEVY_NETWORK_<LIMIT>_BYTES_PER_SECOND` set
+///   one side of a rate limit or send budget, adding the limit if there wasn't one, e.g.
+///   `BEVY_NETWORK_SOCKET_RATE_LIMIT_BYTES_PER_SECOND=65536`
+/// - `BEVY_NETWORK_ENCRYPTION` turns encryption on or off, and
+///   `BEVY_NETWORK_ENCRYPTION_PRE_SHARED_KEY` and `BEVY_NETWORK_CONNECT_TOKEN_KEY` take keys as
+///   64 hex digits. A pre-shared key turns encryption on.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub sockets: Vec<SocketConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SocketConfig {
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub laminar: LaminarConfig,
+}
+
+impl NetworkConfig {
+    /// Loads a config from a `.ron` or `.toml` file, applies any environment variable overrides, and
+    /// validates the result
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, NetworkError> {
+        let mut config = Self::from_file(path)?;
+        config.apply_env_overrides()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, NetworkError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("ron") => Self::from_ron_str(&contents),
+            Some("toml") => Self::from_toml_str(&contents),
+            _ => Err(NetworkError::ConfigFile(format!(
+                "{} is not a .ron or .toml file",
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn from_ron_str(contents: &str) -> Result<Self, NetworkError> {
+        ron::de::from_str(contents).map_err(|e| NetworkError::ConfigFile(e.to_string()))
+    }
+
+    pub fn from_toml_str(contents: &str) -> Result<Self, NetworkError> {
+        toml::from_str(contents).map_err(|e| NetworkError::ConfigFile(e.to_string()))
+    }
+
+    /// Checks each socket's laminar config, and that no two sockets share a label or an address.
+    /// Sockets bound to port 0 are given a port of their own, so they never clash.
+    pub fn validate(&self) -> Result<(), NetworkError> {
+        for (i, socket) in self.sockets.iter().enumerate() {
+            socket.laminar.validate()?;
+
+            let earlier = &self.sockets[..i];
+            if let Some(label) = &socket.label {
+                if earlier.iter().any(|s| s.label.as_ref() == Some(label)) {
+                    return Err(NetworkError::DuplicateSocketLabel(label.clone()));
+                }
+            }
+
+            if earlier.iter().any(|s| addrs_clash(s.addr, socket.addr)) {
+                return Err(NetworkError::InvalidConfig {
+                    field: "addr",
+                    reason: format!("{} is used by more than one socket", socket.addr),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_env_overrides(&mut self) -> Result<(), NetworkError> {
+        self.apply_overrides(|name| std::env::var(name).ok())
+    }
+
+    fn apply_overrides<F: Fn(&str) -> Option<String>>(
+        &mut self,
+        lookup: F,
+    ) -> Result<(), NetworkError> {
+        for (i, socket) in self.sockets.iter_mut().enumerate() {
+            if let Some(addr) = lookup(&format!("{}SOCKET_{}_ADDR", ENV_PREFIX, i)) {
+                socket.addr = parse("addr", &addr)?;
+            }
+
+            let cfg = &mut socket.laminar;
+            let var = |field: &str| lookup(&format!("{}{}", ENV_PREFIX, field.to_uppercase()));
+
+            if let Some(v) = var("idle_connection_timeout_ms") {
+                cfg.idle_connection_timeout = parse_millis("idle_connection_timeout", &v)?;
+            }
+            if let Some(v) = var("heartbeat_interval_ms") {
+                cfg.heartbeat_interval = parse_optional_millis("heartbeat_interval", &v)?;
+            }
+            if let Some(v) = var("max_packets_in_flight") {
+                cfg.max_packets_in_flight = parse("max_packets_in_flight", &v)?;
+            }
+            if let Some(v) = var("blocking_mode") {
+                cfg.blocking_mode = parse("blocking_mode", &v)?;
+            }
+            if let Some(v) = var("max_packet_size") {
+                cfg.max_packet_size = parse("max_packet_size", &v)?;
+            }
+            if let Some(v) = var("max_fragments") {
+                cfg.max_fragments = parse("max_fragments", &v)?;
+            }
+            if let Some(v) = var("fragment_size") {
+                cfg.fragment_size = parse("fragment_size", &v)?;
+            }
+            if let Some(v) = var("fragment_reassembly_buffer_size") {
+                cfg.fragment_reassembly_buffer_size = parse("fragment_reassembly_buffer_size", &v)?;
+            }
+            if let Some(v) = var("receive_buffer_max_size") {
+                cfg.receive_buffer_max_size = parse("receive_buffer_max_size", &v)?;
+            }
+            if let Some(v) = var("rtt_smoothing_factor") {
+                cfg.rtt_smoothing_factor = parse("rtt_smoothing_factor", &v)?;
+            }
+            if let Some(v) = var("rtt_max_value") {
+                cfg.rtt_max_value = parse("rtt_max_value", &v)?;
+            }
+            if let Some(v) = var("socket_event_buffer_size") {
+                cfg.socket_event_buffer_size = parse("socket_event_buffer_size", &v)?;
+            }
+            if let Some(v) = var("socket_polling_timeout_ms") {
+                cfg.socket_polling_timeout = parse_optional_millis("socket_polling_timeout", &v)?;
+            }
+            if let Some(v) = var("max_transfer_size") {
+                cfg.max_transfer_size = parse("max_transfer_size", &v)?;
+            }
+            if let Some(v) = var("delivery_timeout_ms") {
+                cfg.delivery_timeout = parse_millis("delivery_timeout", &v)?;
+            }
+            if let Some(v) = var("coalesce_messages") {
+                cfg.coalesce_messages = parse("coalesce_messages", &v)?;
+            }
+            if let Some(v) = var("compression") {
+                cfg.compression = parse("compression", &v)?;
+            }
+            if let Some(v) = var("compression_threshold") {
+                cfg.compression_threshold = parse("compression_threshold", &v)?;
+            }
+            if let Some(v) = var("challenge_connections") {
+                cfg.challenge_connections = parse("challenge_connections", &v)?;
+            }
+            if let Some(v) = var("migrate_connections") {
+                cfg.migrate_connections = parse("migrate_connections", &v)?;
+            }
+
+            let limits = vec![
+                ("connection_rate_limit", &mut cfg.connection_rate_limit),
+                ("socket_rate_limit", &mut cfg.socket_rate_limit),
+                ("connection_send_budget", &mut cfg.connection_send_budget),
+                ("socket_send_budget", &mut cfg.socket_send_budget),
+            ];
+            for (field, limit) in limits {
+                if let Some(v) = var(&format!("{}_packets_per_second", field)) {
+                    limit
+                        .get_or_insert_with(RateLimit::default)
+                        .packets_per_second = parse_optional(field, &v)?;
+                }
+                if let Some(v) = var(&format!("{}_bytes_per_second", field)) {
+                    limit
+                        .get_or_insert_with(RateLimit::default)
+                        .bytes_per_second = parse_optional(field, &v)?;
+                }
+            }
+
+            if let Some(v) = var("encryption") {
+                cfg.encryption = match parse("encryption", &v)? {
+                    true => Some(cfg.encryption.take().unwrap_or_default()),
+                    false => None,
+                };
+            }
+            if let Some(v) = var("encryption_pre_shared_key") {
+                let key = parse_key("pre_shared_key", &v)?;
+                match &mut cfg.encryption {
+                    Some(encryption) => encryption.pre_shared_key = key,
+                    None if key.is_some() => {
+                        cfg.encryption = Some(Encryption {
+                            pre_shared_key: key,
+                        })
+                    }
+                    None => {}
+                }
+            }
+            if let Some(v) = var("connect_token_key") {
+                cfg.connect_token_key = parse_key("connect_token_key", &v)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// an unspecified address takes the port on every interface, so it clashes with any address on
+// the same port
+fn addrs_clash(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() != 0
+        && a.port() == b.port()
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+fn parse<T: FromStr>(field: &'static str, value: &str) -> Result<T, NetworkError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| NetworkError::InvalidConfig {
+            field,
+            reason: format!("could not parse {:?}", value),
+        })
+}
+
+fn parse_optional<T: FromStr>(field: &'static str, value: &str) -> Result<Option<T>, NetworkError> {
+    match value.trim() {
+        "none" | "" => Ok(None),
+        v => parse(field, v).map(Some),
+    }
+}
+
+fn parse_millis(field: &'static str, value: &str) -> Result<Duration, NetworkError> {
+    parse(field, value).map(Duration::from_millis)
+}
+
+fn parse_optional_millis(
+    field: &'static str,
+    value: &str,
+) -> Result<Option<Duration>, NetworkError> {
+    parse_optional(field, value).map(|millis| millis.map(Duration::from_millis))
+}
+
+// keys are written as 64 hex digits
+fn parse_key(field: &'static str, value: &str) -> Result<Option<[u8; 32]>, NetworkError> {
+    let digits: Option<Vec<u8>> = match value.trim() {
+        "none" | "" => return Ok(None),
+        v => v.chars().map(|c| c.to_digit(16).map(|d| d as u8)).collect(),
+    };
+
+    match digits {
+        Some(digits) if digits.len() == 64 => {
+            let mut key = [0; 32];
+            for (byte, pair) in key.iter_mut().zip(digits.chunks(2)) {
+                *byte = pair[0] << 4 | pair[1];
+            }
+            Ok(Some(key))
+        }
+        _ => Err(NetworkError::InvalidConfig {
+            field,
+            reason: "must be 64 hex digits".to_string(),
+        }),
+    }
+}
+
+// durations are written as milliseconds in config files, rather than serde's `{ secs, nanos }`
+pub(crate) mod millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        u64::deserialize(d).map(Duration::from_millis)
+    }
+
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::time::Duration;
+
+        pub fn serialize<S: Serializer>(
+            duration: &Option<Duration>,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            match duration {
+                Some(d) => s.serialize_some(&(d.as_millis() as u64)),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+            Option::<u64>::deserialize(d).map(|ms| ms.map(Duration::from_millis))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn loads_ron_and_toml() {
+        let ron = r#"(
+            sockets: [
+                (addr: "127.0.0.1:12351", laminar: (idle_connection_timeout: 10000)),
+            ],
+        )"#;
+        let config = NetworkConfig::from_ron_str(ron).unwrap();
+        assert_eq!(
+            config.sockets[0].laminar.idle_connection_timeout,
+            Duration::from_secs(10)
+        );
+
+        let toml = r#"
+            [[sockets]]
+            addr = "127.0.0.1:12351"
+
+            [sockets.laminar]
+            max_packets_in_flight = 512
+        "#;
+        let config = NetworkConfig::from_toml_str(toml).unwrap();
+        assert_eq!(config.sockets[0].laminar.max_packets_in_flight, 512);
+        assert_eq!(
+            config.sockets[0].laminar.heartbeat_interval,
+            LaminarConfig::default().heartbeat_interval
+        );
+    }
+
+    #[test]
+    fn env_overrides_are_applied_and_checked() {
+        let mut config = NetworkConfig {
+            sockets: vec![SocketConfig {
+                addr: "127.0.0.1:12351".parse().unwrap(),
+                label: None,
+                laminar: LaminarConfig::default(),
+            }],
+        };
+
+        let mut env = HashMap::new();
+        env.insert("BEVY_NETWORK_SOCKET_0_ADDR", "0.0.0.0:4000");
+        env.insert("BEVY_NETWORK_HEARTBEAT_INTERVAL_MS", "none");
+
+        config
+            .apply_overrides(|name| env.get(name).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.sockets[0].addr, "0.0.0.0:4000".parse().unwrap());
+        assert_eq!(config.sockets[0].laminar.heartbeat_interval, None);
+
+        let key = "0a".repeat(32);
+        env.insert(
+            "BEVY_NETWORK_CONNECTION_RATE_LIMIT_PACKETS_PER_SECOND",
+            "100",
+        );
+        env.insert("BEVY_NETWORK_CONNECT_TOKEN_KEY", &key);
+        config
+            .apply_overrides(|name| env.get(name).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(
+            config.sockets[0].laminar.connection_rate_limit,
+            Some(RateLimit {
+                packets_per_second: Some(100),
+                bytes_per_second: None,
+            })
+        );
+        assert_eq!(config.sockets[0].laminar.connect_token_key, Some([10; 32]));
+
+        env.insert("BEVY_NETWORK_CONNECT_TOKEN_KEY", "0a0a");
+        let result = config.apply_overrides(|name| env.get(name).map(|v| v.to_string()));
+        assert!(matches!(
+            result,
+            Err(NetworkError::InvalidConfig {
+                field: "connect_token_key",
+                ..
+            })
+        ));
+        env.remove("BEVY_NETWORK_CONNECT_TOKEN_KEY");
+
+        env.insert("BEVY_NETWORK_FRAGMENT_SIZE", "huge");
+        let result = config.apply_overrides(|name| env.get(name).map(|v| v.to_string()));
+        assert!(matches!(
+            result,
+            Err(NetworkError::InvalidConfig {
+                field: "fragment_size",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn sockets_must_not_share_labels_or_addresses() {
+        let socket = |addr: &str, label: Option<&str>| SocketConfig {
+            addr: addr.parse().unwrap(),
+            label: label.map(String::from),
+            laminar: LaminarConfig::default(),
+        };
+
+        let config = NetworkConfig {
+            sockets: vec![
+                socket("127.0.0.1:0", Some("game")),
+                socket("127.0.0.1:0", None),
+                socket("127.0.0.1:12351", Some("admin")),
+            ],
+        };
+        assert!(config.validate().is_ok());
+
+        let mut labels = config.clone();
+        labels.sockets.push(socket("127.0.0.1:12352", Some("game")));
+        assert!(matches!(
+            labels.validate(),
+            Err(NetworkError::DuplicateSocketLabel(label)) if label == "game"
+        ));
+
+        let mut addrs = config;
+        addrs.sockets.push(socket("0.0.0.0:12351", None));
+        assert!(matches!(
+            addrs.validate(),
+            Err(NetworkError::InvalidConfig { field: "addr", .. })
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::MessageId;
+
+// Tracks messages sent with `SendConfig::track_delivery` until the peer acknowledges them.
+//
+// laminar doesn't tell us when a particular packet has been acked, so tracked messages carry their
+// id, and the receiving worker answers with an ack frame of its own. Reliable messages will
+// eventually arrive (or the connection will time out), so only unreliable messages are ever
+// declared lost because their ack didn't arrive in time.
+pub(crate) struct DeliveryTracker {
+    timeout: Duration,
+    pending: HashMap<(SocketAddr, MessageId), Pending>,
+}
+
+struct Pending {
+    sent_at: Instant,
+    reliable: bool,
+}
+
+impl DeliveryTracker {
+    pub fn new(timeout: Duration) -> Self {
+        DeliveryTracker {
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn sent(&mut self, addr: SocketAddr, id: MessageId, reliable: bool, now: Instant) {
+        let pending = Pending {
+            sent_at: now,
+            reliable,
+        };
+
+        self.pending.insert((addr, id), pending);
+    }
+
+    /// Returns true if the message was still waiting for its ack
+    pub fn acknowledge(&mut self, addr: SocketAddr, id: MessageId) -> bool {
+        self.pending.remove(&(addr, id)).is_some()
+    }
+
+    /// Removes and returns the unreliable messages whose ack is overdue
+    pub fn expire(&mut self, now: Instant) -> Vec<(SocketAddr, MessageId)> {
+        let timeout = self.timeout;
+        let expired: Vec<(SocketAddr, MessageId)> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| !p.reliable && now.saturating_duration_since(p.sent_at) > timeout)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in &expired {
+            self.pending.remove(key);
+        }
+
+        expired
+    }
+
+    /// Moves the messages waiting on an ack from one address to another
+    pub fn migrate(&mut self, old: SocketAddr, new: SocketAddr) {
+        let moved: Vec<MessageId> = self
+            .pending
+            .keys()
+            .filter(|(a, _)| *a == old)
+            .map(|(_, id)| *id)
+            .collect();
+
+        for id in moved {
+            if let Some(pending) = self.pending.remove(&(old, id)) {
+                self.pending.insert((new, id), pending);
+            }
+        }
+    }
+
+    /// Removes and returns every message still waiting on an ack from the address
+    pub fn remove_connection(&mut self, addr: SocketAddr) -> Vec<MessageId> {
+        let ids: Vec<MessageId> = self
+            .pending
+            .keys()
+            .filter(|(a, _)| *a == addr)
+            .map(|(_, id)| *id)
+            .collect();
+
+        for id in &ids {
+            self.pending.remove(&(addr, *id));
+        }
+
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_unreliable_messages_expire() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let mut tracker = DeliveryTracker::new(Duration::from_millis(100));
+        let now = Instant::now();
+
+        tracker.sent(addr, MessageId(1), false, now);
+        tracker.sent(addr, MessageId(2), true, now);
+        tracker.sent(addr, MessageId(3), false, now);
+        assert!(tracker.acknowledge(addr, MessageId(3)));
+        assert!(!tracker.acknowledge(addr, MessageId(3)));
+
+        assert!(tracker.expire(now).is_empty());
+        let later = now + Duration::from_millis(200);
+        assert_eq!(tracker.expire(later), vec![(addr, MessageId(1))]);
+
+        assert_eq!(tracker.remove_connection(addr), vec![MessageId(2)]);
+    }
+}
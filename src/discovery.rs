@@ -0,0 +1,264 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use super::{NetworkError, NetworkEvent};
+
+const MAGIC: &[u8; 4] = b"BPNL";
+const VERSION: u8 = 1;
+const KIND_PROBE: u8 = 0;
+const KIND_ANSWER: u8 = 1;
+
+// magic, version, kind, and the probe's nonce
+const HEADER_SIZE: usize = 4 + 1 + 1 + 8;
+// the header, and the port the server's game socket is bound on
+const ANSWER_HEADER_SIZE: usize = HEADER_SIZE + 2;
+
+/// The most info a server can advertise
+pub const MAX_DISCOVERY_INFO: usize = 1024;
+
+// Probes are padded to the size of the largest answer, so a probe sent from a spoofed address
+// can't be used to send anyone more than the attacker sent
+const PROBE_SIZE: usize = ANSWER_HEADER_SIZE + MAX_DISCOVERY_INFO;
+
+/// Where LAN discovery happens. Servers and clients have to agree on the port, and on the
+/// multicast group if there is one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryConfig {
+    /// The port servers listen for probes on. This is separate from the port the game is served on.
+    pub port: u16,
+    /// Where clients send probes. Defaults to the broadcast address, but can be any address
+    /// servers can be reached on, such as the loopback address in tests.
+    pub broadcast_addr: Ipv4Addr,
+    /// A multicast group that servers join and clients probe as well, for networks that don't
+    /// pass broadcasts along
+    pub multicast_group: Option<Ipv4Addr>,
+    /// How long a client listens for answers after sending a probe
+    pub listen_for: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            port: 12350,
+            broadcast_addr: Ipv4Addr::BROADCAST,
+            multicast_group: None,
+            listen_for: Duration::from_secs(2),
+        }
+    }
+}
+
+// Answers discovery probes on behalf of a bound socket, with whatever info the server has
+// advertised
+pub(crate) struct Advertiser {
+    socket: UdpSocket,
+    info: Bytes,
+}
+
+impl Advertiser {
+    pub fn bind(config: &DiscoveryConfig, info: Bytes) -> Result<Self, NetworkError> {
+        check_info(&info)?;
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.port))?;
+        if let Some(group) = config.multicast_group {
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        }
+        socket.set_nonblocking(true)?;
+
+        Ok(Advertiser { socket, info })
+    }
+
+    pub fn set_info(&mut self, info: Bytes) {
+        self.info = info;
+    }
+
+    /// Answers the probes that have arrived since the last call
+    pub fn answer_probes(&mut self, game_port: u16) -> Result<(), NetworkError> {
+        // one byte more than a probe, so anything longer isn't truncated into one
+        let mut buf = [0; PROBE_SIZE + 1];
+
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+
+            let nonce = match decode_probe(&buf[..len]) {
+                Some(nonce) => nonce,
+                None => continue,
+            };
+
+            // an unreachable client is indistinguishable from one that went away
+            let _ = self
+                .socket
+                .send_to(&encode_answer(nonce, game_port, &self.info), from);
+        }
+    }
+}
+
+// Sends a probe, and reports each server that answers it until it expires
+pub(crate) struct Prober {
+    socket: UdpSocket,
+    nonce: u64,
+    expires_at: Instant,
+    // the servers that have already been reported, since a server can hear a probe more than once
+    found: HashSet<SocketAddr>,
+}
+
+impl Prober {
+    pub fn probe(config: &DiscoveryConfig) -> Result<Self, NetworkError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        let nonce = Uuid::new_v4().as_u128() as u64;
+        let probe = encode_probe(nonce);
+
+        socket.send_to(&probe, (config.broadcast_addr, config.port))?;
+        if let Some(group) = config.multicast_group {
+            socket.send_to(&probe, (group, config.port))?;
+        }
+
+        Ok(Prober {
+            socket,
+            nonce,
+            expires_at: Instant::now() + config.listen_for,
+            found: HashSet::new(),
+        })
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expires_at
+    }
+
+    /// Reports the servers that have answered since the last call
+    pub fn receive_answers(&mut self, events: &mut Vec<NetworkEvent>) {
+        let mut buf = [0; ANSWER_HEADER_SIZE + MAX_DISCOVERY_INFO];
+
+        // errors are treated like silence, since the probe is only listening for a while anyway
+        while let Ok((len, from)) = self.socket.recv_from(&mut buf) {
+            let (game_port, info) = match decode_answer(self.nonce, &buf[..len]) {
+                Some(answer) => answer,
+                None => continue,
+            };
+
+            let addr = SocketAddr::new(from.ip(), game_port);
+            if self.found.insert(addr) {
+                events.push(NetworkEvent::ServerDiscovered {
+                    addr,
+                    info: Bytes::copy_from_slice(info),
+                });
+            }
+        }
+    }
+}
+
+pub(crate) fn check_info(info: &[u8]) -> Result<(), NetworkError> {
+    match info.len() > MAX_DISCOVERY_INFO {
+        true => Err(NetworkError::PayloadTooLarge {
+            size: info.len(),
+            max: MAX_DISCOVERY_INFO,
+        }),
+        false => Ok(()),
+    }
+}
+
+fn encode_header(buf: &mut BytesMut, kind: u8, nonce: u64) {
+    buf.put_slice(MAGIC);
+    buf.put_u8(VERSION);
+    buf.put_u8(kind);
+    buf.put_u64(nonce);
+}
+
+fn encode_probe(nonce: u64) -> Bytes {
+    let mut buf = BytesMut::with_capacity(PROBE_SIZE);
+    encode_header(&mut buf, KIND_PROBE, nonce);
+    buf.put_slice(&[0; PROBE_SIZE - HEADER_SIZE]);
+    buf.freeze()
+}
+
+fn encode_answer(nonce: u64, game_port: u16, info: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(ANSWER_HEADER_SIZE + info.len());
+    encode_header(&mut buf, KIND_ANSWER, nonce);
+    buf.put_u16(game_port);
+    buf.put_slice(info);
+    buf.freeze()
+}
+
+fn decode_header(kind: u8, packet: &[u8]) -> Option<u64> {
+    if packet.len() < HEADER_SIZE
+        || &packet[..4] != MAGIC
+        || packet[4] != VERSION
+        || packet[5] != kind
+    {
+        return None;
+    }
+
+    Some(u64::from_be_bytes(packet[6..HEADER_SIZE].try_into().ok()?))
+}
+
+fn decode_probe(packet: &[u8]) -> Option<u64> {
+    match packet.len() == PROBE_SIZE {
+        true => decode_header(KIND_PROBE, packet),
+        false => None,
+    }
+}
+
+fn decode_answer(nonce: u64, packet: &[u8]) -> Option<(u16, &[u8])> {
+    if decode_header(KIND_ANSWER, packet)? != nonce || packet.len() < ANSWER_HEADER_SIZE {
+        return None;
+    }
+
+    let game_port = u16::from_be_bytes(packet[HEADER_SIZE..ANSWER_HEADER_SIZE].try_into().ok()?);
+    Some((game_port, &packet[ANSWER_HEADER_SIZE..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn servers_answer_probes_on_loopback() {
+        let config = DiscoveryConfig {
+            port: 12680,
+            broadcast_addr: Ipv4Addr::LOCALHOST,
+            ..Default::default()
+        };
+
+        let mut advertiser = Advertiser::bind(&config, Bytes::from_static(b"first")).unwrap();
+        advertiser.set_info(Bytes::from_static(b"second"));
+        let mut prober = Prober::probe(&config).unwrap();
+
+        // an answer is never larger than the probe it answers, and a short probe isn't answered
+        let probe = encode_probe(prober.nonce);
+        let largest = encode_answer(prober.nonce, 4000, &[0; MAX_DISCOVERY_INFO]);
+        assert!(largest.len() <= probe.len());
+        assert_eq!(decode_probe(&probe[..HEADER_SIZE]), None);
+
+        // an answer to someone else's probe is ignored
+        let stray = encode_answer(prober.nonce + 1, 4000, b"stray");
+        assert_eq!(decode_answer(prober.nonce, &stray), None);
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut events = Vec::new();
+        while events.is_empty() && Instant::now() < deadline {
+            advertiser.answer_probes(12681).unwrap();
+            prober.receive_answers(&mut events);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        match &events[..] {
+            [NetworkEvent::ServerDiscovered { addr, info }] => {
+                assert_eq!(*addr, SocketAddr::from(([127, 0, 0, 1], 12681)));
+                assert_eq!(&info[..], b"second");
+            }
+            other => panic!("expected a discovered server, got {:?}", other),
+        }
+        assert!(prober.is_expired(Instant::now() + config.listen_for));
+    }
+}
@@ -0,0 +1,491 @@
+use serde::{Deserialize, Serialize};
+#[cfg(not(feature = "encryption"))]
+use std::net::SocketAddr;
+
+/// Encrypts and authenticates everything a socket sends with ChaCha20-Poly1305. Requires the
+/// `encryption` cargo feature, and both ends of a connection have to use it.
+///
+/// Each connection starts with an X25519 key exchange, and gets its own keys. Without a
+/// `pre_shared_key` the exchange is not authenticated, so it protects against eavesdropping and
+/// spoofed packets, but not against an attacker who can intercept the handshake. With a
+/// `pre_shared_key`, only peers that know the key can complete a handshake.
+///
+/// The handshake costs the responder a key exchange, so `LaminarConfig::challenge_connections`
+/// has to be enabled along with encryption, and only peers that have proven they can receive at
+/// their address get that far.
+///
+/// `NetworkEvent::Connected` is sent once the handshake completes, and packets that fail to
+/// decrypt, or that have been seen before, are dropped by the worker.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Encryption {
+    pub pre_shared_key: Option<[u8; 32]>,
+}
+
+// the flag byte and the packet counter, which doubles as the nonce
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+const ENCRYPTED_HEADER_SIZE: usize = 9;
+// the Poly1305 tag appended to the ciphertext
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+const TAG_SIZE: usize = 16;
+
+/// The encryption header and authentication tag added to every packet
+pub(crate) const ENCRYPTION_OVERHEAD: usize = ENCRYPTED_HEADER_SIZE + TAG_SIZE;
+
+// only constructed by the real sessions
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+#[derive(Default)]
+pub(crate) struct Handshake {
+    /// Packets to send back to the peer
+    pub replies: Vec<Vec<u8>>,
+    /// The peer has just become an established connection
+    pub established: bool,
+}
+
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub(crate) struct Opened {
+    pub plaintext: Vec<u8>,
+    pub established: bool,
+}
+
+#[cfg(feature = "encryption")]
+pub(crate) use sessions::Sessions;
+
+// without the feature a socket never has any sessions, which `LaminarConfig::validate` ensures
+#[cfg(not(feature = "encryption"))]
+pub(crate) enum Sessions {}
+
+#[cfg(not(feature = "encryption"))]
+impl Sessions {
+    pub fn new(_: &Encryption) -> Self {
+        unreachable!("encryption requires the `encryption` feature")
+    }
+
+    pub fn established(&self, _: SocketAddr) -> bool {
+        match *self {}
+    }
+
+    pub fn start_handshake(&mut self, _: SocketAddr) -> Option<Vec<u8>> {
+        match *self {}
+    }
+
+    pub fn handshake(&mut self, _: SocketAddr, _: crate::protocol::Frame) -> Handshake {
+        match *self {}
+    }
+
+    pub fn seal(&mut self, _: SocketAddr, _: &[u8]) -> Option<Vec<u8>> {
+        match *self {}
+    }
+
+    pub fn open(&mut self, _: SocketAddr, _: &[u8]) -> Option<Opened> {
+        match *self {}
+    }
+
+    pub fn authentic(&self, _: SocketAddr, _: &[u8]) -> bool {
+        match *self {}
+    }
+
+    pub fn migrate(&mut self, _: SocketAddr, _: SocketAddr) {
+        match *self {}
+    }
+
+    pub fn remove_connection(&mut self, _: SocketAddr) {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "encryption")]
+mod sessions {
+    use chacha20poly1305::aead::{Aead, NewAead, Payload};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+    use hkdf::Hkdf;
+    use rand::rngs::OsRng;
+    use sha2::Sha256;
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+    use std::net::SocketAddr;
+    use x25519_dalek::{EphemeralSecret, PublicKey};
+
+    use super::{Encryption, Handshake, Opened, ENCRYPTED_HEADER_SIZE, TAG_SIZE};
+    use crate::protocol::{self, Frame, FLAG_ENCRYPTED};
+
+    const KEY_INFO: &[u8] = b"bevy_prototype_networking_laminar session keys";
+    const REPLAY_WINDOW: u64 = 64;
+
+    // The handshake is an init carrying the initiator's ephemeral key, and a reply carrying the
+    // responder's key along with the init it answers. The initiator can use the session as soon as
+    // the reply arrives, and confirms it with an empty encrypted packet. The responder only
+    // switches to a new session once a packet decrypts under it, so a replayed init can't tear
+    // down a working session.
+    pub(crate) struct Sessions {
+        pre_shared_key: [u8; 32],
+        peers: HashMap<SocketAddr, Peer>,
+    }
+
+    #[derive(Default)]
+    struct Peer {
+        current: Option<Session>,
+        // a session we responded to, waiting for its first packet
+        candidate: Option<Session>,
+        // our half of a handshake we started
+        initiated: Option<(EphemeralSecret, PublicKey)>,
+    }
+
+    struct Session {
+        send: ChaCha20Poly1305,
+        receive: ChaCha20Poly1305,
+        next_counter: u64,
+        replay: ReplayWindow,
+    }
+
+    #[derive(Default)]
+    struct ReplayWindow {
+        highest: u64,
+        // bit n is set if `highest - n` has been received
+        seen: u64,
+    }
+
+    impl Sessions {
+        pub fn new(config: &Encryption) -> Self {
+            Sessions {
+                pre_shared_key: config.pre_shared_key.unwrap_or([0; 32]),
+                peers: HashMap::new(),
+            }
+        }
+
+        pub fn established(&self, addr: SocketAddr) -> bool {
+            self.peers.get(&addr).is_some_and(|p| p.current.is_some())
+        }
+
+        /// Returns the init to send, unless a handshake with the address is already under way
+        pub fn start_handshake(&mut self, addr: SocketAddr) -> Option<Vec<u8>> {
+            let peer = self.peers.entry(addr).or_default();
+            if peer.initiated.is_some() || peer.candidate.is_some() {
+                return None;
+            }
+
+            let secret = EphemeralSecret::new(OsRng);
+            let public = PublicKey::from(&secret);
+            peer.initiated = Some((secret, public));
+
+            Some(protocol::encode_handshake_init(public.as_bytes()).to_vec())
+        }
+
+        pub fn handshake(&mut self, addr: SocketAddr, frame: Frame) -> Handshake {
+            match frame {
+                Frame::HandshakeInit(initiator) => self.respond(addr, to_key(initiator)),
+                Frame::HandshakeReply(responder, initiator) => {
+                    self.complete(addr, to_key(responder), to_key(initiator))
+                }
+                _ => Handshake::default(),
+            }
+        }
+
+        fn respond(&mut self, addr: SocketAddr, initiator: PublicKey) -> Handshake {
+            let psk = self.pre_shared_key;
+            let peer = self.peers.entry(addr).or_default();
+
+            // when both ends start a handshake at once, the init with the lower key wins
+            if let Some((_, ours)) = &peer.initiated {
+                if ours.as_bytes() < initiator.as_bytes() {
+                    return Handshake::default();
+                }
+                peer.initiated = None;
+            }
+
+            let secret = EphemeralSecret::new(OsRng);
+            let public = PublicKey::from(&secret);
+            let shared = secret.diffie_hellman(&initiator);
+
+            peer.candidate = Some(Session::derive(
+                &psk,
+                shared.as_bytes(),
+                &initiator,
+                &public,
+                false,
+            ));
+
+            let reply = protocol::encode_handshake_reply(public.as_bytes(), initiator.as_bytes());
+            Handshake {
+                replies: vec![reply.to_vec()],
+                established: false,
+            }
+        }
+
+        fn complete(
+            &mut self,
+            addr: SocketAddr,
+            responder: PublicKey,
+            initiator: PublicKey,
+        ) -> Handshake {
+            let psk = self.pre_shared_key;
+            let peer = match self.peers.get_mut(&addr) {
+                Some(peer) => peer,
+                None => return Handshake::default(),
+            };
+
+            // a reply is only accepted for the init we're waiting on
+            match &peer.initiated {
+                Some((_, ours)) if ours.as_bytes() == initiator.as_bytes() => {}
+                _ => return Handshake::default(),
+            }
+
+            let (secret, public) = peer.initiated.take().unwrap();
+            let shared = secret.diffie_hellman(&responder);
+            let mut session = Session::derive(&psk, shared.as_bytes(), &public, &responder, true);
+
+            let confirm = session.seal(&[]);
+            let established = peer.current.is_none();
+            peer.current = Some(session);
+            peer.candidate = None;
+
+            Handshake {
+                replies: vec![confirm],
+                established,
+            }
+        }
+
+        pub fn seal(&mut self, addr: SocketAddr, plaintext: &[u8]) -> Option<Vec<u8>> {
+            let session = self.peers.get_mut(&addr)?.current.as_mut()?;
+            Some(session.seal(plaintext))
+        }
+
+        pub fn open(&mut self, addr: SocketAddr, packet: &[u8]) -> Option<Opened> {
+            let peer = self.peers.get_mut(&addr)?;
+
+            if let Some(plaintext) = peer.current.as_mut().and_then(|s| s.open(packet)) {
+                return Some(Opened {
+                    plaintext,
+                    established: false,
+                });
+            }
+
+            let plaintext = peer.candidate.as_mut()?.open(packet)?;
+            let established = peer.current.is_none();
+            peer.current = peer.candidate.take();
+
+            Some(Opened {
+                plaintext,
+                established,
+            })
+        }
+
+        /// Checks a packet against the address's session, without opening it
+        pub fn authentic(&self, addr: SocketAddr, packet: &[u8]) -> bool {
+            self.peers
+                .get(&addr)
+                .and_then(|p| p.current.as_ref())
+                .is_some_and(|s| s.decrypt(packet).is_some())
+        }
+
+        pub fn migrate(&mut self, old: SocketAddr, new: SocketAddr) {
+            if let Some(peer) = self.peers.remove(&old) {
+                self.peers.insert(new, peer);
+            }
+        }
+
+        pub fn remove_connection(&mut self, addr: SocketAddr) {
+            self.peers.remove(&addr);
+        }
+    }
+
+    impl Session {
+        fn derive(
+            psk: &[u8; 32],
+            shared: &[u8],
+            initiator: &PublicKey,
+            responder: &PublicKey,
+            is_initiator: bool,
+        ) -> Self {
+            let mut info = KEY_INFO.to_vec();
+            info.extend_from_slice(initiator.as_bytes());
+            info.extend_from_slice(responder.as_bytes());
+
+            let mut keys = [0; 64];
+            Hkdf::<Sha256>::new(Some(psk), shared)
+                .expand(&info, &mut keys)
+                .expect("64 bytes is a valid HKDF-SHA256 output length");
+
+            let (to_responder, to_initiator) = keys.split_at(32);
+            let (send, receive) = match is_initiator {
+                true => (to_responder, to_initiator),
+                false => (to_initiator, to_responder),
+            };
+
+            let send: [u8; 32] = send.try_into().unwrap();
+            let receive: [u8; 32] = receive.try_into().unwrap();
+
+            Session {
+                send: ChaCha20Poly1305::new(&Key::from(send)),
+                receive: ChaCha20Poly1305::new(&Key::from(receive)),
+                next_counter: 1,
+                replay: ReplayWindow::default(),
+            }
+        }
+
+        fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+            let counter = self.next_counter;
+            self.next_counter += 1;
+
+            let mut packet = Vec::with_capacity(ENCRYPTED_HEADER_SIZE + plaintext.len() + TAG_SIZE);
+            packet.push(FLAG_ENCRYPTED);
+            packet.extend_from_slice(&counter.to_be_bytes());
+
+            let payload = Payload {
+                msg: plaintext,
+                aad: &packet[..ENCRYPTED_HEADER_SIZE],
+            };
+            let ciphertext = self
+                .send
+                .encrypt(&Nonce::from(nonce(counter)), payload)
+                .expect("encrypting into a Vec can't fail");
+
+            packet.extend_from_slice(&ciphertext);
+            packet
+        }
+
+        fn open(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+            let (counter, plaintext) = self.decrypt(packet)?;
+
+            // only authentic packets move the window, so forged counters can't push it forward
+            self.replay.mark(counter);
+            Some(plaintext)
+        }
+
+        fn decrypt(&self, packet: &[u8]) -> Option<(u64, Vec<u8>)> {
+            if packet.len() < ENCRYPTED_HEADER_SIZE || packet[0] != FLAG_ENCRYPTED {
+                return None;
+            }
+
+            let (header, ciphertext) = packet.split_at(ENCRYPTED_HEADER_SIZE);
+            let counter = u64::from_be_bytes(header[1..].try_into().unwrap());
+            if !self.replay.is_new(counter) {
+                return None;
+            }
+
+            let payload = Payload {
+                msg: ciphertext,
+                aad: header,
+            };
+            let plaintext = self
+                .receive
+                .decrypt(&Nonce::from(nonce(counter)), payload)
+                .ok()?;
+
+            Some((counter, plaintext))
+        }
+    }
+
+    impl ReplayWindow {
+        fn is_new(&self, counter: u64) -> bool {
+            if counter == 0 {
+                return false;
+            }
+
+            if counter > self.highest {
+                return true;
+            }
+
+            let age = self.highest - counter;
+            age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+        }
+
+        fn mark(&mut self, counter: u64) {
+            if counter > self.highest {
+                let shift = counter - self.highest;
+                self.seen = match shift < REPLAY_WINDOW {
+                    true => self.seen << shift,
+                    false => 0,
+                };
+                self.highest = counter;
+            }
+
+            self.seen |= 1 << (self.highest - counter);
+        }
+    }
+
+    fn nonce(counter: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    fn to_key(bytes: &[u8]) -> PublicKey {
+        let bytes: [u8; 32] = bytes.try_into().unwrap();
+        PublicKey::from(bytes)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn addr(port: u16) -> SocketAddr {
+            SocketAddr::from(([127, 0, 0, 1], port))
+        }
+
+        fn connect(client: &mut Sessions, server: &mut Sessions) {
+            let init = client.start_handshake(addr(1)).unwrap();
+            let reply = server.handshake(addr(2), protocol::decode(&init).unwrap());
+            assert!(!reply.established);
+
+            let confirm = client.handshake(addr(1), protocol::decode(&reply.replies[0]).unwrap());
+            assert!(confirm.established);
+
+            let opened = server.open(addr(2), &confirm.replies[0]).unwrap();
+            assert!(opened.established);
+            assert!(opened.plaintext.is_empty());
+        }
+
+        #[test]
+        fn sessions_reject_tampered_and_replayed_packets() {
+            let config = Encryption::default();
+            let mut client = Sessions::new(&config);
+            let mut server = Sessions::new(&config);
+            connect(&mut client, &mut server);
+
+            let packet = client.seal(addr(1), b"hello").unwrap();
+            assert_eq!(packet.len(), 5 + crate::encryption::ENCRYPTION_OVERHEAD);
+            assert_eq!(server.open(addr(2), &packet).unwrap().plaintext, b"hello");
+            assert!(server.open(addr(2), &packet).is_none());
+
+            let mut tampered = client.seal(addr(1), b"hello").unwrap();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(server.open(addr(2), &tampered).is_none());
+
+            let reply = server.seal(addr(2), b"hi").unwrap();
+            assert_eq!(client.open(addr(1), &reply).unwrap().plaintext, b"hi");
+        }
+
+        #[test]
+        fn handshakes_need_the_same_pre_shared_key() {
+            let mut client = Sessions::new(&Encryption {
+                pre_shared_key: Some([1; 32]),
+            });
+            let mut server = Sessions::new(&Encryption {
+                pre_shared_key: Some([2; 32]),
+            });
+
+            let init = client.start_handshake(addr(1)).unwrap();
+            let reply = server.handshake(addr(2), protocol::decode(&init).unwrap());
+            let confirm = client.handshake(addr(1), protocol::decode(&reply.replies[0]).unwrap());
+
+            assert!(server.open(addr(2), &confirm.replies[0]).is_none());
+            assert!(!server.established(addr(2)));
+        }
+
+        #[test]
+        fn replay_window_accepts_late_packets_once() {
+            let mut window = ReplayWindow::default();
+
+            window.mark(5);
+            assert!(window.is_new(3));
+            window.mark(3);
+            assert!(!window.is_new(3));
+            assert!(!window.is_new(5));
+
+            window.mark(100);
+            assert!(!window.is_new(20));
+            assert!(window.is_new(99));
+        }
+    }
+}
@@ -3,6 +3,7 @@ use laminar::ErrorKind as LaminarError;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
 use std::io;
+use std::net::SocketAddr;
 use std::sync::{MutexGuard, PoisonError};
 
 use super::{Message, SocketHandle, WorkerInstructions};
@@ -11,11 +12,23 @@ use super::{Message, SocketHandle, WorkerInstructions};
 pub enum NetworkError {
     NoSocket(SocketHandle),
     NoDefaultSocket,
+    NoSocketLabel(String),
+    DuplicateSocketLabel(String),
+    PayloadTooLarge { size: usize, max: usize },
+    SendBudgetExceeded,
+    NoSession(SocketAddr),
+    ChallengeFailed(SocketAddr),
+    ConnectFailed(SocketAddr),
+    UnsupportedAddress(SocketAddr),
+    NotMulticast(SocketAddr),
+    InvalidConfig { field: &'static str, reason: String },
+    ConfigFile(String),
     InternalError(InternalErrorKind),
     IOError(io::Error),
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum InternalErrorKind {
     MutexLockError,
     SendWorkerInstructionsError(String),
@@ -34,6 +47,12 @@ impl From<LaminarError> for NetworkError {
     }
 }
 
+impl From<io::Error> for NetworkError {
+    fn from(err: io::Error) -> Self {
+        IOError(err)
+    }
+}
+
 // impl<T> From<PoisonError<MutexGuard<'_, Sender<T>>>> for NetworkError {
 impl<T> From<PoisonError<MutexGuard<'_, T>>> for NetworkError {
     fn from(_: PoisonError<MutexGuard<'_, T>>) -> Self {
@@ -64,6 +83,46 @@ impl Display for NetworkError {
                 handle
             ),
             NoDefaultSocket => write!(fmt, "No default socket is bound."),
+            NoSocketLabel(label) => write!(fmt, "No socket is bound with the label {:?}", label),
+            DuplicateSocketLabel(label) => write!(
+                fmt,
+                "A socket is already bound with the label {:?}",
+                label
+            ),
+            PayloadTooLarge { size, max } => write!(
+                fmt,
+                "The payload is {} bytes, but at most {} bytes can be sent in one message",
+                size, max
+            ),
+            SendBudgetExceeded => write!(
+                fmt,
+                "The message was dropped, since the connection is over its send budget"
+            ),
+            NoSession(addr) => write!(
+                fmt,
+                "No encrypted session could be established with {}",
+                addr
+            ),
+            ChallengeFailed(addr) => write!(
+                fmt,
+                "{} never answered the connection challenge",
+                addr
+            ),
+            ConnectFailed(addr) => write!(
+                fmt,
+                "{} couldn't be reached after retrying the connection",
+                addr
+            ),
+            UnsupportedAddress(addr) => write!(
+                fmt,
+                "The address {} can't be used here, since no socket is bound for its address family",
+                addr
+            ),
+            NotMulticast(addr) => write!(fmt, "The address {} isn't a multicast group", addr),
+            InvalidConfig { field, reason } => {
+                write!(fmt, "The config value `{}` is invalid: {}", field, reason)
+            }
+            ConfigFile(e) => write!(fmt, "The config file could not be loaded: {}", e),
             IOError(e) => write!(fmt, "An IO error occurred: {}", e),
             InternalError(e) => write!(fmt, "An internal error occurred: {}", e),
         }
@@ -3,19 +3,56 @@ use bevy::prelude::*;
 use crossbeam_channel::{Receiver, Sender};
 use std::fmt;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::Mutex;
-
-use laminar::Socket;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use bytes::Bytes;
 use uuid::Uuid;
 
+mod challenge;
+mod chunk;
+mod compression;
+mod config;
+mod delivery;
+mod discovery;
+mod encryption;
 mod error;
+mod master;
+mod multicast;
+mod protocol;
+mod punch;
+mod rate_limit;
+mod reader;
+mod reconnect;
+mod relay;
+mod session;
+mod tick;
+mod token;
 mod transport;
 mod worker;
 
+pub use chunk::TransferId;
+pub use compression::{Compression, CompressionStats};
+pub use config::{NetworkConfig, SocketConfig};
+pub use discovery::{DiscoveryConfig, MAX_DISCOVERY_INFO};
+pub use encryption::Encryption;
 pub use error::NetworkError;
-pub use transport::{LaminarConfig, Transport};
+pub use master::{
+    parse_server_list, MasterRegistration, MasterServer, ServerFilter, ServerInfo, ServerListing,
+    DEFAULT_HEARTBEAT_INTERVAL,
+};
+pub use rate_limit::RateLimit;
+pub use reader::NetworkEventReader;
+pub use reconnect::RetryPolicy;
+pub use tick::NetworkTick;
+pub use token::{ClientAuth, TokenIssuer, MAX_TOKEN_USER_DATA};
+pub use transport::{LaminarConfig, LaminarConfigBuilder, PayloadLimits, Transport};
+
+use compression::SharedCompressionStats;
+use discovery::{Advertiser, Prober};
+use multicast::MulticastGroup;
+use worker::TrackedSocket;
 
 pub struct NetworkingPlugin;
 
@@ -30,10 +67,19 @@ impl SocketHandle {
     }
 }
 
+/// Identifies a message passed to one of the `send` or `broadcast` functions. A broadcast uses one
+/// id for every connection it's sent to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(u64);
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Connection {
     pub addr: SocketAddr,
     pub socket: SocketHandle,
+    /// The multicast group a message was sent to, if it arrived on one the socket joined with
+    /// `join_multicast`. Anyone who can reach the group can send to it, so the sender isn't
+    /// authenticated, and its address isn't necessarily that of a connection.
+    pub multicast: Option<SocketAddr>,
 }
 
 impl fmt::Display for Connection {
@@ -44,12 +90,112 @@ impl fmt::Display for Connection {
 
 #[derive(Debug)]
 pub enum NetworkEvent {
-    Connected(Connection),
+    /// A new connection. If the socket has a `connect_token_key`, this is sent once the client's
+    /// connect token has been accepted, along with who the token was issued to.
+    Connected(Connection, Option<ClientAuth>),
     Disconnected(Connection),
     Message(Connection, Bytes),
-    SendError(NetworkError),
+    /// The worker failed to send the message with this id to the connection
+    SendFailed {
+        id: MessageId,
+        connection: Connection,
+        error: NetworkError,
+    },
+    /// Something went wrong with a bound socket outside of sending a message
+    SocketError(SocketHandle, NetworkError),
+    /// The `NetworkConfig` given to the plugin was invalid, or one of its sockets couldn't be
+    /// bound. None of its sockets are left bound.
+    ConfigFailed(NetworkError),
+    /// The socket has taken on the config given to `NetworkResource::reconfigure`, and sends
+    /// are now checked against its payload limits
+    Reconfigured(SocketHandle, PayloadLimits),
+    /// The socket was closed with `close_socket`, or couldn't get its address back after a
+    /// failed `reconfigure`. A `Disconnected` event is sent for each of its
+    /// connections before this event.
+    SocketClosed(SocketHandle),
+    /// The connection exceeded its inbound rate limit, and its packets are being dropped
+    RateLimited(Connection),
+    /// Part of a large message sent with `send_large` has arrived. Once the final part arrives the
+    /// reassembled message is delivered as a `NetworkEvent::Message`.
+    TransferProgress {
+        connection: Connection,
+        transfer: TransferId,
+        received: usize,
+        total: usize,
+    },
+    /// A connection on a socket with `migrate_connections` has moved to a new address. The old
+    /// connection is replaced by the new one, and messages sent to the old one are redirected.
+    Reconnected {
+        old: Connection,
+        new: Connection,
+    },
+    /// A server connected to with `connect_with_retry` timed out, and the introduction will be
+    /// sent again after a backoff. The connection is kept while it's being retried.
+    Reconnecting {
+        connection: Connection,
+        attempt: u32,
+    },
+    /// A server on the LAN answered a probe sent with `discover`. The address is the server's
+    /// game socket, and the info is whatever it's advertising.
+    ServerDiscovered {
+        addr: SocketAddr,
+        info: Bytes,
+    },
+    /// NAT punch-through started with `punch` reached the peer, which can be sent to as usual
+    PunchSucceeded(Connection),
+    /// NAT punch-through started with `punch` timed out. If the rendezvous server never
+    /// introduced a peer there's no `peer`, otherwise the peer couldn't be reached.
+    PunchFailed {
+        socket: SocketHandle,
+        rendezvous: SocketAddr,
+        peer: Option<SocketAddr>,
+    },
+    /// The relay server given to `relay` didn't pair us with a peer within the timeout
+    RelayFailed {
+        socket: SocketHandle,
+        relay: SocketAddr,
+    },
+    /// The peer acknowledged a message sent with `SendConfig::track_delivery`
+    Delivered {
+        id: MessageId,
+        connection: Connection,
+    },
+    /// A message sent with `SendConfig::track_delivery` will not be acknowledged, either because
+    /// an unreliable message's ack didn't arrive within the socket's `delivery_timeout`, or
+    /// because the connection timed out first
+    Lost {
+        id: MessageId,
+        connection: Connection,
+    },
+}
+impl NetworkEvent {
+    /// The socket this event happened on, if it's tied to one
+    pub fn socket(&self) -> Option<SocketHandle> {
+        match self {
+            NetworkEvent::Connected(conn, _)
+            | NetworkEvent::Disconnected(conn)
+            | NetworkEvent::Message(conn, _)
+            | NetworkEvent::RateLimited(conn)
+            | NetworkEvent::PunchSucceeded(conn) => Some(conn.socket),
+            NetworkEvent::TransferProgress { connection, .. }
+            | NetworkEvent::SendFailed { connection, .. }
+            | NetworkEvent::Reconnecting { connection, .. }
+            | NetworkEvent::Reconnected {
+                new: connection, ..
+            }
+            | NetworkEvent::Delivered { connection, .. }
+            | NetworkEvent::Lost { connection, .. } => Some(connection.socket),
+            NetworkEvent::SocketError(handle, _)
+            | NetworkEvent::Reconfigured(handle, _)
+            | NetworkEvent::SocketClosed(handle)
+            | NetworkEvent::PunchFailed { socket: handle, .. }
+            | NetworkEvent::RelayFailed { socket: handle, .. } => Some(*handle),
+            NetworkEvent::ConfigFailed(_) | NetworkEvent::ServerDiscovered { .. } => None,
+        }
+    }
 }
-#[derive(Debug, Copy, Clone, PartialEq)]
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum NetworkDelivery {
     UnreliableUnordered,
     UnreliableSequenced(Option<u8>),
@@ -58,11 +204,36 @@ pub enum NetworkDelivery {
     ReliableOrdered(Option<u8>),
 }
 
+/// How urgently the worker sends a message when a socket has a send budget. Higher priorities are
+/// sent first, and reliable messages are sent before unreliable ones of the same priority.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SendPriority {
+    /// Unreliable messages are dropped instead of waiting when the budget is spent, and fail with
+    /// `NetworkError::SendBudgetExceeded`
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl NetworkDelivery {
+    pub(crate) fn is_reliable(&self) -> bool {
+        matches!(
+            self,
+            NetworkDelivery::ReliableUnordered
+                | NetworkDelivery::ReliableSequenced(_)
+                | NetworkDelivery::ReliableOrdered(_)
+        )
+    }
+}
+
 pub struct NetworkResource {
     default_socket: Option<SocketHandle>,
 
-    bound_sockets: Vec<SocketHandle>,
+    bound_sockets: Vec<BoundSocket>,
     connections: Vec<Connection>,
+    next_transfer_id: AtomicU32,
+    next_message_id: AtomicU64,
     event_rx: Mutex<Receiver<NetworkEvent>>,
     message_tx: Mutex<Sender<Message>>,
     instruction_tx: Mutex<Sender<WorkerInstructions>>,
@@ -70,10 +241,26 @@ pub struct NetworkResource {
 
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        let network_resource = worker::start_worker_thread();
+        let mut network_resource = worker::start_worker_thread();
+        app.add_event::<NetworkEvent>();
+
+        let bound = match app.resources().get::<NetworkConfig>() {
+            Some(config) => network_resource.bind_config(&config),
+            None => Ok(Vec::new()),
+        };
+        if let Err(e) = bound {
+            app.resources()
+                .get_mut::<Events<NetworkEvent>>()
+                .expect("the network events were just added")
+                .send(NetworkEvent::ConfigFailed(e));
+        }
+
+        if app.resources().get::<NetworkTick>().is_none() {
+            app.add_resource(NetworkTick::default());
+        }
 
-        app.add_event::<NetworkEvent>()
-            .add_resource(network_resource)
+        app.add_resource(network_resource)
+            .add_system_to_stage(stage::PRE_UPDATE, tick::network_tick_system.system())
             .add_system(process_network_events.system());
     }
 }
@@ -114,11 +301,40 @@ impl NetworkResource {
     }
 
     pub fn has_connection(&self, connection: Connection) -> bool {
-        self.connections.iter().any(|c| *c == connection)
+        self.connections.contains(&connection)
     }
 
     pub fn bind<A: ToSocketAddrs>(&mut self, addr: A) -> Result<SocketHandle, NetworkError> {
-        self.bind_with_transport(addr, Transport::Laminar(LaminarConfig::default()))
+        self.bind_with_config(addr, BindConfig::default())
+    }
+
+    /// Binds every socket in the config, in order, so the first socket becomes the default socket.
+    /// If a socket can't be bound, the sockets bound before it are closed again.
+    pub fn bind_config(
+        &mut self,
+        config: &NetworkConfig,
+    ) -> Result<Vec<SocketHandle>, NetworkError> {
+        config.validate()?;
+
+        let mut handles = Vec::with_capacity(config.sockets.len());
+        for s in &config.sockets {
+            let bind_config = BindConfig {
+                transport: Transport::Laminar(s.laminar.clone()),
+                label: s.label.clone(),
+            };
+
+            match self.bind_with_config(s.addr, bind_config) {
+                Ok(handle) => handles.push(handle),
+                Err(e) => {
+                    for handle in handles {
+                        let _ = self.close_socket(handle);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(handles)
     }
 
     pub fn bind_with_transport<A: ToSocketAddrs>(
@@ -126,8 +342,23 @@ impl NetworkResource {
         addr: A,
         transport: Transport,
     ) -> Result<SocketHandle, NetworkError> {
-        match transport {
-            Transport::Laminar(config) => self.bind_with_laminar(addr, config),
+        let config = BindConfig {
+            transport,
+            ..Default::default()
+        };
+
+        self.bind_with_config(addr, config)
+    }
+
+    pub fn bind_with_config<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        config: BindConfig,
+    ) -> Result<SocketHandle, NetworkError> {
+        self.check_label(&config.label)?;
+
+        match config.transport {
+            Transport::Laminar(laminar) => self.bind_with_laminar(addr, laminar, config.label),
         }
     }
 
@@ -135,19 +366,96 @@ impl NetworkResource {
         &mut self,
         addr: A,
         config: LaminarConfig,
+        label: Option<String>,
+    ) -> Result<SocketHandle, NetworkError> {
+        config.validate()?;
+
+        let payload_limits = PayloadLimits::from_config(&config);
+
+        let handle = SocketHandle::new();
+        let tracked = TrackedSocket::bind(handle, addr, config)?;
+
+        self.add_socket(tracked, handle, payload_limits, label)
+    }
+
+    /// Binds an IPv4 and an IPv6 address under a single `SocketHandle`. Messages are sent from
+    /// whichever address matches the destination's address family, and connections on either
+    /// address are reported against the same handle.
+    pub fn bind_dual_stack<A: ToSocketAddrs, B: ToSocketAddrs>(
+        &mut self,
+        v4: A,
+        v6: B,
+    ) -> Result<SocketHandle, NetworkError> {
+        self.bind_dual_stack_with_config(v4, v6, BindConfig::default())
+    }
+
+    pub fn bind_dual_stack_with_config<A: ToSocketAddrs, B: ToSocketAddrs>(
+        &mut self,
+        v4: A,
+        v6: B,
+        config: BindConfig,
+    ) -> Result<SocketHandle, NetworkError> {
+        self.check_label(&config.label)?;
+
+        match config.transport {
+            Transport::Laminar(laminar) => {
+                self.bind_dual_stack_with_laminar(v4, v6, laminar, config.label)
+            }
+        }
+    }
+
+    fn bind_dual_stack_with_laminar<A: ToSocketAddrs, B: ToSocketAddrs>(
+        &mut self,
+        v4: A,
+        v6: B,
+        config: LaminarConfig,
+        label: Option<String>,
     ) -> Result<SocketHandle, NetworkError> {
-        let cfg = config.into();
+        config.validate()?;
+
+        let v4 = resolve(v4, false)?;
+        let v6 = resolve(v6, true)?;
+        let payload_limits = PayloadLimits::from_config(&config);
 
         let handle = SocketHandle::new();
-        let socket = Socket::bind_with_config(addr, cfg)?;
+        let tracked = TrackedSocket::bind_dual_stack(handle, v4, v6, config)?;
+
+        self.add_socket(tracked, handle, payload_limits, label)
+    }
 
-        let instruction = WorkerInstructions::AddSocket(handle, socket);
+    fn check_label(&self, label: &Option<String>) -> Result<(), NetworkError> {
+        match label {
+            Some(label) if self.socket_by_label(label).is_some() => {
+                Err(NetworkError::DuplicateSocketLabel(label.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn add_socket(
+        &mut self,
+        tracked: TrackedSocket,
+        handle: SocketHandle,
+        payload_limits: PayloadLimits,
+        label: Option<String>,
+    ) -> Result<SocketHandle, NetworkError> {
+        let compression_stats = tracked.compression_stats();
+        let challenged = tracked.challenged();
+        let instruction = WorkerInstructions::AddSocket(Box::new(tracked));
         {
             let locked = self.instruction_tx.lock()?;
             locked.send(instruction)?;
         }
 
-        self.bound_sockets.push(handle);
+        self.bound_sockets.push(BoundSocket {
+            handle,
+            label,
+            payload_limits,
+            compression_stats,
+            challenged,
+            advertised: false,
+            multicast_groups: Vec::new(),
+        });
 
         if self.default_socket.is_none() {
             self.default_socket = Some(handle);
@@ -156,16 +464,93 @@ impl NetworkResource {
         Ok(handle)
     }
 
+    /// The socket used when a send doesn't name one. This is the first socket bound, until it's
+    /// changed with `set_default_socket` or the default socket is closed.
+    pub fn default_socket(&self) -> Option<SocketHandle> {
+        self.default_socket
+    }
+
+    pub fn set_default_socket(&mut self, socket: SocketHandle) -> Result<(), NetworkError> {
+        self.get_socket_or_default(Some(socket))?;
+        self.default_socket = Some(socket);
+
+        Ok(())
+    }
+
+    /// Closes a bound socket. If it was the default socket there is no longer a default socket,
+    /// and sends that don't name a socket fail with `NetworkError::NoDefaultSocket` until
+    /// `set_default_socket` is called. Another socket is never promoted in its place.
+    pub fn close_socket(&mut self, socket: SocketHandle) -> Result<(), NetworkError> {
+        if !self.bound_sockets.iter().any(|s| s.handle == socket) {
+            return Err(NetworkError::NoSocket(socket));
+        }
+
+        self.instruction_tx
+            .lock()?
+            .send(WorkerInstructions::CloseSocket(socket))?;
+
+        self.forget_socket(socket);
+
+        Ok(())
+    }
+
+    // the worker closes a socket on its own when it can't get the socket's address back after a
+    // failed rebind, so this is also called when the worker reports a closed socket
+    fn forget_socket(&mut self, socket: SocketHandle) {
+        self.bound_sockets.retain(|s| s.handle != socket);
+
+        if self.default_socket == Some(socket) {
+            self.default_socket = None;
+        }
+    }
+
+    /// Finds a socket by the label it was bound with
+    pub fn socket_by_label(&self, label: &str) -> Option<SocketHandle> {
+        self.bound_sockets
+            .iter()
+            .find(|s| s.label.as_deref() == Some(label))
+            .map(|s| s.handle)
+    }
+
+    /// Applies a new config to a bound socket. If any of the laminar settings have changed the
+    /// socket is transparently rebound on the same address. Connections are kept, but reliable
+    /// messages that are in flight during a rebind may be lost. Once the socket has the new config
+    /// a `NetworkEvent::Reconfigured` is sent, and sends are checked against the new payload
+    /// limits from then on. If the rebind fails, a `NetworkEvent::SocketError` is sent and the
+    /// socket keeps its previous config and limits. If the socket's address can't be bound again
+    /// after a failed rebind, the socket is closed and a `NetworkEvent::SocketClosed` follows.
+    pub fn reconfigure(
+        &mut self,
+        socket: SocketHandle,
+        config: LaminarConfig,
+    ) -> Result<(), NetworkError> {
+        config.validate()?;
+
+        if !self.bound_sockets.iter().any(|s| s.handle == socket) {
+            return Err(NetworkError::NoSocket(socket));
+        }
+
+        self.instruction_tx
+            .lock()?
+            .send(WorkerInstructions::Reconfigure(socket, config))?;
+
+        Ok(())
+    }
+
     pub fn send(
         &self,
         addr: SocketAddr,
         message: &[u8],
         delivery: NetworkDelivery,
-    ) -> Result<(), NetworkError> {
+    ) -> Result<MessageId, NetworkError> {
         self.send_with_config(addr, message, delivery, SendConfig::default())
     }
 
-    pub fn broadcast(&self, message: &[u8], delivery: NetworkDelivery) -> Result<(), NetworkError> {
+    pub fn broadcast(
+        &self,
+        message: &[u8],
+        delivery: NetworkDelivery,
+    ) -> Result<MessageId, NetworkError> {
         self.broadcast_with_config(message, delivery, SendConfig::default())
     }
 
@@ -175,56 +560,530 @@ impl NetworkResource {
         message: &[u8],
         delivery: NetworkDelivery,
         config: SendConfig,
-    ) -> Result<(), NetworkError> {
-        let socket = self.get_socket_or_default(config.socket)?;
+    ) -> Result<MessageId, NetworkError> {
+        let socket = self.get_socket_for_config(&config)?;
+        socket.check_payload(message, delivery, &config)?;
 
+        let id = self.next_message_id();
         let msg = Message {
+            id,
             destination: addr,
             delivery,
-            socket_handle: socket,
-            message: Bytes::copy_from_slice(message),
+            socket_handle: socket.handle,
+            message: encode(id, message, &config),
+            track_delivery: config.track_delivery,
+            priority: config.priority,
         };
 
         self.message_tx.lock()?.send(msg)?;
 
+        Ok(id)
+    }
+
+    /// Sends a message that may be too large for a single packet, such as a map download or a
+    /// save game. The message is split into chunks that are sent with reliable ordered delivery,
+    /// and the receiver gets `NetworkEvent::TransferProgress` events as the chunks arrive. Every
+    /// chunk shares the returned id. `SendConfig::track_delivery` is ignored for large messages.
+    pub fn send_large(
+        &self,
+        addr: SocketAddr,
+        message: &[u8],
+        config: SendConfig,
+    ) -> Result<MessageId, NetworkError> {
+        let socket = self.get_socket_for_config(&config)?;
+
+        if message.len() > u32::MAX as usize {
+            return Err(NetworkError::PayloadTooLarge {
+                size: message.len(),
+                max: u32::MAX as usize,
+            });
+        }
+
+        let id = self.next_message_id();
+        let transfer = TransferId(self.next_transfer_id.fetch_add(1, Ordering::Relaxed));
+        let chunks = chunk::split(transfer, message, socket.payload_limits.chunk_size());
+
+        let locked = self.message_tx.lock()?;
+        for chunk in chunks {
+            locked.send(Message {
+                id,
+                destination: addr,
+                delivery: NetworkDelivery::ReliableOrdered(Some(protocol::CHUNK_STREAM_ID)),
+                socket_handle: socket.handle,
+                message: chunk,
+                track_delivery: false,
+                priority: config.priority,
+            })?;
+        }
+
+        Ok(id)
+    }
+
+    /// Presents a connect token from a `TokenIssuer` to a server that requires one. The server
+    /// ignores everything else the client sends until it accepts the token, so this should be
+    /// the first thing sent to it.
+    pub fn connect_with_token(
+        &self,
+        addr: SocketAddr,
+        token: &[u8],
+        config: SendConfig,
+    ) -> Result<MessageId, NetworkError> {
+        let socket = self.get_socket_for_config(&config)?;
+        let delivery = NetworkDelivery::ReliableUnordered;
+        socket.check_payload(token, delivery, &SendConfig::default())?;
+
+        let id = self.next_message_id();
+        self.message_tx.lock()?.send(Message {
+            id,
+            destination: addr,
+            delivery,
+            socket_handle: socket.handle,
+            message: protocol::encode_connect_token(token),
+            track_delivery: false,
+            priority: SendPriority::High,
+        })?;
+
+        Ok(id)
+    }
+
+    /// Sends an introduction to a server, and sends it again whenever the connection times out,
+    /// waiting longer between each attempt. A `NetworkEvent::Reconnecting` is sent for each
+    /// attempt instead of a `Disconnected`, so the connection survives a short outage. Once the
+    /// policy's attempts run out, the introduction fails with `NetworkError::ConnectFailed` and
+    /// the connection is disconnected.
+    pub fn connect_with_retry(
+        &self,
+        addr: SocketAddr,
+        hello: &[u8],
+        delivery: NetworkDelivery,
+        policy: RetryPolicy,
+        config: SendConfig,
+    ) -> Result<MessageId, NetworkError> {
+        let socket = self.get_socket_for_config(&config)?;
+        socket.check_payload(hello, delivery, &SendConfig::default())?;
+
+        let id = self.next_message_id();
+        let hello = Message {
+            id,
+            destination: addr,
+            delivery,
+            socket_handle: socket.handle,
+            message: protocol::encode_message(hello),
+            track_delivery: false,
+            priority: SendPriority::High,
+        };
+
+        self.instruction_tx
+            .lock()?
+            .send(WorkerInstructions::RetryConnection(hello, policy))?;
+
+        Ok(id)
+    }
+
+    /// Answers LAN discovery probes on the config's port with the given info, such as the server's
+    /// name, player count and version. Clients that probe with `discover` are told the address
+    /// of the socket along with the info. Advertising a socket again only replaces its info.
+    pub fn advertise(
+        &mut self,
+        socket: SocketHandle,
+        config: &DiscoveryConfig,
+        info: &[u8],
+    ) -> Result<(), NetworkError> {
+        discovery::check_info(info)?;
+
+        let bound = self
+            .bound_sockets
+            .iter_mut()
+            .find(|s| s.handle == socket)
+            .ok_or(NetworkError::NoSocket(socket))?;
+        let info = Bytes::copy_from_slice(info);
+
+        let instruction = match bound.advertised {
+            true => WorkerInstructions::AdvertisedInfo(socket, info),
+            false => WorkerInstructions::Advertise(socket, Advertiser::bind(config, info)?),
+        };
+        self.instruction_tx.lock()?.send(instruction)?;
+
+        bound.advertised = true;
+
+        Ok(())
+    }
+
+    pub fn stop_advertising(&mut self, socket: SocketHandle) -> Result<(), NetworkError> {
+        let bound = self
+            .bound_sockets
+            .iter_mut()
+            .find(|s| s.handle == socket)
+            .ok_or(NetworkError::NoSocket(socket))?;
+
+        if bound.advertised {
+            self.instruction_tx
+                .lock()?
+                .send(WorkerInstructions::StopAdvertising(socket))?;
+            bound.advertised = false;
+        }
+
+        Ok(())
+    }
+
+    /// Probes the LAN for servers, which are reported with `NetworkEvent::ServerDiscovered` as
+    /// they answer. Answers are listened for until the config's `listen_for` has passed, and
+    /// each server is only reported once per probe.
+    pub fn discover(&self, config: &DiscoveryConfig) -> Result<(), NetworkError> {
+        let prober = Prober::probe(config)?;
+
+        self.instruction_tx
+            .lock()?
+            .send(WorkerInstructions::Discover(prober))?;
+
+        Ok(())
+    }
+
+    /// Joins the socket to a multicast group, so it gets a `NetworkEvent::Message` for each packet
+    /// sent to the group with `send_multicast`, from the sender with the group in
+    /// `Connection::multicast`. Packets are received on the group's
+    /// port rather than the socket's, which any number of sockets on a machine can share.
+    pub fn join_multicast(
+        &mut self,
+        socket: SocketHandle,
+        group: SocketAddr,
+    ) -> Result<(), NetworkError> {
+        if !group.ip().is_multicast() {
+            return Err(NetworkError::NotMulticast(group));
+        }
+
+        let bound = self
+            .bound_sockets
+            .iter_mut()
+            .find(|s| s.handle == socket)
+            .ok_or(NetworkError::NoSocket(socket))?;
+
+        if !bound.multicast_groups.contains(&group) {
+            let joined = MulticastGroup::join(group)?;
+            self.instruction_tx
+                .lock()?
+                .send(WorkerInstructions::JoinMulticast(socket, joined))?;
+            bound.multicast_groups.push(group);
+        }
+
+        Ok(())
+    }
+
+    pub fn leave_multicast(
+        &mut self,
+        socket: SocketHandle,
+        group: SocketAddr,
+    ) -> Result<(), NetworkError> {
+        let bound = self
+            .bound_sockets
+            .iter_mut()
+            .find(|s| s.handle == socket)
+            .ok_or(NetworkError::NoSocket(socket))?;
+
+        if bound.multicast_groups.contains(&group) {
+            self.instruction_tx
+                .lock()?
+                .send(WorkerInstructions::LeaveMulticast(socket, group))?;
+            bound.multicast_groups.retain(|g| *g != group);
+        }
+
         Ok(())
     }
 
+    /// Sends a message to every socket that has joined the multicast group, such as spectators on
+    /// the LAN. Multicast is always unreliable and unordered, and skips the socket's handshakes,
+    /// compression and encryption, so `SendConfig` only picks the socket it's sent from. Errors
+    /// sending the packet are reported with `NetworkEvent::SocketError`.
+    pub fn send_multicast(
+        &self,
+        group: SocketAddr,
+        message: &[u8],
+        config: SendConfig,
+    ) -> Result<(), NetworkError> {
+        if !group.ip().is_multicast() {
+            return Err(NetworkError::NotMulticast(group));
+        }
+
+        let socket = self.get_socket_for_config(&config)?;
+        let max = socket.payload_limits.unreliable;
+        if message.len() > max {
+            return Err(NetworkError::PayloadTooLarge {
+                size: message.len(),
+                max,
+            });
+        }
+
+        self.instruction_tx
+            .lock()?
+            .send(WorkerInstructions::SendMulticast {
+                socket: socket.handle,
+                group,
+                message: Bytes::copy_from_slice(message),
+            })?;
+
+        Ok(())
+    }
+
+    /// Makes the socket a rendezvous server for NAT punch-through, which introduces each pair of
+    /// peers that call `punch` with the same key to each other. Only peers that have answered the
+    /// socket's challenge are introduced, so the socket needs `LaminarConfig::challenge_connections`.
+    pub fn serve_rendezvous(&self, socket: SocketHandle) -> Result<(), NetworkError> {
+        self.get_socket_or_default(Some(socket))?
+            .require_challenge("a rendezvous server")?;
+
+        self.instruction_tx
+            .lock()?
+            .send(WorkerInstructions::ServeRendezvous(socket))?;
+
+        Ok(())
+    }
+
+    /// Connects to a peer behind a NAT, such as a player hosting a game from home. Both peers
+    /// call this with the same key, such as a lobby id, and the rendezvous server introduces them
+    /// by their public addresses. Each then probes the other until a probe gets through both
+    /// NATs, which is reported with `NetworkEvent::PunchSucceeded`, or with
+    /// `NetworkEvent::PunchFailed` if it doesn't happen within the timeout.
+    ///
+    /// This works through NATs that keep the same public port for every destination. Symmetric
+    /// NATs, which pick a new port for each destination, can't be punched through this way.
+    ///
+    /// The rendezvous server has to challenge us before introducing us, so the socket needs
+    /// `LaminarConfig::challenge_connections`.
+    pub fn punch(
+        &self,
+        rendezvous: SocketAddr,
+        key: &[u8],
+        timeout: Duration,
+        config: SendConfig,
+    ) -> Result<(), NetworkError> {
+        let socket = self.get_socket_for_config(&config)?;
+        socket.require_challenge("NAT punch-through")?;
+
+        self.instruction_tx
+            .lock()?
+            .send(WorkerInstructions::Punch {
+                socket: socket.handle,
+                rendezvous,
+                key: punch::punch_key(key),
+                timeout,
+            })?;
+
+        Ok(())
+    }
+
+    /// Makes the socket a relay server, which pairs up the peers that call `relay` with the same
+    /// key and forwards packets between them. Only peers that have answered the socket's
+    /// challenge are paired, so the socket needs `LaminarConfig::challenge_connections`. A pair
+    /// that forwards nothing for 30 seconds is dropped.
+    pub fn serve_relay(&self, socket: SocketHandle) -> Result<(), NetworkError> {
+        self.get_socket_or_default(Some(socket))?
+            .require_challenge("relaying")?;
+
+        self.instruction_tx
+            .lock()?
+            .send(WorkerInstructions::ServeRelay(socket))?;
+
+        Ok(())
+    }
+
+    /// Connects to a peer through a relay server, for when the peers can't reach each other
+    /// directly, such as when `punch` fails. Both peers call this with the same key, and once the
+    /// relay pairs them and they've answered each other's challenge, each gets a
+    /// `NetworkEvent::Connected` for the other's public address. If the relay doesn't pair them
+    /// within the timeout, `NetworkEvent::RelayFailed` is sent instead.
+    ///
+    /// From then on the peer is an ordinary connection. Everything sent to it goes by way of the
+    /// relay, which forwards it with the same `NetworkDelivery`, and the connection is
+    /// disconnected if either the peer or the relay times out. Relaying adds up to
+    /// 22 bytes to each packet, so unreliable payloads right at the socket's limit can fail.
+    ///
+    /// The relay has to challenge us before pairing us, so the socket needs
+    /// `LaminarConfig::challenge_connections`.
+    pub fn relay(
+        &self,
+        relay: SocketAddr,
+        key: &[u8],
+        timeout: Duration,
+        config: SendConfig,
+    ) -> Result<(), NetworkError> {
+        let socket = self.get_socket_for_config(&config)?;
+        socket.require_challenge("relaying")?;
+
+        self.instruction_tx
+            .lock()?
+            .send(WorkerInstructions::Relay {
+                socket: socket.handle,
+                relay,
+                key: punch::punch_key(key),
+                timeout,
+            })?;
+
+        Ok(())
+    }
+
+    /// Asks a master server for the servers that match the filter. The master answers with a
+    /// message that can be read with `parse_server_list`. Master servers only answer sockets with
+    /// `LaminarConfig::challenge_connections` enabled.
+    pub fn query_master(
+        &self,
+        master: SocketAddr,
+        filter: &ServerFilter,
+        config: SendConfig,
+    ) -> Result<MessageId, NetworkError> {
+        self.send_with_config(
+            master,
+            &master::encode_query(filter),
+            NetworkDelivery::ReliableUnordered,
+            config,
+        )
+    }
+
+    /// How well the socket's outgoing packets have compressed so far
+    pub fn compression_stats(
+        &self,
+        socket: SocketHandle,
+    ) -> Result<CompressionStats, NetworkError> {
+        self.get_socket_or_default(Some(socket))
+            .map(|s| s.compression_stats.snapshot())
+    }
+
+    /// Whether the socket's peers have to answer a challenge before they're connected, which
+    /// follows `reconfigure` once the worker has applied the new config
+    pub fn challenges_connections(&self, socket: SocketHandle) -> Result<bool, NetworkError> {
+        self.get_socket_or_default(Some(socket))
+            .map(|s| s.challenged.load(Ordering::Relaxed))
+    }
+
+    /// The largest payloads that can be passed to `send` on the given socket
+    pub fn payload_limits(&self, socket: SocketHandle) -> Result<PayloadLimits, NetworkError> {
+        self.get_socket_or_default(Some(socket))
+            .map(|s| s.payload_limits)
+    }
+
     pub fn broadcast_with_config(
         &self,
         message: &[u8],
         delivery: NetworkDelivery,
         config: SendConfig,
-    ) -> Result<(), NetworkError> {
-        let socket = self.get_socket_or_default(config.socket)?;
+    ) -> Result<MessageId, NetworkError> {
+        let socket = self.get_socket_for_config(&config)?;
+        socket.check_payload(message, delivery, &config)?;
 
-        let broadcast_to = self.connections_for_socket(socket);
+        let id = self.next_message_id();
+        let broadcast_to = self.connections_for_socket(socket.handle);
+        let message = encode(id, message, &config);
 
         for conn in broadcast_to {
             let msg = Message {
+                id,
                 destination: conn.addr,
                 delivery,
-                socket_handle: socket,
-                message: Bytes::copy_from_slice(message),
+                socket_handle: socket.handle,
+                message: message.clone(),
+                track_delivery: config.track_delivery,
+                priority: config.priority,
             };
 
             self.message_tx.lock()?.send(msg)?;
         }
 
-        Ok(())
+        Ok(id)
+    }
+
+    fn next_message_id(&self) -> MessageId {
+        MessageId(self.next_message_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn get_socket_for_config(&self, config: &SendConfig) -> Result<&BoundSocket, NetworkError> {
+        let socket = match (config.socket, &config.label) {
+            (Some(socket), _) => Some(socket),
+            (None, Some(label)) => Some(
+                self.socket_by_label(label)
+                    .ok_or_else(|| NetworkError::NoSocketLabel(label.clone()))?,
+            ),
+            (None, None) => None,
+        };
+
+        self.get_socket_or_default(socket)
     }
 
     fn get_socket_or_default(
         &self,
         socket: Option<SocketHandle>,
-    ) -> Result<SocketHandle, NetworkError> {
+    ) -> Result<&BoundSocket, NetworkError> {
         let socket = socket
             .or(self.default_socket)
             .ok_or(NetworkError::NoDefaultSocket)?;
 
-        match self.bound_sockets.contains(&socket) {
-            true => Ok(socket),
-            false => Err(NetworkError::NoSocket(socket)),
+        self.bound_sockets
+            .iter()
+            .find(|s| s.handle == socket)
+            .ok_or(NetworkError::NoSocket(socket))
+    }
+}
+
+fn encode(id: MessageId, message: &[u8], config: &SendConfig) -> Bytes {
+    match config.track_delivery {
+        true => protocol::encode_tracked(id.0, message),
+        false => protocol::encode_message(message),
+    }
+}
+
+fn resolve<A: ToSocketAddrs>(addr: A, ipv6: bool) -> Result<SocketAddr, NetworkError> {
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+
+    addrs
+        .iter()
+        .find(|a| a.is_ipv6() == ipv6)
+        .copied()
+        .ok_or_else(|| match addrs.first() {
+            Some(a) => NetworkError::UnsupportedAddress(*a),
+            None => NetworkError::IOError(std::io::ErrorKind::InvalidInput.into()),
+        })
+}
+
+struct BoundSocket {
+    handle: SocketHandle,
+    label: Option<String>,
+    payload_limits: PayloadLimits,
+    compression_stats: Arc<SharedCompressionStats>,
+    challenged: Arc<AtomicBool>,
+    advertised: bool,
+    multicast_groups: Vec<SocketAddr>,
+}
+
+impl BoundSocket {
+    fn require_challenge(&self, feature: &str) -> Result<(), NetworkError> {
+        match self.challenged.load(Ordering::Relaxed) {
+            true => Ok(()),
+            false => Err(NetworkError::InvalidConfig {
+                field: "challenge_connections",
+                reason: format!("{} requires challenge_connections", feature),
+            }),
+        }
+    }
+
+    fn check_payload(
+        &self,
+        message: &[u8],
+        delivery: NetworkDelivery,
+        config: &SendConfig,
+    ) -> Result<(), NetworkError> {
+        let overhead = match config.track_delivery {
+            true => protocol::TRACKED_HEADER_SIZE,
+            false => 0,
+        };
+        let max = self
+            .payload_limits
+            .for_delivery(delivery)
+            .saturating_sub(overhead);
+
+        match message.len() > max {
+            true => Err(NetworkError::PayloadTooLarge {
+                size: message.len(),
+                max,
+            }),
+            false => Ok(()),
         }
     }
 }
@@ -236,21 +1095,80 @@ impl Drop for NetworkResource {
     }
 }
 
+#[derive(Default)]
+pub struct BindConfig {
+    pub transport: Transport,
+    /// A name the socket can be found by with `socket_by_label`, or targeted by with `SendConfig`
+    pub label: Option<String>,
+}
+
 #[derive(Default)]
 pub struct SendConfig {
-    pub socket: Option<SocketHandle>, // if none, use the default socket
+    pub socket: Option<SocketHandle>, // if none, use the label or the default socket
+    pub label: Option<String>,
+    /// Report a `NetworkEvent::Delivered` or `NetworkEvent::Lost` for the message. Tracked
+    /// messages carry their id, so their payload limit is 8 bytes smaller.
+    pub track_delivery: bool,
+    pub priority: SendPriority,
 }
 
-#[derive(Debug)]
+impl SendConfig {
+    pub fn for_socket(socket: SocketHandle) -> Self {
+        SendConfig {
+            socket: Some(socket),
+            ..Default::default()
+        }
+    }
+
+    pub fn for_label(label: &str) -> Self {
+        SendConfig {
+            label: Some(label.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Message {
+    id: MessageId,
     message: Bytes,
     delivery: NetworkDelivery,
     socket_handle: SocketHandle,
     destination: SocketAddr,
+    track_delivery: bool,
+    priority: SendPriority,
 }
 
 enum WorkerInstructions {
-    AddSocket(SocketHandle, Socket),
+    AddSocket(Box<TrackedSocket>),
+    Reconfigure(SocketHandle, LaminarConfig),
+    RetryConnection(Message, RetryPolicy),
+    Advertise(SocketHandle, Advertiser),
+    AdvertisedInfo(SocketHandle, Bytes),
+    StopAdvertising(SocketHandle),
+    Discover(Prober),
+    JoinMulticast(SocketHandle, MulticastGroup),
+    LeaveMulticast(SocketHandle, SocketAddr),
+    SendMulticast {
+        socket: SocketHandle,
+        group: SocketAddr,
+        message: Bytes,
+    },
+    ServeRendezvous(SocketHandle),
+    Punch {
+        socket: SocketHandle,
+        rendezvous: SocketAddr,
+        key: punch::PunchKey,
+        timeout: Duration,
+    },
+    ServeRelay(SocketHandle),
+    Relay {
+        socket: SocketHandle,
+        relay: SocketAddr,
+        key: punch::PunchKey,
+        timeout: Duration,
+    },
+    CloseSocket(SocketHandle),
     Terminate,
 }
 
@@ -258,10 +1176,11 @@ fn process_network_events(
     mut net: ResMut<NetworkResource>,
     mut network_events: ResMut<Events<NetworkEvent>>,
 ) {
-    let mut added_connections: Vec<Connection> = Vec::new();
+    let mut added_connections: Vec<(Connection, Option<ClientAuth>)> = Vec::new();
     let mut removed_connections: Vec<Connection> = Vec::new();
+    let mut closed_sockets: Vec<SocketHandle> = Vec::new();
 
-    {
+    let drained: Vec<NetworkEvent> = {
         let locked = match net.event_rx.lock() {
             Ok(l) => l,
             // this system is the only consumer of `event_rx`, so if this lock is poisoned that means
@@ -271,32 +1190,64 @@ fn process_network_events(
             Err(p) => p.into_inner(),
         };
 
-        while let Ok(event) = locked.try_recv() {
-            match event {
-                NetworkEvent::Connected(conn) => {
-                    if !net.has_connection(conn) && !added_connections.contains(&conn) {
-                        added_connections.push(conn);
-                    }
+        // the lock borrows `net`, so it's released before the connections are updated
+        locked.try_iter().collect()
+    };
+
+    for event in drained {
+        match event {
+            NetworkEvent::Connected(conn, auth) => {
+                if !net.has_connection(conn) && !added_connections.iter().any(|(c, _)| *c == conn) {
+                    added_connections.push((conn, auth));
                 }
-                NetworkEvent::Disconnected(conn) => {
-                    if net.has_connection(conn) && !removed_connections.contains(&conn) {
-                        removed_connections.push(conn);
-                    }
+            }
+            NetworkEvent::Disconnected(conn) => {
+                if net.has_connection(conn) && !removed_connections.contains(&conn) {
+                    removed_connections.push(conn);
+                }
+            }
+            NetworkEvent::Reconnected { old, new } => {
+                // the connection may have moved in the same frame it connected
+                if let Some(added) = added_connections.iter_mut().find(|(c, _)| *c == old) {
+                    added.0 = new;
+                } else if net.has_connection(old) {
+                    net.remove_connection(old);
+                    net.add_connection(new);
                 }
-                _ => network_events.send(event),
+                network_events.send(event);
             }
+            NetworkEvent::Reconfigured(handle, limits) => {
+                // the socket may have been closed since it was reconfigured
+                if let Some(bound) = net.bound_sockets.iter_mut().find(|s| s.handle == handle) {
+                    bound.payload_limits = limits;
+                }
+                network_events.send(event);
+            }
+            NetworkEvent::SocketClosed(handle) => closed_sockets.push(handle),
+            _ => network_events.send(event),
         }
     }
 
-    for conn in added_connections {
+    for (conn, auth) in added_connections {
         net.add_connection(conn);
-        network_events.send(NetworkEvent::Connected(conn));
+        network_events.send(NetworkEvent::Connected(conn, auth));
     }
 
     for conn in removed_connections {
         net.remove_connection(conn);
         network_events.send(NetworkEvent::Disconnected(conn));
     }
+
+    for handle in closed_sockets {
+        net.forget_socket(handle);
+
+        for conn in net.connections_for_socket(handle) {
+            net.remove_connection(conn);
+            network_events.send(NetworkEvent::Disconnected(conn));
+        }
+
+        network_events.send(NetworkEvent::SocketClosed(handle));
+    }
 }
 
 #[cfg(test)]
@@ -317,4 +1268,656 @@ mod tests {
         assert!(network_resource.bind("127.0.0.1:12591").is_ok());
         assert!(network_resource.default_socket.is_some());
     }
+
+    #[test]
+    fn closing_the_default_socket_clears_it() {
+        let mut network_resource = worker::start_worker_thread();
+
+        let first = network_resource.bind("127.0.0.1:12602").unwrap();
+        let second = network_resource.bind("127.0.0.1:12603").unwrap();
+        assert_eq!(network_resource.default_socket(), Some(first));
+
+        network_resource.set_default_socket(second).unwrap();
+        assert_eq!(network_resource.default_socket(), Some(second));
+
+        network_resource.close_socket(second).unwrap();
+        assert_eq!(network_resource.default_socket(), None);
+        assert!(matches!(
+            network_resource.set_default_socket(second),
+            Err(NetworkError::NoSocket(_))
+        ));
+
+        let to: SocketAddr = "127.0.0.1:12604".parse().unwrap();
+        let result = network_resource.send(to, b"hi", NetworkDelivery::ReliableUnordered);
+        assert!(matches!(result, Err(NetworkError::NoDefaultSocket)));
+    }
+
+    #[test]
+    fn sending_an_oversized_payload_fails() {
+        let mut network_resource = worker::start_worker_thread();
+        let handle = network_resource.bind("127.0.0.1:12592").unwrap();
+
+        let limits = network_resource.payload_limits(handle).unwrap();
+        let message = vec![0; limits.unreliable + 1];
+        let to: SocketAddr = "127.0.0.1:12593".parse().unwrap();
+
+        let result = network_resource.send(to, &message, NetworkDelivery::UnreliableUnordered);
+        assert!(matches!(result, Err(NetworkError::PayloadTooLarge { .. })));
+
+        let result = network_resource.send(to, &message, NetworkDelivery::ReliableUnordered);
+        assert!(result.is_ok());
+    }
+
+    fn next_event<T, F: Fn(NetworkEvent) -> Option<T>>(
+        network_resource: &NetworkResource,
+        filter: F,
+    ) -> Option<T> {
+        let events = network_resource.event_rx.lock().unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+
+        while let Some(timeout) = deadline.checked_duration_since(std::time::Instant::now()) {
+            match events.recv_timeout(timeout) {
+                Ok(event) => {
+                    if let Some(found) = filter(event) {
+                        return Some(found);
+                    }
+                }
+                Err(_) => return None,
+            }
+        }
+
+        None
+    }
+
+    fn next_message(network_resource: &NetworkResource) -> Option<(Connection, Bytes)> {
+        next_event(network_resource, |e| match e {
+            NetworkEvent::Message(conn, msg) => Some((conn, msg)),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn dual_stack_sockets_receive_on_both_families() {
+        let mut server = worker::start_worker_thread();
+        let handle = server
+            .bind_dual_stack("127.0.0.1:12594", "[::1]:12594")
+            .unwrap();
+
+        let mut v4_client = worker::start_worker_thread();
+        v4_client.bind("127.0.0.1:12595").unwrap();
+        v4_client
+            .send(
+                "127.0.0.1:12594".parse().unwrap(),
+                b"v4",
+                NetworkDelivery::ReliableUnordered,
+            )
+            .unwrap();
+
+        let (conn, msg) = next_message(&server).expect("no message over IPv4");
+        assert_eq!((conn.socket, &msg[..]), (handle, &b"v4"[..]));
+
+        let mut v6_client = worker::start_worker_thread();
+        v6_client.bind("[::1]:12596").unwrap();
+        v6_client
+            .send(
+                "[::1]:12594".parse().unwrap(),
+                b"v6",
+                NetworkDelivery::ReliableUnordered,
+            )
+            .unwrap();
+
+        let (conn, msg) = next_message(&server).expect("no message over IPv6");
+        assert_eq!((conn.socket, &msg[..]), (handle, &b"v6"[..]));
+        assert!(conn.addr.is_ipv6());
+
+        // replies are routed out of the endpoint matching the destination's family
+        server
+            .send(conn.addr, b"reply", NetworkDelivery::ReliableUnordered)
+            .unwrap();
+        let (_, msg) = next_message(&v6_client).expect("no reply over IPv6");
+        assert_eq!(&msg[..], b"reply");
+    }
+
+    #[test]
+    fn sockets_can_be_found_and_targeted_by_label() {
+        let mut network_resource = worker::start_worker_thread();
+
+        let config = BindConfig {
+            label: Some("lobby".to_string()),
+            ..Default::default()
+        };
+        let handle = network_resource
+            .bind_with_config("127.0.0.1:12599", config)
+            .unwrap();
+        assert_eq!(network_resource.socket_by_label("lobby"), Some(handle));
+        assert_eq!(network_resource.socket_by_label("game"), None);
+
+        let config = BindConfig {
+            label: Some("lobby".to_string()),
+            ..Default::default()
+        };
+        let result = network_resource.bind_with_config("127.0.0.1:12600", config);
+        assert!(matches!(result, Err(NetworkError::DuplicateSocketLabel(_))));
+
+        let to: SocketAddr = "127.0.0.1:12601".parse().unwrap();
+        let delivery = NetworkDelivery::ReliableUnordered;
+        let result =
+            network_resource.send_with_config(to, b"hi", delivery, SendConfig::for_label("game"));
+        assert!(matches!(result, Err(NetworkError::NoSocketLabel(_))));
+
+        let result =
+            network_resource.send_with_config(to, b"hi", delivery, SendConfig::for_label("lobby"));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn a_config_that_fails_to_bind_leaves_nothing_bound() {
+        let mut network_resource = worker::start_worker_thread();
+        let taken = network_resource.bind("127.0.0.1:12628").unwrap();
+
+        let socket = |addr: &str, label: &str| SocketConfig {
+            addr: addr.parse().unwrap(),
+            label: Some(label.to_string()),
+            laminar: LaminarConfig::default(),
+        };
+        let config = NetworkConfig {
+            sockets: vec![
+                socket("127.0.0.1:12629", "game"),
+                socket("127.0.0.1:12628", "admin"),
+            ],
+        };
+
+        assert!(network_resource.bind_config(&config).is_err());
+        assert_eq!(network_resource.socket_by_label("game"), None);
+        assert_eq!(network_resource.default_socket(), Some(taken));
+    }
+
+    #[test]
+    fn dual_stack_requires_one_address_per_family() {
+        let mut network_resource = worker::start_worker_thread();
+
+        let result = network_resource.bind_dual_stack("127.0.0.1:12597", "127.0.0.1:12598");
+        assert!(matches!(result, Err(NetworkError::UnsupportedAddress(_))));
+    }
+
+    #[test]
+    fn ordered_messages_arrive_in_order() {
+        let mut server = worker::start_worker_thread();
+        server.bind("127.0.0.1:12631").unwrap();
+
+        let mut client = worker::start_worker_thread();
+        client.bind("127.0.0.1:12632").unwrap();
+
+        let to: SocketAddr = "127.0.0.1:12631".parse().unwrap();
+        let mut received = Vec::new();
+        for i in 1..=3u8 {
+            client
+                .send(to, &[i], NetworkDelivery::ReliableOrdered(Some(1)))
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(400));
+
+            while let Some((_, msg)) = next_message(&server) {
+                received.push(msg[0]);
+            }
+        }
+
+        assert_eq!(received, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn tracked_messages_report_their_delivery() {
+        let mut server = worker::start_worker_thread();
+        server.bind("127.0.0.1:12605").unwrap();
+
+        let mut client = worker::start_worker_thread();
+        client.bind("127.0.0.1:12606").unwrap();
+
+        let config = SendConfig {
+            track_delivery: true,
+            ..Default::default()
+        };
+        let to: SocketAddr = "127.0.0.1:12605".parse().unwrap();
+        let id = client
+            .send_with_config(
+                to,
+                b"snapshot",
+                NetworkDelivery::UnreliableUnordered,
+                config,
+            )
+            .unwrap();
+
+        let (_, msg) = next_message(&server).expect("no tracked message");
+        assert_eq!(&msg[..], b"snapshot");
+
+        let delivered = next_event(&client, |e| match e {
+            NetworkEvent::Delivered { id, connection } => Some((id, connection)),
+            _ => None,
+        });
+        assert_eq!(delivered.map(|(id, c)| (id, c.addr)), Some((id, to)));
+    }
+
+    #[test]
+    fn reconfigured_limits_wait_for_the_worker() {
+        let mut client = worker::start_worker_thread();
+        let handle = client.bind("127.0.0.1:12627").unwrap();
+        let before = client.payload_limits(handle).unwrap();
+
+        let laminar = LaminarConfig::builder()
+            .fragment_size(512)
+            .max_packet_size(8 * 1024)
+            .build()
+            .unwrap();
+        client.reconfigure(handle, laminar.clone()).unwrap();
+        assert_eq!(client.payload_limits(handle).unwrap(), before);
+
+        let reconfigured = next_event(&client, |e| match e {
+            NetworkEvent::Reconfigured(socket, limits) => Some((socket, limits)),
+            _ => None,
+        });
+        assert_eq!(
+            reconfigured,
+            Some((handle, PayloadLimits::from_config(&laminar)))
+        );
+    }
+
+    #[test]
+    fn reconfigured_limits_are_applied_by_the_plugin() {
+        let mut client = worker::start_worker_thread();
+        let handle = client.bind("127.0.0.1:12630").unwrap();
+
+        let laminar = LaminarConfig::builder()
+            .fragment_size(512)
+            .max_packet_size(8 * 1024)
+            .build()
+            .unwrap();
+        client.reconfigure(handle, laminar.clone()).unwrap();
+
+        let mut resources = Resources::default();
+        resources.insert(client);
+        resources.insert(Events::<NetworkEvent>::default());
+        let world = World::new();
+        let mut system = process_network_events.system();
+
+        let expected = PayloadLimits::from_config(&laminar);
+        let applied = || {
+            let net = resources.get::<NetworkResource>().unwrap();
+            net.payload_limits(handle).unwrap() == expected
+        };
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while !applied() && std::time::Instant::now() < deadline {
+            system.run(&world, &resources);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(applied());
+    }
+
+    #[test]
+    fn low_priority_messages_are_dropped_over_budget() {
+        let mut client = worker::start_worker_thread();
+
+        let budget = RateLimit {
+            packets_per_second: Some(1),
+            bytes_per_second: None,
+        };
+        let laminar = LaminarConfig::builder()
+            .connection_send_budget(Some(budget))
+            .build()
+            .unwrap();
+        client
+            .bind_with_transport("127.0.0.1:12607", Transport::Laminar(laminar))
+            .unwrap();
+
+        let to: SocketAddr = "127.0.0.1:12608".parse().unwrap();
+        let delivery = NetworkDelivery::UnreliableUnordered;
+        let low = || SendConfig {
+            priority: SendPriority::Low,
+            ..Default::default()
+        };
+
+        // the first message uses up the budget, whichever order the worker picks the rest up in
+        client.send(to, b"first", delivery).unwrap();
+        let second = client
+            .send_with_config(to, b"second", delivery, low())
+            .unwrap();
+        let third = client
+            .send_with_config(to, b"third", delivery, low())
+            .unwrap();
+        // normal priority messages wait for the budget instead
+        client.send(to, b"fourth", delivery).unwrap();
+
+        let mut failed = Vec::new();
+        while let Some(found) = next_event(&client, |e| match e {
+            NetworkEvent::SendFailed { id, error, .. } => Some((id, error)),
+            _ => None,
+        }) {
+            failed.push(found);
+        }
+
+        assert_eq!(failed.len(), 2);
+        assert!(matches!(failed[0], (id, NetworkError::SendBudgetExceeded) if id == second));
+        assert!(matches!(failed[1], (id, NetworkError::SendBudgetExceeded) if id == third));
+    }
+
+    #[test]
+    fn held_back_messages_keep_their_stream_order() {
+        let mut server = worker::start_worker_thread();
+        server.bind("127.0.0.1:12625").unwrap();
+
+        let mut client = worker::start_worker_thread();
+        let budget = RateLimit {
+            packets_per_second: None,
+            bytes_per_second: Some(100),
+        };
+        let laminar = LaminarConfig::builder()
+            .connection_send_budget(Some(budget))
+            .build()
+            .unwrap();
+        client
+            .bind_with_transport("127.0.0.1:12626", Transport::Laminar(laminar))
+            .unwrap();
+
+        // the small message fits in what's left of the budget, but it mustn't overtake the
+        // medium one that's held back
+        let to: SocketAddr = "127.0.0.1:12625".parse().unwrap();
+        let delivery = NetworkDelivery::ReliableOrdered(Some(1));
+        for message in &[&[1; 90][..], &[2; 50][..], &[3; 5][..]] {
+            client.send(to, message, delivery).unwrap();
+        }
+
+        let mut first_bytes = Vec::new();
+        for _ in 0..3 {
+            let (_, msg) = next_message(&server).expect("a message didn't arrive");
+            first_bytes.push(msg[0]);
+        }
+        assert_eq!(first_bytes, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn coalesced_messages_arrive_separately() {
+        let mut server = worker::start_worker_thread();
+        server.bind("127.0.0.1:12609").unwrap();
+
+        let mut client = worker::start_worker_thread();
+        let laminar = LaminarConfig::builder()
+            .coalesce_messages(true)
+            .build()
+            .unwrap();
+        client
+            .bind_with_transport("127.0.0.1:12610", Transport::Laminar(laminar))
+            .unwrap();
+
+        let to: SocketAddr = "127.0.0.1:12609".parse().unwrap();
+        for msg in &["one", "two", "three"] {
+            client
+                .send(to, msg.as_bytes(), NetworkDelivery::ReliableOrdered(None))
+                .unwrap();
+        }
+
+        let received: Vec<Bytes> = (0..3)
+            .filter_map(|_| next_message(&server).map(|(_, msg)| msg))
+            .collect();
+        assert_eq!(received, vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn servers_only_accept_clients_with_a_connect_token() {
+        let key = [3; 32];
+        let to: SocketAddr = "127.0.0.1:12611".parse().unwrap();
+
+        let mut server = worker::start_worker_thread();
+        let laminar = LaminarConfig::builder()
+            .connect_token_key(Some(key))
+            .build()
+            .unwrap();
+        server
+            .bind_with_transport(to, Transport::Laminar(laminar))
+            .unwrap();
+
+        let mut client = worker::start_worker_thread();
+        client.bind("127.0.0.1:12612").unwrap();
+
+        client
+            .send(to, b"sneaky", NetworkDelivery::ReliableUnordered)
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let token = TokenIssuer::new(key)
+            .issue(7, to, std::time::Duration::from_secs(10), b"red team")
+            .unwrap();
+        client
+            .connect_with_token(to, &token, SendConfig::default())
+            .unwrap();
+        client
+            .send(to, b"hello", NetworkDelivery::ReliableUnordered)
+            .unwrap();
+
+        let auth = next_event(&server, |e| match e {
+            NetworkEvent::Connected(_, auth) => Some(auth),
+            _ => None,
+        });
+        let auth = auth.expect("no connection").expect("no client auth");
+        assert_eq!(auth.client_id, 7);
+        assert_eq!(&auth.user_data[..], b"red team");
+
+        let (_, msg) = next_message(&server).expect("no message");
+        assert_eq!(&msg[..], b"hello");
+    }
+
+    #[test]
+    fn challenged_peers_connect_before_sending() {
+        let laminar = LaminarConfig::builder()
+            .challenge_connections(true)
+            .build()
+            .unwrap();
+
+        let mut server = worker::start_worker_thread();
+        server
+            .bind_with_transport("127.0.0.1:12613", Transport::Laminar(laminar.clone()))
+            .unwrap();
+
+        let mut client = worker::start_worker_thread();
+        client
+            .bind_with_transport("127.0.0.1:12614", Transport::Laminar(laminar))
+            .unwrap();
+
+        let to: SocketAddr = "127.0.0.1:12613".parse().unwrap();
+        client
+            .send(to, b"hello", NetworkDelivery::ReliableOrdered(None))
+            .unwrap();
+
+        let connected = next_event(&server, |e| match e {
+            NetworkEvent::Connected(conn, _) => Some(conn.addr),
+            _ => None,
+        });
+        assert_eq!(connected, Some("127.0.0.1:12614".parse().unwrap()));
+
+        let (_, msg) = next_message(&server).expect("no message");
+        assert_eq!(&msg[..], b"hello");
+    }
+
+    #[test]
+    fn clients_retry_until_the_server_is_up() {
+        let laminar = LaminarConfig::builder()
+            .idle_connection_timeout(std::time::Duration::from_millis(200))
+            .heartbeat_interval(Some(std::time::Duration::from_millis(50)))
+            .build()
+            .unwrap();
+        let policy = RetryPolicy {
+            initial_delay: std::time::Duration::from_millis(50),
+            max_delay: std::time::Duration::from_millis(200),
+            max_attempts: None,
+        };
+
+        let mut client = worker::start_worker_thread();
+        client
+            .bind_with_transport("127.0.0.1:12616", Transport::Laminar(laminar.clone()))
+            .unwrap();
+
+        let to: SocketAddr = "127.0.0.1:12615".parse().unwrap();
+        client
+            .connect_with_retry(
+                to,
+                b"hello",
+                NetworkDelivery::ReliableUnordered,
+                policy,
+                SendConfig::default(),
+            )
+            .unwrap();
+
+        // nobody is listening yet, so the first connection times out
+        let attempt = next_event(&client, |e| match e {
+            NetworkEvent::Reconnecting { attempt, .. } => Some(attempt),
+            NetworkEvent::Disconnected(_) => panic!("the client gave up"),
+            _ => None,
+        });
+        assert_eq!(attempt, Some(1));
+
+        let mut server = worker::start_worker_thread();
+        server
+            .bind_with_transport(to, Transport::Laminar(laminar))
+            .unwrap();
+
+        let (conn, msg) = next_message(&server).expect("no introduction");
+        assert_eq!(conn.addr, "127.0.0.1:12616".parse().unwrap());
+        assert_eq!(&msg[..], b"hello");
+    }
+
+    #[test]
+    fn servers_can_be_discovered_on_loopback() {
+        let config = DiscoveryConfig {
+            port: 12618,
+            broadcast_addr: std::net::Ipv4Addr::LOCALHOST,
+            ..Default::default()
+        };
+
+        let mut server = worker::start_worker_thread();
+        let handle = server.bind("127.0.0.1:12617").unwrap();
+        server.advertise(handle, &config, b"lobby 1/8").unwrap();
+        server.advertise(handle, &config, b"lobby 2/8").unwrap();
+
+        // the worker has to have the advertiser before the probe arrives
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let client = worker::start_worker_thread();
+        client.discover(&config).unwrap();
+
+        let found = next_event(&client, |e| match e {
+            NetworkEvent::ServerDiscovered { addr, info } => Some((addr, info)),
+            _ => None,
+        });
+        let (addr, info) = found.expect("no server was discovered");
+        assert_eq!(addr, "127.0.0.1:12617".parse().unwrap());
+        assert_eq!(&info[..], b"lobby 2/8");
+    }
+
+    #[test]
+    fn multicast_messages_arrive_from_the_sender_with_their_group() {
+        let group: SocketAddr = "239.255.42.2:12624".parse().unwrap();
+
+        let mut spectator = worker::start_worker_thread();
+        let handle = spectator.bind("127.0.0.1:12623").unwrap();
+        assert!(matches!(
+            spectator.join_multicast(handle, "127.0.0.1:12624".parse().unwrap()),
+            Err(NetworkError::NotMulticast(_))
+        ));
+        spectator.join_multicast(handle, group).unwrap();
+        spectator.join_multicast(handle, group).unwrap();
+
+        // the worker has to have joined the group before the message is sent
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let mut server = worker::start_worker_thread();
+        server.bind("127.0.0.1:12622").unwrap();
+        server
+            .send_multicast(group, b"kickoff", SendConfig::default())
+            .unwrap();
+
+        let (conn, data) = next_message(&spectator).expect("no multicast message arrived");
+        // the sender's address is wherever the packet came from, not a connection
+        assert_eq!(conn.socket, handle);
+        assert_eq!(conn.multicast, Some(group));
+        assert_eq!(&data[..], b"kickoff");
+
+        spectator.leave_multicast(handle, group).unwrap();
+    }
+
+    #[test]
+    fn servers_register_with_the_master_and_clients_query_it() {
+        let master_addr: SocketAddr = "127.0.0.1:12619".parse().unwrap();
+
+        let challenged = || {
+            let laminar = LaminarConfig::builder()
+                .challenge_connections(true)
+                .build()
+                .unwrap();
+            Transport::Laminar(laminar)
+        };
+
+        let mut master_net = worker::start_worker_thread();
+        master_net
+            .bind_with_transport(master_addr, challenged())
+            .unwrap();
+        let mut master = MasterServer::new(None, std::time::Duration::from_secs(30));
+
+        let mut server = worker::start_worker_thread();
+        server
+            .bind_with_transport("127.0.0.1:12620", challenged())
+            .unwrap();
+        let info = ServerInfo {
+            name: "Test server".to_string(),
+            version: "1.0".to_string(),
+            players: 1,
+            max_players: 4,
+            ..Default::default()
+        };
+        let mut registration = MasterRegistration::new(master_addr, info.clone());
+        registration
+            .heartbeat(&server, std::time::Instant::now())
+            .unwrap();
+
+        let mut client = worker::start_worker_thread();
+        client
+            .bind_with_transport("127.0.0.1:12621", challenged())
+            .unwrap();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        while master.servers().is_empty() && std::time::Instant::now() < deadline {
+            if let Some(event) = next_event(&master_net, Some) {
+                master
+                    .handle_event(&master_net, &event, std::time::Instant::now())
+                    .unwrap();
+            }
+        }
+        assert_eq!(master.servers().len(), 1);
+
+        let filter = ServerFilter {
+            version: Some("1.0".to_string()),
+            ..Default::default()
+        };
+        client
+            .query_master(master_addr, &filter, SendConfig::default())
+            .unwrap();
+
+        let list = loop {
+            let event = next_event(&master_net, Some).expect("no query");
+            master
+                .handle_event(&master_net, &event, std::time::Instant::now())
+                .unwrap();
+
+            if let Some(list) = next_event(&client, |e| match e {
+                NetworkEvent::Message(_, msg) => parse_server_list(&msg),
+                _ => None,
+            }) {
+                break list;
+            }
+        };
+        assert_eq!(
+            list,
+            vec![ServerListing {
+                addr: "127.0.0.1:12620".parse().unwrap(),
+                info,
+            }]
+        );
+    }
 }
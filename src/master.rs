@@ -0,0 +1,559 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use super::token::take;
+use super::{
+    Connection, NetworkDelivery, NetworkError, NetworkEvent, NetworkResource, SendConfig,
+    SocketHandle,
+};
+
+const MAGIC: &[u8; 4] = b"BPNM";
+const KIND_REGISTER: u8 = 0;
+const KIND_UNREGISTER: u8 = 1;
+const KIND_QUERY: u8 = 2;
+const KIND_LIST: u8 = 3;
+
+const FILTER_HIDE_FULL: u8 = 0b0001;
+const FILTER_HIDE_EMPTY: u8 = 0b0010;
+const FILTER_VERSION: u8 = 0b0100;
+const FILTER_NAME: u8 = 0b1000;
+
+// the most servers that can be registered from one IP address, and in total
+const MAX_SERVERS_PER_IP: usize = 16;
+const MAX_SERVERS: usize = 4096;
+
+/// How often a `MasterRegistration` heartbeats by default
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What a game server tells the master server about itself
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: String,
+    pub players: u16,
+    pub max_players: u16,
+    /// Anything else the game wants to list, such as the map or game mode
+    pub user_data: Bytes,
+}
+
+/// A server in the list returned by the master server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerListing {
+    /// The address the server registered from, which is where clients can connect to it
+    pub addr: SocketAddr,
+    pub info: ServerInfo,
+}
+
+/// Which servers a query to the master server should return
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ServerFilter {
+    /// Only servers whose name contains this, ignoring case
+    pub name_contains: Option<String>,
+    /// Only servers with exactly this version
+    pub version: Option<String>,
+    pub hide_full: bool,
+    pub hide_empty: bool,
+}
+
+impl ServerFilter {
+    pub fn matches(&self, info: &ServerInfo) -> bool {
+        if self.hide_full && info.players >= info.max_players {
+            return false;
+        }
+        if self.hide_empty && info.players == 0 {
+            return false;
+        }
+        if let Some(version) = &self.version {
+            if *version != info.version {
+                return false;
+            }
+        }
+        if let Some(name) = &self.name_contains {
+            if !info.name.to_lowercase().contains(&name.to_lowercase()) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// The bookkeeping for a master server, which game servers register with and clients query for
+/// the servers they can join. It runs on a socket bound with `NetworkResource`, and is driven by
+/// passing it that socket's events with `handle_event`, and calling `expire` every so often.
+///
+/// Servers have to heartbeat with a `MasterRegistration`, and are dropped from the list when
+/// they haven't been heard from for the `expire_after` duration, or their connection times out.
+/// At most 16 servers can register from one IP address, and 4096 in total.
+///
+/// A server list can be far larger than the query that asked for it, so the socket has to have
+/// `LaminarConfig::challenge_connections` enabled, and queries on any other socket fail with
+/// `NetworkError::InvalidConfig`.
+pub struct MasterServer {
+    socket: Option<SocketHandle>,
+    expire_after: Duration,
+    servers: HashMap<SocketAddr, Registration>,
+}
+
+struct Registration {
+    info: ServerInfo,
+    heard_at: Instant,
+}
+
+impl MasterServer {
+    /// A master server for the given socket, or for every socket if it's `None`
+    pub fn new(socket: Option<SocketHandle>, expire_after: Duration) -> Self {
+        MasterServer {
+            socket,
+            expire_after,
+            servers: HashMap::new(),
+        }
+    }
+
+    /// Updates the list from a network event, answering any queries with the servers that match
+    pub fn handle_event(
+        &mut self,
+        net: &NetworkResource,
+        event: &NetworkEvent,
+        now: Instant,
+    ) -> Result<(), NetworkError> {
+        match event {
+            NetworkEvent::Message(conn, msg) if self.serves(conn) => {
+                if let Some(list) = self.receive(conn.addr, msg, now) {
+                    if !net.challenges_connections(conn.socket)? {
+                        return Err(NetworkError::InvalidConfig {
+                            field: "challenge_connections",
+                            reason: "a master server only answers queries on sockets that \
+                                     challenge connections"
+                                .to_string(),
+                        });
+                    }
+
+                    net.send_large(conn.addr, &list, SendConfig::for_socket(conn.socket))?;
+                }
+            }
+            NetworkEvent::Disconnected(conn) if self.serves(conn) => {
+                self.servers.remove(&conn.addr);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Drops the servers that have stopped heartbeating
+    pub fn expire(&mut self, now: Instant) {
+        let expire_after = self.expire_after;
+
+        self.servers
+            .retain(|_, r| now.saturating_duration_since(r.heard_at) < expire_after);
+    }
+
+    /// Every registered server
+    pub fn servers(&self) -> Vec<ServerListing> {
+        self.list(&ServerFilter::default())
+    }
+
+    /// Handles a message from the address, returning the reply to send, if any
+    fn receive(&mut self, from: SocketAddr, msg: &[u8], now: Instant) -> Option<Bytes> {
+        match decode(msg)? {
+            Request::Register(info) => {
+                if !self.servers.contains_key(&from) && !self.has_room_for(from.ip()) {
+                    return None;
+                }
+
+                let registration = Registration {
+                    info,
+                    heard_at: now,
+                };
+                self.servers.insert(from, registration);
+                None
+            }
+            Request::Unregister => {
+                self.servers.remove(&from);
+                None
+            }
+            Request::Query(filter) => Some(encode_list(&self.list(&filter))),
+        }
+    }
+
+    fn list(&self, filter: &ServerFilter) -> Vec<ServerListing> {
+        self.servers
+            .iter()
+            .filter(|(_, r)| filter.matches(&r.info))
+            .map(|(addr, r)| ServerListing {
+                addr: *addr,
+                info: r.info.clone(),
+            })
+            .collect()
+    }
+
+    fn has_room_for(&self, ip: IpAddr) -> bool {
+        let from_ip = self.servers.keys().filter(|addr| addr.ip() == ip).count();
+
+        self.servers.len() < MAX_SERVERS && from_ip < MAX_SERVERS_PER_IP
+    }
+
+    fn serves(&self, conn: &Connection) -> bool {
+        self.socket.is_none_or(|s| s == conn.socket)
+    }
+}
+
+/// Keeps a game server registered with a master server. `heartbeat` should be called regularly,
+/// such as from a system, and sends the server's info whenever it's due, or has changed.
+pub struct MasterRegistration {
+    master: SocketAddr,
+    info: ServerInfo,
+    socket: Option<SocketHandle>,
+    interval: Duration,
+    sent_at: Option<Instant>,
+}
+
+impl MasterRegistration {
+    /// Registers from the default socket, which is the address clients will be given
+    pub fn new(master: SocketAddr, info: ServerInfo) -> Self {
+        MasterRegistration {
+            master,
+            info,
+            socket: None,
+            interval: DEFAULT_HEARTBEAT_INTERVAL,
+            sent_at: None,
+        }
+    }
+
+    pub fn with_socket(mut self, socket: SocketHandle) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// How often the info is sent again. It should be well under the master server's
+    /// `expire_after`, so a lost heartbeat doesn't drop the server from the list.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn info(&self) -> &ServerInfo {
+        &self.info
+    }
+
+    /// Changes the listed info, which is sent with the next heartbeat
+    pub fn set_info(&mut self, info: ServerInfo) {
+        self.info = info;
+        self.sent_at = None;
+    }
+
+    pub fn heartbeat(&mut self, net: &NetworkResource, now: Instant) -> Result<(), NetworkError> {
+        if let Some(sent_at) = self.sent_at {
+            if now.saturating_duration_since(sent_at) < self.interval {
+                return Ok(());
+            }
+        }
+
+        self.sent_at = Some(now);
+        self.send(net, &encode_register(&self.info))
+    }
+
+    /// Takes the server off the list straight away, instead of waiting for it to expire
+    pub fn unregister(&mut self, net: &NetworkResource) -> Result<(), NetworkError> {
+        self.sent_at = None;
+        self.send(net, &encode_header(KIND_UNREGISTER, 0))
+    }
+
+    fn send(&self, net: &NetworkResource, msg: &[u8]) -> Result<(), NetworkError> {
+        let config = SendConfig {
+            socket: self.socket,
+            ..Default::default()
+        };
+
+        net.send_with_config(self.master, msg, NetworkDelivery::ReliableUnordered, config)
+            .map(|_| ())
+    }
+}
+
+/// Reads the master server's answer to `NetworkResource::query_master`. Returns `None` if the
+/// message isn't a server list.
+pub fn parse_server_list(msg: &[u8]) -> Option<Vec<ServerListing>> {
+    let mut msg = msg;
+    if take(&mut msg, MAGIC.len())? != MAGIC || take(&mut msg, 1)?[0] != KIND_LIST {
+        return None;
+    }
+
+    let count = u16::from_be_bytes(take(&mut msg, 2)?.try_into().ok()?);
+    let mut servers = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let addr = decode_addr(&mut msg)?;
+        let info = decode_info(&mut msg)?;
+        servers.push(ServerListing { addr, info });
+    }
+
+    match msg.is_empty() {
+        true => Some(servers),
+        false => None,
+    }
+}
+
+enum Request {
+    Register(ServerInfo),
+    Unregister,
+    Query(ServerFilter),
+}
+
+fn encode_header(kind: u8, capacity: usize) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(MAGIC.len() + 1 + capacity);
+    buf.put_slice(MAGIC);
+    buf.put_u8(kind);
+    buf
+}
+
+fn encode_register(info: &ServerInfo) -> Bytes {
+    let mut buf = encode_header(KIND_REGISTER, 64);
+    encode_info(&mut buf, info);
+    buf.freeze()
+}
+
+pub(crate) fn encode_query(filter: &ServerFilter) -> Bytes {
+    let mut flags = 0;
+    if filter.hide_full {
+        flags |= FILTER_HIDE_FULL;
+    }
+    if filter.hide_empty {
+        flags |= FILTER_HIDE_EMPTY;
+    }
+    if filter.version.is_some() {
+        flags |= FILTER_VERSION;
+    }
+    if filter.name_contains.is_some() {
+        flags |= FILTER_NAME;
+    }
+
+    let mut buf = encode_header(KIND_QUERY, 32);
+    buf.put_u8(flags);
+    if let Some(version) = &filter.version {
+        encode_str(&mut buf, version);
+    }
+    if let Some(name) = &filter.name_contains {
+        encode_str(&mut buf, name);
+    }
+    buf.freeze()
+}
+
+fn encode_list(servers: &[ServerListing]) -> Bytes {
+    // a list longer than this wouldn't be much use to anyone
+    let servers = &servers[..servers.len().min(u16::MAX as usize)];
+
+    let mut buf = encode_header(KIND_LIST, 2 + servers.len() * 64);
+    buf.put_u16(servers.len() as u16);
+    for server in servers {
+        match server.addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.put_u8(4);
+                buf.put_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.put_u8(6);
+                buf.put_slice(&ip.octets());
+            }
+        }
+        buf.put_u16(server.addr.port());
+        encode_info(&mut buf, &server.info);
+    }
+    buf.freeze()
+}
+
+fn encode_info(buf: &mut BytesMut, info: &ServerInfo) {
+    encode_str(buf, &info.name);
+    encode_str(buf, &info.version);
+    buf.put_u16(info.players);
+    buf.put_u16(info.max_players);
+    let len = info.user_data.len().min(u16::MAX as usize);
+    buf.put_u16(len as u16);
+    buf.put_slice(&info.user_data[..len]);
+}
+
+// strings are cut to 255 bytes, on a character boundary
+fn encode_str(buf: &mut BytesMut, s: &str) {
+    let mut len = s.len().min(u8::MAX as usize);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    buf.put_u8(len as u8);
+    buf.put_slice(&s.as_bytes()[..len]);
+}
+
+fn decode(msg: &[u8]) -> Option<Request> {
+    let mut msg = msg;
+    if take(&mut msg, MAGIC.len())? != MAGIC {
+        return None;
+    }
+
+    let request = match take(&mut msg, 1)?[0] {
+        KIND_REGISTER => Request::Register(decode_info(&mut msg)?),
+        KIND_UNREGISTER => Request::Unregister,
+        KIND_QUERY => {
+            let flags = take(&mut msg, 1)?[0];
+            let version = match flags & FILTER_VERSION {
+                0 => None,
+                _ => Some(decode_str(&mut msg)?),
+            };
+            let name_contains = match flags & FILTER_NAME {
+                0 => None,
+                _ => Some(decode_str(&mut msg)?),
+            };
+
+            Request::Query(ServerFilter {
+                name_contains,
+                version,
+                hide_full: flags & FILTER_HIDE_FULL != 0,
+                hide_empty: flags & FILTER_HIDE_EMPTY != 0,
+            })
+        }
+        _ => return None,
+    };
+
+    match msg.is_empty() {
+        true => Some(request),
+        false => None,
+    }
+}
+
+fn decode_addr(msg: &mut &[u8]) -> Option<SocketAddr> {
+    let ip = match take(msg, 1)?[0] {
+        4 => {
+            let octets: [u8; 4] = take(msg, 4)?.try_into().ok()?;
+            IpAddr::from(octets)
+        }
+        6 => {
+            let octets: [u8; 16] = take(msg, 16)?.try_into().ok()?;
+            IpAddr::from(octets)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(take(msg, 2)?.try_into().ok()?);
+
+    Some(SocketAddr::new(ip, port))
+}
+
+fn decode_info(msg: &mut &[u8]) -> Option<ServerInfo> {
+    let name = decode_str(msg)?;
+    let version = decode_str(msg)?;
+    let players = u16::from_be_bytes(take(msg, 2)?.try_into().ok()?);
+    let max_players = u16::from_be_bytes(take(msg, 2)?.try_into().ok()?);
+    let len = u16::from_be_bytes(take(msg, 2)?.try_into().ok()?) as usize;
+    let user_data = Bytes::copy_from_slice(take(msg, len)?);
+
+    Some(ServerInfo {
+        name,
+        version,
+        players,
+        max_players,
+        user_data,
+    })
+}
+
+fn decode_str(msg: &mut &[u8]) -> Option<String> {
+    let len = take(msg, 1)?[0] as usize;
+    String::from_utf8(take(msg, len)?.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn info(name: &str, players: u16) -> ServerInfo {
+        ServerInfo {
+            name: name.to_string(),
+            version: "1.0".to_string(),
+            players,
+            max_players: 8,
+            user_data: Bytes::from_static(b"ctf"),
+        }
+    }
+
+    #[test]
+    fn queries_return_the_servers_that_match() {
+        let now = Instant::now();
+        let mut master = MasterServer::new(None, Duration::from_secs(30));
+
+        assert!(master
+            .receive(addr(5000), &encode_register(&info("Alpha", 0)), now)
+            .is_none());
+        master.receive(addr(5001), &encode_register(&info("Bravo", 3)), now);
+        master.receive(addr(5002), &encode_register(&info("alpha two", 8)), now);
+        assert_eq!(master.servers().len(), 3);
+
+        let filter = ServerFilter {
+            name_contains: Some("ALPHA".to_string()),
+            hide_full: true,
+            ..Default::default()
+        };
+        let reply = master
+            .receive(addr(6000), &encode_query(&filter), now)
+            .unwrap();
+        assert_eq!(
+            parse_server_list(&reply),
+            Some(vec![ServerListing {
+                addr: addr(5000),
+                info: info("Alpha", 0),
+            }])
+        );
+
+        let filter = ServerFilter {
+            version: Some("2.0".to_string()),
+            ..Default::default()
+        };
+        let reply = master
+            .receive(addr(6000), &encode_query(&filter), now)
+            .unwrap();
+        assert_eq!(parse_server_list(&reply), Some(vec![]));
+
+        // a game message isn't a server list
+        assert_eq!(parse_server_list(b"hello"), None);
+        assert!(master.receive(addr(6000), b"hello", now).is_none());
+    }
+
+    #[test]
+    fn servers_expire_without_a_heartbeat() {
+        let now = Instant::now();
+        let mut master = MasterServer::new(None, Duration::from_secs(30));
+
+        master.receive(addr(5000), &encode_register(&info("Alpha", 1)), now);
+        master.receive(addr(5001), &encode_register(&info("Bravo", 1)), now);
+
+        // Bravo heartbeats, and Alpha doesn't
+        let later = now + Duration::from_secs(20);
+        master.receive(addr(5001), &encode_register(&info("Bravo", 2)), later);
+        master.expire(now + Duration::from_secs(40));
+        let servers = master.servers();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].info.players, 2);
+
+        master.receive(addr(5001), &encode_header(KIND_UNREGISTER, 0), later);
+        assert!(master.servers().is_empty());
+    }
+
+    #[test]
+    fn registrations_per_ip_are_capped() {
+        let now = Instant::now();
+        let mut master = MasterServer::new(None, Duration::from_secs(30));
+
+        for port in 0..MAX_SERVERS_PER_IP as u16 + 1 {
+            master.receive(addr(5000 + port), &encode_register(&info("Spam", 0)), now);
+        }
+        assert_eq!(master.servers().len(), MAX_SERVERS_PER_IP);
+
+        // servers that are already registered can still heartbeat, and other IPs can register
+        master.receive(addr(5000), &encode_register(&info("Spam", 1)), now);
+        let other = SocketAddr::from(([127, 0, 0, 2], 5000));
+        master.receive(other, &encode_register(&info("Alpha", 0)), now);
+        assert_eq!(master.servers().len(), MAX_SERVERS_PER_IP + 1);
+    }
+}
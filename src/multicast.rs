@@ -0,0 +1,222 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use super::NetworkError;
+
+const MAGIC: &[u8; 4] = b"BPNG";
+const VERSION: u8 = 1;
+
+// magic and version
+const HEADER_SIZE: usize = 4 + 1;
+// the largest payload a UDP datagram can carry
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+// The multicast groups a bound socket has joined, and the sockets it sends to groups with.
+// Laminar's sockets can't join groups, so multicast skips laminar entirely. Packets are unreliable,
+// and since anyone who can reach a group can send to it, they're never attributed to a connection.
+pub(crate) struct Multicast {
+    groups: Vec<MulticastGroup>,
+    // one for each address family, bound when it's first needed
+    senders: Vec<UdpSocket>,
+    // shared by every group, and only allocated once a group is joined
+    buf: Vec<u8>,
+}
+
+// A joined group. It's bound to the group's port with the address reused, so any number of
+// sockets on a machine can join groups on the same port.
+pub(crate) struct MulticastGroup {
+    group: SocketAddr,
+    socket: UdpSocket,
+}
+
+impl MulticastGroup {
+    pub fn join(group: SocketAddr) -> Result<Self, NetworkError> {
+        let domain = match group {
+            SocketAddr::V4(_) => Domain::ipv4(),
+            SocketAddr::V6(_) => Domain::ipv6(),
+        };
+        let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&bind_addr(group).into())?;
+
+        let socket = socket.into_udp_socket();
+        match group.ip() {
+            IpAddr::V4(ip) => socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?,
+            IpAddr::V6(ip) => socket.join_multicast_v6(&ip, 0)?,
+        }
+        socket.set_nonblocking(true)?;
+
+        Ok(MulticastGroup { group, socket })
+    }
+}
+
+// Unix filters what a socket bound to the group's address receives by group, so groups that share
+// a port don't get each other's packets. Windows can't bind to a multicast address.
+#[cfg(unix)]
+fn bind_addr(group: SocketAddr) -> SocketAddr {
+    group
+}
+
+#[cfg(not(unix))]
+fn bind_addr(group: SocketAddr) -> SocketAddr {
+    match group {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, group.port()).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, group.port()).into(),
+    }
+}
+
+impl Multicast {
+    pub fn new() -> Self {
+        Multicast {
+            groups: Vec::new(),
+            senders: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, group: MulticastGroup) {
+        self.groups.push(group);
+    }
+
+    /// Leaves the group, which happens when its socket is closed
+    pub fn leave(&mut self, group: SocketAddr) {
+        self.groups.retain(|g| g.group != group);
+    }
+
+    pub fn send(&mut self, group: SocketAddr, payload: &[u8]) -> Result<(), NetworkError> {
+        let packet = encode(payload);
+        self.sender(group.is_ipv6())?.send_to(&packet, group)?;
+
+        Ok(())
+    }
+
+    /// The packets that have arrived for any of the groups since the last call, as the group,
+    /// the address the packet was sent from, and the payload
+    pub fn receive(&mut self) -> Vec<(SocketAddr, SocketAddr, Bytes)> {
+        let mut received = Vec::new();
+        if self.groups.is_empty() {
+            return received;
+        }
+
+        if self.buf.is_empty() {
+            self.buf.resize(MAX_DATAGRAM_SIZE, 0);
+        }
+        let buf = &mut self.buf;
+
+        for group in self.groups.iter() {
+            // errors are treated like silence, since nothing is waiting on a multicast packet
+            while let Ok((len, from)) = group.socket.recv_from(buf) {
+                if let Some(payload) = decode(&buf[..len]) {
+                    received.push((group.group, from, Bytes::copy_from_slice(payload)));
+                }
+            }
+        }
+
+        received
+    }
+
+    fn sender(&mut self, ipv6: bool) -> Result<&UdpSocket, NetworkError> {
+        let found = self
+            .senders
+            .iter()
+            .position(|s| s.local_addr().is_ok_and(|a| a.is_ipv6() == ipv6));
+
+        let idx = match found {
+            Some(idx) => idx,
+            None => {
+                // bound to the unspecified address, so the OS picks the interface to send from
+                let socket = match ipv6 {
+                    true => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+                    false => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+                };
+                self.senders.push(socket);
+                self.senders.len() - 1
+            }
+        };
+
+        Ok(&self.senders[idx])
+    }
+}
+
+fn encode(payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_SIZE + payload.len());
+    buf.put_slice(MAGIC);
+    buf.put_u8(VERSION);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+fn decode(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < HEADER_SIZE || &packet[..4] != MAGIC || packet[4] != VERSION {
+        return None;
+    }
+
+    Some(&packet[HEADER_SIZE..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn group_members_receive_packets_from_their_real_source() {
+        let group = SocketAddr::from(([239, 255, 42, 1], 12690));
+
+        let mut member = Multicast::new();
+        member.add(MulticastGroup::join(group).unwrap());
+        let mut sender = Multicast::new();
+        sender.send(group, b"spectate").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut received = Vec::new();
+        while received.is_empty() && Instant::now() < deadline {
+            received = member.receive();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        match &received[..] {
+            [(to, from, payload)] => {
+                assert_eq!(*to, group);
+                assert_eq!(from.port(), sender.senders[0].local_addr().unwrap().port());
+                assert_eq!(&payload[..], b"spectate");
+            }
+            other => panic!("expected one packet, got {:?}", other),
+        }
+
+        // anyone can send to the group's port, so packets that aren't ours are ignored
+        assert_eq!(decode(b"BPN"), None);
+        assert_eq!(decode(b"hello there"), None);
+    }
+
+    #[test]
+    fn members_on_one_machine_share_the_group_port() {
+        let group = SocketAddr::from(([239, 255, 42, 2], 12691));
+        let other = SocketAddr::from(([239, 255, 42, 3], 12691));
+
+        let mut first = Multicast::new();
+        first.add(MulticastGroup::join(group).unwrap());
+        let mut second = Multicast::new();
+        second.add(MulticastGroup::join(group).unwrap());
+        let mut elsewhere = Multicast::new();
+        elsewhere.add(MulticastGroup::join(other).unwrap());
+
+        Multicast::new().send(group, b"spectate").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let (mut to_first, mut to_second) = (Vec::new(), Vec::new());
+        while (to_first.is_empty() || to_second.is_empty()) && Instant::now() < deadline {
+            to_first.extend(first.receive());
+            to_second.extend(second.receive());
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(to_first.len(), 1);
+        assert_eq!(to_second.len(), 1);
+        #[cfg(unix)]
+        assert!(elsewhere.receive().is_empty());
+    }
+}
@@ -0,0 +1,585 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::convert::{TryFrom, TryInto};
+use std::net::{IpAddr, SocketAddr};
+
+use super::NetworkDelivery;
+
+// Every packet we hand to laminar starts with a single byte identifying the kind of frame it
+// carries, so the worker can tell application messages apart from the plumbing it needs
+// (chunked transfers, etc.)
+pub const FRAME_HEADER_SIZE: usize = 1;
+pub const CHUNK_HEADER_SIZE: usize = 16;
+// the message id carried by tracked messages and their acks
+pub const TRACKED_HEADER_SIZE: usize = 8;
+// the session id that prefixes every packet on a socket with `migrate_connections`
+pub const SESSION_HEADER_SIZE: usize = 9;
+// each frame in a batch is prefixed with its length
+pub const BATCH_ENTRY_HEADER_SIZE: usize = 2;
+// the most a relay frame adds to the packet it carries: the peer's address, and the delivery the
+// relay forwards it with
+pub const RELAY_HEADER_SIZE: usize = FRAME_HEADER_SIZE + ADDR_SIZE_V6 + 2;
+
+// large transfers are sent on their own ordered stream, so they can't hold up (or be held up by)
+// application messages sent with `ReliableOrdered(None)`
+pub const CHUNK_STREAM_ID: u8 = 254;
+
+// the high bits of the kind byte are flags describing how the rest of the packet is encoded
+pub const FLAG_COMPRESSED: u8 = 0x80;
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub const FLAG_ENCRYPTED: u8 = 0x40;
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const COOKIE_SIZE: usize = 32;
+pub const PUNCH_KEY_SIZE: usize = 32;
+
+// an address is its family, the ip and the port
+const ADDR_SIZE_V4: usize = 1 + 4 + 2;
+const ADDR_SIZE_V6: usize = 1 + 16 + 2;
+
+// the delivery in a relay frame has a flag for whether there's a stream id
+const DELIVERY_HAS_STREAM: u8 = 0x80;
+
+const KIND_MESSAGE: u8 = 0;
+const KIND_CHUNK: u8 = 1;
+const KIND_TRACKED: u8 = 2;
+const KIND_ACK: u8 = 3;
+const KIND_BATCH: u8 = 4;
+const KIND_HANDSHAKE_INIT: u8 = 5;
+const KIND_HANDSHAKE_REPLY: u8 = 6;
+const KIND_CONNECT_TOKEN: u8 = 7;
+const KIND_CONNECT_ACCEPTED: u8 = 8;
+const KIND_CHALLENGE_REQUEST: u8 = 9;
+const KIND_CHALLENGE: u8 = 10;
+const KIND_CHALLENGE_RESPONSE: u8 = 11;
+const KIND_CHALLENGE_ACCEPTED: u8 = 12;
+const KIND_SESSION: u8 = 13;
+const KIND_RENDEZVOUS_REGISTER: u8 = 14;
+const KIND_INTRODUCE: u8 = 15;
+const KIND_PUNCH: u8 = 16;
+const KIND_RELAY_REGISTER: u8 = 17;
+const KIND_RELAY_PAIRED: u8 = 18;
+const KIND_RELAY_CLOSED: u8 = 19;
+const KIND_RELAYED: u8 = 20;
+const KIND_PUNCH_ACK: u8 = 21;
+const KIND_PING: u8 = 22;
+const KIND_PONG: u8 = 23;
+
+#[derive(Debug, PartialEq)]
+pub enum Frame<'a> {
+    Message(&'a [u8]),
+    Chunk(ChunkHeader, &'a [u8]),
+    /// A message the sender wants an `Ack` for
+    Tracked(u64, &'a [u8]),
+    Ack(u64),
+    /// Several frames packed into one packet. A batch never contains another batch.
+    Batch(Vec<&'a [u8]>),
+    /// The initiator's public key
+    HandshakeInit(&'a [u8]),
+    /// The responder's public key, followed by the initiator's
+    HandshakeReply(&'a [u8], &'a [u8]),
+    /// A connect token presented by a client
+    ConnectToken(&'a [u8]),
+    /// The server accepted the client's connect token
+    ConnectAccepted,
+    /// Asks the peer for a challenge. It's padded to the size of the challenge.
+    ChallengeRequest,
+    /// A cookie the peer has to send back
+    Challenge(&'a [u8]),
+    ChallengeResponse(&'a [u8]),
+    /// The peer answered the challenge, with the cookie it answered with
+    ChallengeAccepted(&'a [u8]),
+    /// A whole packet, tagged with the id of the session it belongs to
+    Session(u64, &'a [u8]),
+    /// Asks a rendezvous server to introduce us to the other peer registered with the key
+    RendezvousRegister(&'a [u8]),
+    /// The rendezvous server's introduction to the other peer, by its public address
+    Introduce(SocketAddr),
+    /// A NAT punch-through probe, carrying the key the peers were introduced with
+    Punch(&'a [u8]),
+    /// The answer to a punch-through probe, which isn't answered in turn
+    PunchAck(&'a [u8]),
+    /// Asks a relay server to pair us with the other peer registered with the key
+    RelayRegister(&'a [u8]),
+    /// The relay server paired us with the peer at the public address, under the key we
+    /// registered with
+    RelayPaired(SocketAddr, &'a [u8]),
+    /// The relayed peer at the address timed out at the relay server
+    RelayClosed(SocketAddr),
+    /// A packet for the relay server to forward to the peer at the address. When the relay
+    /// forwards it, the address is replaced with the sender's.
+    Relayed(SocketAddr, NetworkDelivery, &'a [u8]),
+    /// Asks the peer to send us something, so its ordered streams with us can start
+    Ping,
+    /// The answer to a ping, which isn't answered in turn
+    Pong,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChunkHeader {
+    pub transfer: u32,
+    pub index: u32,
+    pub count: u32,
+    pub total_len: u32,
+}
+
+pub fn encode_message(payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    buf.put_u8(KIND_MESSAGE);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+pub fn encode_chunk(header: ChunkHeader, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + CHUNK_HEADER_SIZE + payload.len());
+    buf.put_u8(KIND_CHUNK);
+    buf.put_u32(header.transfer);
+    buf.put_u32(header.index);
+    buf.put_u32(header.count);
+    buf.put_u32(header.total_len);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+pub fn encode_tracked(id: u64, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + TRACKED_HEADER_SIZE + payload.len());
+    buf.put_u8(KIND_TRACKED);
+    buf.put_u64(id);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+pub fn encode_ack(id: u64) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + TRACKED_HEADER_SIZE);
+    buf.put_u8(KIND_ACK);
+    buf.put_u64(id);
+    buf.freeze()
+}
+
+pub fn encode_batch(frames: &[&Bytes]) -> Bytes {
+    let len = frames
+        .iter()
+        .map(|f| BATCH_ENTRY_HEADER_SIZE + f.len())
+        .sum::<usize>();
+
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + len);
+    buf.put_u8(KIND_BATCH);
+    for frame in frames {
+        buf.put_u16(frame.len() as u16);
+        buf.put_slice(frame);
+    }
+    buf.freeze()
+}
+
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub fn encode_handshake_init(public_key: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + PUBLIC_KEY_SIZE);
+    buf.put_u8(KIND_HANDSHAKE_INIT);
+    buf.put_slice(public_key);
+    buf.freeze()
+}
+
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub fn encode_handshake_reply(public_key: &[u8], initiator_key: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + 2 * PUBLIC_KEY_SIZE);
+    buf.put_u8(KIND_HANDSHAKE_REPLY);
+    buf.put_slice(public_key);
+    buf.put_slice(initiator_key);
+    buf.freeze()
+}
+
+pub fn encode_connect_token(token: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + token.len());
+    buf.put_u8(KIND_CONNECT_TOKEN);
+    buf.put_slice(token);
+    buf.freeze()
+}
+
+pub fn encode_connect_accepted() -> Bytes {
+    Bytes::from_static(&[KIND_CONNECT_ACCEPTED])
+}
+
+pub fn encode_ping() -> Bytes {
+    Bytes::from_static(&[KIND_PING])
+}
+
+pub fn encode_pong() -> Bytes {
+    Bytes::from_static(&[KIND_PONG])
+}
+
+pub fn encode_challenge_request() -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + COOKIE_SIZE);
+    buf.put_u8(KIND_CHALLENGE_REQUEST);
+    buf.put_slice(&[0; COOKIE_SIZE]);
+    buf.freeze()
+}
+
+pub fn encode_challenge(cookie: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + COOKIE_SIZE);
+    buf.put_u8(KIND_CHALLENGE);
+    buf.put_slice(cookie);
+    buf.freeze()
+}
+
+pub fn encode_challenge_response(cookie: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + COOKIE_SIZE);
+    buf.put_u8(KIND_CHALLENGE_RESPONSE);
+    buf.put_slice(cookie);
+    buf.freeze()
+}
+
+pub fn encode_challenge_accepted(cookie: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + COOKIE_SIZE);
+    buf.put_u8(KIND_CHALLENGE_ACCEPTED);
+    buf.put_slice(cookie);
+    buf.freeze()
+}
+
+pub fn encode_session(id: u64, packet: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(SESSION_HEADER_SIZE + packet.len());
+    buf.put_u8(KIND_SESSION);
+    buf.put_u64(id);
+    buf.put_slice(packet);
+    buf.freeze()
+}
+
+pub fn encode_rendezvous_register(key: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + PUNCH_KEY_SIZE);
+    buf.put_u8(KIND_RENDEZVOUS_REGISTER);
+    buf.put_slice(key);
+    buf.freeze()
+}
+
+pub fn encode_introduce(peer: SocketAddr) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + ADDR_SIZE_V6);
+    buf.put_u8(KIND_INTRODUCE);
+    put_addr(&mut buf, peer);
+    buf.freeze()
+}
+
+pub fn encode_punch(key: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + PUNCH_KEY_SIZE);
+    buf.put_u8(KIND_PUNCH);
+    buf.put_slice(key);
+    buf.freeze()
+}
+
+pub fn encode_punch_ack(key: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + PUNCH_KEY_SIZE);
+    buf.put_u8(KIND_PUNCH_ACK);
+    buf.put_slice(key);
+    buf.freeze()
+}
+
+pub fn encode_relay_register(key: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + PUNCH_KEY_SIZE);
+    buf.put_u8(KIND_RELAY_REGISTER);
+    buf.put_slice(key);
+    buf.freeze()
+}
+
+pub fn encode_relay_paired(peer: SocketAddr, key: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + ADDR_SIZE_V6 + PUNCH_KEY_SIZE);
+    buf.put_u8(KIND_RELAY_PAIRED);
+    put_addr(&mut buf, peer);
+    buf.put_slice(key);
+    buf.freeze()
+}
+
+pub fn encode_relay_closed(peer: SocketAddr) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + ADDR_SIZE_V6);
+    buf.put_u8(KIND_RELAY_CLOSED);
+    put_addr(&mut buf, peer);
+    buf.freeze()
+}
+
+pub fn encode_relayed(peer: SocketAddr, delivery: NetworkDelivery, packet: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(RELAY_HEADER_SIZE + packet.len());
+    buf.put_u8(KIND_RELAYED);
+    put_addr(&mut buf, peer);
+
+    let (kind, stream) = match delivery {
+        NetworkDelivery::UnreliableUnordered => (0, None),
+        NetworkDelivery::UnreliableSequenced(stream) => (1, stream),
+        NetworkDelivery::ReliableUnordered => (2, None),
+        NetworkDelivery::ReliableSequenced(stream) => (3, stream),
+        NetworkDelivery::ReliableOrdered(stream) => (4, stream),
+    };
+    match stream {
+        Some(stream) => {
+            buf.put_u8(kind | DELIVERY_HAS_STREAM);
+            buf.put_u8(stream);
+        }
+        None => {
+            buf.put_u8(kind);
+            buf.put_u8(0);
+        }
+    }
+
+    buf.put_slice(packet);
+    buf.freeze()
+}
+
+fn put_addr(buf: &mut BytesMut, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.put_u8(4);
+            buf.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.put_u8(6);
+            buf.put_slice(&ip.octets());
+        }
+    }
+    buf.put_u16(addr.port());
+}
+
+pub fn decode(bytes: &[u8]) -> Option<Frame<'_>> {
+    let (kind, body) = bytes.split_first()?;
+
+    match *kind {
+        KIND_MESSAGE => Some(Frame::Message(body)),
+        KIND_CHUNK => {
+            if body.len() < CHUNK_HEADER_SIZE {
+                return None;
+            }
+
+            let header = ChunkHeader {
+                transfer: read_u32(&body[0..4]),
+                index: read_u32(&body[4..8]),
+                count: read_u32(&body[8..12]),
+                total_len: read_u32(&body[12..16]),
+            };
+
+            Some(Frame::Chunk(header, &body[CHUNK_HEADER_SIZE..]))
+        }
+        KIND_TRACKED if body.len() >= TRACKED_HEADER_SIZE => Some(Frame::Tracked(
+            read_u64(&body[0..8]),
+            &body[TRACKED_HEADER_SIZE..],
+        )),
+        KIND_ACK if body.len() == TRACKED_HEADER_SIZE => Some(Frame::Ack(read_u64(body))),
+        KIND_BATCH => decode_batch(body).map(Frame::Batch),
+        KIND_HANDSHAKE_INIT if body.len() == PUBLIC_KEY_SIZE => Some(Frame::HandshakeInit(body)),
+        KIND_HANDSHAKE_REPLY if body.len() == 2 * PUBLIC_KEY_SIZE => Some(Frame::HandshakeReply(
+            &body[..PUBLIC_KEY_SIZE],
+            &body[PUBLIC_KEY_SIZE..],
+        )),
+        KIND_CONNECT_TOKEN => Some(Frame::ConnectToken(body)),
+        KIND_CONNECT_ACCEPTED if body.is_empty() => Some(Frame::ConnectAccepted),
+        KIND_PING if body.is_empty() => Some(Frame::Ping),
+        KIND_PONG if body.is_empty() => Some(Frame::Pong),
+        KIND_CHALLENGE_REQUEST if body.len() == COOKIE_SIZE => Some(Frame::ChallengeRequest),
+        KIND_CHALLENGE if body.len() == COOKIE_SIZE => Some(Frame::Challenge(body)),
+        KIND_CHALLENGE_RESPONSE if body.len() == COOKIE_SIZE => {
+            Some(Frame::ChallengeResponse(body))
+        }
+        KIND_CHALLENGE_ACCEPTED if body.len() == COOKIE_SIZE => {
+            Some(Frame::ChallengeAccepted(body))
+        }
+        KIND_SESSION if body.len() >= 8 => Some(Frame::Session(read_u64(&body[..8]), &body[8..])),
+        // a registration is at least as large as the introduction it prompts
+        KIND_RENDEZVOUS_REGISTER if body.len() == PUNCH_KEY_SIZE => {
+            Some(Frame::RendezvousRegister(body))
+        }
+        KIND_INTRODUCE => decode_addr(body).map(Frame::Introduce),
+        KIND_PUNCH if body.len() == PUNCH_KEY_SIZE => Some(Frame::Punch(body)),
+        KIND_PUNCH_ACK if body.len() == PUNCH_KEY_SIZE => Some(Frame::PunchAck(body)),
+        KIND_RELAY_REGISTER if body.len() == PUNCH_KEY_SIZE => Some(Frame::RelayRegister(body)),
+        KIND_RELAY_PAIRED => match split_addr(body)? {
+            (peer, key) if key.len() == PUNCH_KEY_SIZE => Some(Frame::RelayPaired(peer, key)),
+            _ => None,
+        },
+        KIND_RELAY_CLOSED => decode_addr(body).map(Frame::RelayClosed),
+        KIND_RELAYED => {
+            let (peer, body) = split_addr(body)?;
+            if body.len() < 2 {
+                return None;
+            }
+
+            let stream = match body[0] & DELIVERY_HAS_STREAM {
+                0 => None,
+                _ => Some(body[1]),
+            };
+            let delivery = match body[0] & !DELIVERY_HAS_STREAM {
+                0 if stream.is_none() => NetworkDelivery::UnreliableUnordered,
+                1 => NetworkDelivery::UnreliableSequenced(stream),
+                2 if stream.is_none() => NetworkDelivery::ReliableUnordered,
+                3 => NetworkDelivery::ReliableSequenced(stream),
+                4 => NetworkDelivery::ReliableOrdered(stream),
+                _ => return None,
+            };
+
+            Some(Frame::Relayed(peer, delivery, &body[2..]))
+        }
+        _ => None,
+    }
+}
+
+fn decode_addr(body: &[u8]) -> Option<SocketAddr> {
+    match split_addr(body)? {
+        (addr, []) => Some(addr),
+        _ => None,
+    }
+}
+
+// Reads the address at the start of the body, returning it and the rest of the body
+fn split_addr(body: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    let (ip, len) = match *body.first()? {
+        4 if body.len() >= ADDR_SIZE_V4 => (
+            IpAddr::from(<[u8; 4]>::try_from(&body[1..5]).ok()?),
+            ADDR_SIZE_V4,
+        ),
+        6 if body.len() >= ADDR_SIZE_V6 => (
+            IpAddr::from(<[u8; 16]>::try_from(&body[1..17]).ok()?),
+            ADDR_SIZE_V6,
+        ),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(body[len - 2..len].try_into().ok()?);
+
+    Some((SocketAddr::new(ip, port), &body[len..]))
+}
+
+fn decode_batch(mut body: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut frames = Vec::new();
+
+    while !body.is_empty() {
+        if body.len() < BATCH_ENTRY_HEADER_SIZE {
+            return None;
+        }
+
+        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        let end = BATCH_ENTRY_HEADER_SIZE + len;
+        if body.len() < end {
+            return None;
+        }
+
+        frames.push(&body[BATCH_ENTRY_HEADER_SIZE..end]);
+        body = &body[end..];
+    }
+
+    match frames.is_empty() {
+        true => None,
+        false => Some(frames),
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let message = encode_message(b"hello");
+        assert_eq!(decode(&message), Some(Frame::Message(b"hello")));
+
+        let header = ChunkHeader {
+            transfer: 7,
+            index: 1,
+            count: 3,
+            total_len: 12,
+        };
+        let chunk = encode_chunk(header, b"abcd");
+        assert_eq!(decode(&chunk), Some(Frame::Chunk(header, b"abcd")));
+
+        let tracked = encode_tracked(42, b"state");
+        assert_eq!(decode(&tracked), Some(Frame::Tracked(42, b"state")));
+        assert_eq!(decode(&encode_ack(42)), Some(Frame::Ack(42)));
+
+        let batch = encode_batch(&[&message, &tracked]);
+        assert_eq!(
+            decode(&batch),
+            Some(Frame::Batch(vec![&message[..], &tracked[..]]))
+        );
+
+        let reply = encode_handshake_reply(&[1; 32], &[2; 32]);
+        assert_eq!(
+            decode(&reply),
+            Some(Frame::HandshakeReply(&[1; 32], &[2; 32]))
+        );
+
+        let token = encode_connect_token(b"token");
+        assert_eq!(decode(&token), Some(Frame::ConnectToken(b"token")));
+        assert_eq!(
+            decode(&encode_connect_accepted()),
+            Some(Frame::ConnectAccepted)
+        );
+        assert_eq!(decode(&encode_ping()), Some(Frame::Ping));
+        assert_eq!(decode(&encode_pong()), Some(Frame::Pong));
+
+        // a challenge request that isn't padded is ignored
+        let request = encode_challenge_request();
+        assert_eq!(decode(&request), Some(Frame::ChallengeRequest));
+        assert_eq!(decode(&request[..COOKIE_SIZE]), None);
+
+        let session = encode_session(9, &message);
+        assert_eq!(decode(&session), Some(Frame::Session(9, &message[..])));
+
+        let register = encode_rendezvous_register(&[5; PUNCH_KEY_SIZE]);
+        assert_eq!(
+            decode(&register),
+            Some(Frame::RendezvousRegister(&[5; PUNCH_KEY_SIZE]))
+        );
+        for peer in &["1.2.3.4:5000", "[2001:db8::1]:6000"] {
+            let peer: SocketAddr = peer.parse().unwrap();
+            assert_eq!(
+                decode(&encode_introduce(peer)),
+                Some(Frame::Introduce(peer))
+            );
+            assert!(encode_introduce(peer).len() <= register.len());
+        }
+        let punch = encode_punch(&[5; PUNCH_KEY_SIZE]);
+        assert_eq!(decode(&punch), Some(Frame::Punch(&[5; PUNCH_KEY_SIZE])));
+        let ack = encode_punch_ack(&[5; PUNCH_KEY_SIZE]);
+        assert_eq!(decode(&ack), Some(Frame::PunchAck(&[5; PUNCH_KEY_SIZE])));
+
+        let register = encode_relay_register(&[5; PUNCH_KEY_SIZE]);
+        assert_eq!(
+            decode(&register),
+            Some(Frame::RelayRegister(&[5; PUNCH_KEY_SIZE]))
+        );
+        let peer: SocketAddr = "[2001:db8::1]:6000".parse().unwrap();
+        assert_eq!(
+            decode(&encode_relay_paired(peer, &[5; PUNCH_KEY_SIZE])),
+            Some(Frame::RelayPaired(peer, &[5; PUNCH_KEY_SIZE]))
+        );
+        assert_eq!(
+            decode(&encode_relay_closed(peer)),
+            Some(Frame::RelayClosed(peer))
+        );
+        for delivery in &[
+            NetworkDelivery::UnreliableUnordered,
+            NetworkDelivery::UnreliableSequenced(Some(3)),
+            NetworkDelivery::ReliableUnordered,
+            NetworkDelivery::ReliableSequenced(None),
+            NetworkDelivery::ReliableOrdered(Some(0)),
+        ] {
+            let relayed = encode_relayed(peer, *delivery, &message);
+            assert_eq!(relayed.len(), RELAY_HEADER_SIZE + message.len());
+            assert_eq!(
+                decode(&relayed),
+                Some(Frame::Relayed(peer, *delivery, &message[..]))
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        assert_eq!(decode(&[]), None);
+        assert_eq!(decode(&[KIND_CHUNK, 0, 0]), None);
+        assert_eq!(decode(&[200, 1, 2, 3]), None);
+        assert_eq!(decode(&[KIND_TRACKED, 0, 0, 0]), None);
+        assert_eq!(decode(&[KIND_ACK, 0, 0, 0, 0, 0, 0, 0, 0, 0]), None);
+        assert_eq!(decode(&[KIND_BATCH]), None);
+        assert_eq!(decode(&[KIND_BATCH, 0, 4, KIND_MESSAGE]), None);
+        assert_eq!(decode(&[KIND_RELAY_PAIRED, 4, 127, 0, 0, 1, 0]), None);
+        assert_eq!(decode(&[KIND_RELAYED, 4, 127, 0, 0, 1, 0, 80, 2]), None);
+        assert_eq!(decode(&[KIND_RELAYED, 4, 127, 0, 0, 1, 0, 80, 9, 0]), None);
+    }
+}
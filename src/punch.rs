@@ -0,0 +1,348 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::protocol::{self, PUNCH_KEY_SIZE};
+
+// how long the rendezvous server remembers a registration, so a peer that registers again (say,
+// because its introduction was lost) is introduced again
+pub(crate) const REGISTRATION_LIFETIME: Duration = Duration::from_secs(30);
+// how often a peer registers again until it's introduced
+const REGISTER_INTERVAL: Duration = Duration::from_millis(500);
+// how often a peer probes the other until it hears back
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+// how often expired registrations are swept out
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// the most registrations kept at once, so registrations can't grow the map without bound.
+// Registrations under new keys are ignored while it's full.
+const MAX_REGISTRATIONS: usize = 65_536;
+
+pub(crate) type PunchKey = [u8; PUNCH_KEY_SIZE];
+
+/// Keys are hashed, so they're a fixed size on the wire whatever the game uses
+pub(crate) fn punch_key(key: &[u8]) -> PunchKey {
+    let mut hashed = [0; PUNCH_KEY_SIZE];
+    hashed.copy_from_slice(&Sha256::digest(key));
+    hashed
+}
+
+// The rendezvous server's side of NAT punch-through. Two peers that register with the same key
+// are introduced to each other, by the public addresses their registrations arrived from.
+pub(crate) struct Rendezvous {
+    registrations: HashMap<PunchKey, Registration>,
+    swept_at: Instant,
+}
+
+struct Registration {
+    peers: Vec<SocketAddr>,
+    registered_at: Instant,
+}
+
+impl Registration {
+    fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.registered_at) >= REGISTRATION_LIFETIME
+    }
+}
+
+impl Rendezvous {
+    pub fn new() -> Self {
+        Rendezvous {
+            registrations: HashMap::new(),
+            swept_at: Instant::now(),
+        }
+    }
+
+    /// Registers the address under the key, returning the other peer if there is one
+    pub fn register(
+        &mut self,
+        addr: SocketAddr,
+        key: PunchKey,
+        now: Instant,
+    ) -> Option<SocketAddr> {
+        if now.saturating_duration_since(self.swept_at) >= SWEEP_INTERVAL {
+            self.registrations.retain(|_, r| !r.is_expired(now));
+            self.swept_at = now;
+        }
+
+        if self
+            .registrations
+            .get(&key)
+            .is_some_and(|r| r.is_expired(now))
+        {
+            self.registrations.remove(&key);
+        }
+        if self.registrations.len() >= MAX_REGISTRATIONS && !self.registrations.contains_key(&key) {
+            return None;
+        }
+
+        let registration = self.registrations.entry(key).or_insert(Registration {
+            peers: Vec::new(),
+            registered_at: now,
+        });
+
+        // a key is for two peers, and anyone else who tries to use it is ignored
+        if !registration.peers.contains(&addr) {
+            if registration.peers.len() == 2 {
+                return None;
+            }
+            registration.peers.push(addr);
+            registration.registered_at = now;
+        }
+
+        registration.peers.iter().find(|p| **p != addr).copied()
+    }
+
+    /// Forgets the registration under the key, so the key can be used again
+    pub fn remove(&mut self, key: &PunchKey) {
+        self.registrations.remove(key);
+    }
+}
+
+// A peer's side of NAT punch-through. It registers with the rendezvous server until it's
+// introduced to the other peer, and then both probe each other. Each probe opens the sender's
+// NAT to the other peer, so once both have sent one, the next probe gets through.
+pub(crate) struct Puncher {
+    attempts: Vec<Attempt>,
+}
+
+struct Attempt {
+    rendezvous: SocketAddr,
+    key: PunchKey,
+    peer: Option<SocketAddr>,
+    sent_at: Option<Instant>,
+    expires_at: Instant,
+    succeeded: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Punch {
+    /// Send a registration to the rendezvous server at the address
+    Register(SocketAddr, Vec<u8>),
+    /// Send a probe to the peer at the address
+    Probe(SocketAddr, Vec<u8>),
+    Failed {
+        rendezvous: SocketAddr,
+        peer: Option<SocketAddr>,
+    },
+}
+
+impl Puncher {
+    pub fn new() -> Self {
+        Puncher {
+            attempts: Vec::new(),
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        rendezvous: SocketAddr,
+        key: PunchKey,
+        timeout: Duration,
+        now: Instant,
+    ) {
+        self.attempts.push(Attempt {
+            rendezvous,
+            key,
+            peer: None,
+            sent_at: None,
+            expires_at: now + timeout,
+            succeeded: false,
+        });
+    }
+
+    /// The rendezvous server introduced us to our peer
+    pub fn introduced(&mut self, from: SocketAddr, peer: SocketAddr) {
+        let attempt = self
+            .attempts
+            .iter_mut()
+            .find(|a| a.rendezvous == from && a.peer.is_none());
+
+        if let Some(attempt) = attempt {
+            attempt.peer = Some(peer);
+            attempt.sent_at = None;
+        }
+    }
+
+    /// A probe arrived. Returns the ack to send, and whether the peer has just been reached.
+    pub fn probed(&mut self, from: SocketAddr, key: &[u8]) -> Option<(Vec<u8>, bool)> {
+        let reached = self.reached(from, key)?;
+
+        // the peer keeps probing until it hears from us, so every probe is answered, but with an
+        // ack rather than a probe so the peers don't answer each other forever
+        Some((protocol::encode_punch_ack(key).to_vec(), reached))
+    }
+
+    /// An ack to one of our probes arrived. Returns whether the peer has just been reached.
+    pub fn acked(&mut self, from: SocketAddr, key: &[u8]) -> bool {
+        self.reached(from, key).unwrap_or(false)
+    }
+
+    fn reached(&mut self, from: SocketAddr, key: &[u8]) -> Option<bool> {
+        let attempt = self
+            .attempts
+            .iter_mut()
+            .find(|a| a.peer == Some(from) && a.key[..] == *key)?;
+
+        let reached = !attempt.succeeded;
+        attempt.succeeded = true;
+
+        Some(reached)
+    }
+
+    /// The registrations and probes that are due, and the attempts that have timed out
+    pub fn poll(&mut self, now: Instant) -> Vec<Punch> {
+        let mut punches = Vec::new();
+
+        self.attempts.retain(|attempt| {
+            if now < attempt.expires_at {
+                return true;
+            }
+
+            if !attempt.succeeded {
+                punches.push(Punch::Failed {
+                    rendezvous: attempt.rendezvous,
+                    peer: attempt.peer,
+                });
+            }
+            false
+        });
+
+        for attempt in self.attempts.iter_mut().filter(|a| !a.succeeded) {
+            let interval = match attempt.peer {
+                Some(_) => PROBE_INTERVAL,
+                None => REGISTER_INTERVAL,
+            };
+
+            if let Some(sent_at) = attempt.sent_at {
+                if now.saturating_duration_since(sent_at) < interval {
+                    continue;
+                }
+            }
+
+            attempt.sent_at = Some(now);
+            punches.push(match attempt.peer {
+                Some(peer) => Punch::Probe(peer, protocol::encode_punch(&attempt.key).to_vec()),
+                None => Punch::Register(
+                    attempt.rendezvous,
+                    protocol::encode_rendezvous_register(&attempt.key).to_vec(),
+                ),
+            });
+        }
+
+        punches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn peers_with_the_same_key_are_introduced() {
+        let now = Instant::now();
+        let key = punch_key(b"lobby 7");
+        let mut rendezvous = Rendezvous::new();
+
+        assert_eq!(rendezvous.register(addr(5000), key, now), None);
+        assert_eq!(rendezvous.register(addr(5000), key, now), None);
+        assert_eq!(rendezvous.register(addr(6000), key, now), Some(addr(5000)));
+        assert_eq!(rendezvous.register(addr(5000), key, now), Some(addr(6000)));
+
+        // nobody else can join, and other keys are kept apart
+        assert_eq!(rendezvous.register(addr(7000), key, now), None);
+        assert_eq!(
+            rendezvous.register(addr(7000), punch_key(b"lobby 8"), now),
+            None
+        );
+
+        let later = now + REGISTRATION_LIFETIME;
+        assert_eq!(rendezvous.register(addr(6000), key, later), None);
+    }
+
+    #[test]
+    fn registrations_are_bounded() {
+        let now = Instant::now();
+        let mut rendezvous = Rendezvous::new();
+
+        for i in 0..MAX_REGISTRATIONS as u32 {
+            let mut key = [0; PUNCH_KEY_SIZE];
+            key[..4].copy_from_slice(&i.to_be_bytes());
+            assert_eq!(rendezvous.register(addr(5000), key, now), None);
+        }
+
+        // a full rendezvous ignores new keys, but still introduces peers on the ones it has
+        let key = punch_key(b"lobby 7");
+        assert_eq!(rendezvous.register(addr(5000), key, now), None);
+        assert_eq!(rendezvous.register(addr(6000), key, now), None);
+        assert_eq!(
+            rendezvous.register(addr(6000), [0; PUNCH_KEY_SIZE], now),
+            Some(addr(5000))
+        );
+
+        // expired registrations make room again
+        let later = now + REGISTRATION_LIFETIME;
+        rendezvous.register(addr(5000), key, later);
+        assert_eq!(
+            rendezvous.register(addr(6000), key, later),
+            Some(addr(5000))
+        );
+        assert_eq!(rendezvous.registrations.len(), 1);
+    }
+
+    #[test]
+    fn attempts_register_probe_and_time_out() {
+        let now = Instant::now();
+        let key = punch_key(b"lobby 7");
+        let mut puncher = Puncher::new();
+        puncher.start(addr(4000), key, Duration::from_secs(5), now);
+
+        let register = protocol::encode_rendezvous_register(&key).to_vec();
+        assert_eq!(
+            puncher.poll(now),
+            vec![Punch::Register(addr(4000), register)]
+        );
+        assert!(puncher.poll(now).is_empty());
+
+        // only the rendezvous server can introduce a peer
+        puncher.introduced(addr(9999), addr(6000));
+        assert!(puncher.probed(addr(6000), &key).is_none());
+        puncher.introduced(addr(4000), addr(6000));
+
+        let probe = protocol::encode_punch(&key).to_vec();
+        assert_eq!(
+            puncher.poll(now),
+            vec![Punch::Probe(addr(6000), probe.clone())]
+        );
+        assert!(puncher.probed(addr(6000), &[0; PUNCH_KEY_SIZE]).is_none());
+
+        // probes are answered with acks, which aren't answered
+        let ack = protocol::encode_punch_ack(&key).to_vec();
+        assert_eq!(puncher.probed(addr(6000), &key), Some((ack.clone(), true)));
+        assert_eq!(puncher.probed(addr(6000), &key), Some((ack, false)));
+        assert!(!puncher.acked(addr(6000), &key));
+        assert!(puncher.poll(now + PROBE_INTERVAL).is_empty());
+
+        // an ack reaches the peer as well as a probe does
+        puncher.start(addr(4000), key, Duration::from_secs(5), now);
+        puncher.introduced(addr(4000), addr(7000));
+        assert!(!puncher.acked(addr(7000), &[0; PUNCH_KEY_SIZE]));
+        assert!(puncher.acked(addr(7000), &key));
+
+        // a punched attempt doesn't fail when it expires
+        assert!(puncher.poll(now + Duration::from_secs(5)).is_empty());
+
+        puncher.start(addr(4000), key, Duration::from_secs(5), now);
+        assert_eq!(
+            puncher.poll(now + Duration::from_secs(5)),
+            vec![Punch::Failed {
+                rendezvous: addr(4000),
+                peer: None,
+            }]
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// how long a connection's buckets are kept after its last packet. Every bucket holds a second
+// worth of traffic, so by then they'd have refilled anyway.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+// the most connections tracked at once, so packets from spoofed addresses can't grow the map
+// without bound. Packets from new addresses are dropped while it's full.
+const MAX_CONNECTIONS: usize = 65_536;
+
+/// Limits applied to inbound traffic, or to outbound traffic when used as a send budget. A `None`
+/// field is not limited.
+///
+/// Each limit is enforced with a token bucket that can absorb up to one second worth of traffic
+/// in a single burst. A packet larger than that is let through once the bucket is full, and the
+/// bucket has to refill from below zero afterwards.
+///
+/// A connection's buckets are forgotten once it's been idle for a second, and at most 65536
+/// connections are tracked at once. While that many are active, packets from any other address
+/// are dropped.
+#[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub packets_per_second: Option<u32>,
+    pub bytes_per_second: Option<u32>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Verdict {
+    Allow,
+    Drop,
+    // the packet was dropped, and the connection has just gone over its limit
+    DropAndReport,
+}
+
+pub(crate) struct RateLimiter {
+    connection_limit: Option<RateLimit>,
+    socket_buckets: Option<Buckets>,
+    connections: HashMap<SocketAddr, ConnectionBuckets>,
+    swept_at: Instant,
+}
+
+struct ConnectionBuckets {
+    buckets: Buckets,
+    limited: bool,
+    seen_at: Instant,
+}
+
+struct Buckets {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(connection_limit: Option<RateLimit>, socket_limit: Option<RateLimit>) -> Self {
+        let now = Instant::now();
+
+        RateLimiter {
+            connection_limit,
+            socket_buckets: socket_limit.map(|limit| Buckets::new(limit, now)),
+            connections: HashMap::new(),
+            swept_at: now,
+        }
+    }
+
+    pub fn check(&mut self, addr: SocketAddr, len: usize, now: Instant) -> Verdict {
+        if now.saturating_duration_since(self.swept_at) >= IDLE_TIMEOUT {
+            self.connections
+                .retain(|_, c| now.saturating_duration_since(c.seen_at) < IDLE_TIMEOUT);
+            self.swept_at = now;
+        }
+
+        if self.connection_limit.is_some()
+            && self.connections.len() >= MAX_CONNECTIONS
+            && !self.connections.contains_key(&addr)
+        {
+            return Verdict::Drop;
+        }
+
+        let connections = &mut self.connections;
+        let mut conn = self.connection_limit.map(move |limit| {
+            let conn = connections
+                .entry(addr)
+                .or_insert_with(|| ConnectionBuckets {
+                    buckets: Buckets::new(limit, now),
+                    limited: false,
+                    seen_at: now,
+                });
+            conn.seen_at = now;
+            conn
+        });
+
+        // an abusive connection should not be able to eat into the socket-wide allowance, so the
+        // per-connection limits are checked first
+        if let Some(conn) = &mut conn {
+            if !conn.buckets.has(len, now) {
+                if conn.limited {
+                    return Verdict::Drop;
+                }
+
+                conn.limited = true;
+                return Verdict::DropAndReport;
+            }
+
+            conn.limited = false;
+        }
+
+        // nothing is taken until both limits allow the packet, so a packet dropped by the socket
+        // limit doesn't count against the connection
+        if let Some(buckets) = &mut self.socket_buckets {
+            if !buckets.has(len, now) {
+                return Verdict::Drop;
+            }
+
+            buckets.take(len);
+        }
+
+        if let Some(conn) = conn {
+            conn.buckets.take(len);
+        }
+
+        Verdict::Allow
+    }
+
+    pub fn remove_connection(&mut self, addr: SocketAddr) {
+        self.connections.remove(&addr);
+    }
+}
+
+impl Buckets {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Buckets {
+            packets: limit.packets_per_second.map(|r| TokenBucket::new(r, now)),
+            bytes: limit.bytes_per_second.map(|r| TokenBucket::new(r, now)),
+        }
+    }
+
+    fn has(&mut self, len: usize, now: Instant) -> bool {
+        let has_packet = self.packets.as_mut().is_none_or(|b| b.has(1.0, now));
+        let has_bytes = self.bytes.as_mut().is_none_or(|b| b.has(len as f64, now));
+
+        has_packet && has_bytes
+    }
+
+    fn take(&mut self, len: usize) {
+        if let Some(b) = &mut self.packets {
+            b.take(1.0);
+        }
+
+        if let Some(b) = &mut self.bytes {
+            b.take(len as f64);
+        }
+    }
+}
+
+impl TokenBucket {
+    fn new(per_second: u32, now: Instant) -> Self {
+        TokenBucket {
+            capacity: per_second as f64,
+            tokens: per_second as f64,
+            last_refill: now,
+        }
+    }
+
+    fn has(&mut self, amount: f64, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.capacity).min(self.capacity);
+        self.last_refill = now;
+
+        self.tokens >= amount.min(self.capacity)
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn connection_limit_drops_and_reports_once() {
+        let limit = RateLimit {
+            packets_per_second: Some(2),
+            bytes_per_second: None,
+        };
+        let mut limiter = RateLimiter::new(Some(limit), None);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(addr(1), 10, now), Verdict::Allow);
+        assert_eq!(limiter.check(addr(1), 10, now), Verdict::Allow);
+        assert_eq!(limiter.check(addr(1), 10, now), Verdict::DropAndReport);
+        assert_eq!(limiter.check(addr(1), 10, now), Verdict::Drop);
+
+        // other connections have their own allowance
+        assert_eq!(limiter.check(addr(2), 10, now), Verdict::Allow);
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(addr(1), 10, later), Verdict::Allow);
+    }
+
+    #[test]
+    fn socket_limit_is_shared_between_connections() {
+        let limit = RateLimit {
+            packets_per_second: None,
+            bytes_per_second: Some(100),
+        };
+        let mut limiter = RateLimiter::new(None, Some(limit));
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(addr(1), 60, now), Verdict::Allow);
+        assert_eq!(limiter.check(addr(2), 60, now), Verdict::Drop);
+        assert_eq!(limiter.check(addr(2), 40, now), Verdict::Allow);
+    }
+
+    #[test]
+    fn socket_drops_do_not_count_against_the_connection() {
+        let connection = RateLimit {
+            packets_per_second: None,
+            bytes_per_second: Some(100),
+        };
+        let socket = RateLimit {
+            packets_per_second: Some(1),
+            bytes_per_second: None,
+        };
+        let mut limiter = RateLimiter::new(Some(connection), Some(socket));
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(addr(1), 10, now), Verdict::Allow);
+        assert_eq!(limiter.check(addr(2), 90, now), Verdict::Drop);
+
+        // addr(2) still has its whole allowance once the socket limit refills, and a packet larger
+        // than the allowance gets through when the bucket is full
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(addr(2), 150, later), Verdict::Allow);
+        assert_eq!(limiter.check(addr(2), 1, later), Verdict::DropAndReport);
+    }
+
+    #[test]
+    fn tracked_connections_are_bounded() {
+        let limit = RateLimit {
+            packets_per_second: Some(10),
+            bytes_per_second: None,
+        };
+        let mut limiter = RateLimiter::new(Some(limit), None);
+        let now = Instant::now();
+
+        for ip in 0..MAX_CONNECTIONS as u32 {
+            let spoofed = SocketAddr::from((std::net::Ipv4Addr::from(ip), 5000));
+            assert_eq!(limiter.check(spoofed, 10, now), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(addr(1), 10, now), Verdict::Drop);
+
+        // idle connections are forgotten, which makes room again
+        let later = now + IDLE_TIMEOUT;
+        assert_eq!(limiter.check(addr(1), 10, later), Verdict::Allow);
+        assert_eq!(limiter.connections.len(), 1);
+    }
+}
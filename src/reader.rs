@@ -0,0 +1,103 @@
+use bevy::prelude::*;
+use bytes::Bytes;
+
+use super::{Connection, NetworkEvent, SocketHandle};
+
+/// Reads `NetworkEvent`s, optionally only those for a single socket. This lets a plugin that owns
+/// one socket consume its own traffic without checking `Connection::socket` on every event.
+///
+/// Like an `EventReader`, every call to `iter`, `messages` or `connections` moves the reader past
+/// all of the current events, including any that were filtered out.
+#[derive(Default)]
+pub struct NetworkEventReader {
+    reader: EventReader<NetworkEvent>,
+    socket: Option<SocketHandle>,
+}
+
+impl NetworkEventReader {
+    pub fn for_socket(socket: SocketHandle) -> Self {
+        NetworkEventReader {
+            socket: Some(socket),
+            ..Default::default()
+        }
+    }
+
+    /// Changes the socket events are read for. Useful when the reader is created before the
+    /// socket is bound. `None` reads events for every socket.
+    pub fn set_socket(&mut self, socket: Option<SocketHandle>) {
+        self.socket = socket;
+    }
+
+    pub fn socket(&self) -> Option<SocketHandle> {
+        self.socket
+    }
+
+    pub fn iter<'a>(
+        &mut self,
+        events: &'a Events<NetworkEvent>,
+    ) -> impl Iterator<Item = &'a NetworkEvent> {
+        let socket = self.socket;
+
+        self.reader
+            .iter(events)
+            .filter(move |e| socket.is_none() || e.socket() == socket)
+    }
+
+    pub fn messages<'a>(
+        &mut self,
+        events: &'a Events<NetworkEvent>,
+    ) -> impl Iterator<Item = (&'a Connection, &'a Bytes)> {
+        self.iter(events).filter_map(|e| match e {
+            NetworkEvent::Message(conn, msg) => Some((conn, msg)),
+            _ => None,
+        })
+    }
+
+    /// Only the `Connected`, `Reconnected`, `Reconnecting` and `Disconnected` events
+    pub fn connections<'a>(
+        &mut self,
+        events: &'a Events<NetworkEvent>,
+    ) -> impl Iterator<Item = &'a NetworkEvent> {
+        self.iter(events).filter(|e| {
+            matches!(
+                e,
+                NetworkEvent::Connected(..)
+                    | NetworkEvent::Reconnected { .. }
+                    | NetworkEvent::Reconnecting { .. }
+                    | NetworkEvent::Disconnected(_)
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader_only_sees_its_socket() {
+        let ours = SocketHandle::new();
+        let theirs = SocketHandle::new();
+
+        let conn = |socket| Connection {
+            addr: "127.0.0.1:12350".parse().unwrap(),
+            socket,
+            multicast: None,
+        };
+
+        let mut events = Events::<NetworkEvent>::default();
+        events.send(NetworkEvent::Connected(conn(ours), None));
+        events.send(NetworkEvent::Message(conn(theirs), Bytes::from("theirs")));
+        events.send(NetworkEvent::Message(conn(ours), Bytes::from("ours")));
+
+        let mut reader = NetworkEventReader::for_socket(ours);
+        let messages: Vec<_> = reader.messages(&events).map(|(_, m)| m.clone()).collect();
+        assert_eq!(messages, vec![Bytes::from("ours")]);
+
+        // the connected event was passed over by the messages call
+        assert_eq!(reader.connections(&events).count(), 0);
+
+        let mut reader = NetworkEventReader::default();
+        assert_eq!(reader.iter(&events).count(), 3);
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::Message;
+
+/// How `NetworkResource::connect_with_retry` retries a connection that has timed out. The delay
+/// before each attempt doubles from `initial_delay`, up to `max_delay`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// The connection is given up on after this many attempts in a row. `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(8),
+            max_attempts: Some(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the given attempt, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .initial_delay
+            .checked_mul(1 << doublings)
+            .unwrap_or(self.max_delay);
+
+        delay.min(self.max_delay)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Outcome {
+    /// The attempt is scheduled
+    Retrying(u32),
+    /// Every attempt failed, and the server has been forgotten
+    GaveUp(Message),
+}
+
+// Remembers the servers a client connected to with `connect_with_retry`, and the introduction
+// that's sent to them again whenever their connection times out.
+pub(crate) struct Reconnector {
+    servers: HashMap<SocketAddr, Server>,
+}
+
+struct Server {
+    hello: Message,
+    policy: RetryPolicy,
+    // the attempts since the server was last heard from
+    attempt: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Reconnector {
+    pub fn new() -> Self {
+        Reconnector {
+            servers: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, hello: Message, policy: RetryPolicy) {
+        let server = Server {
+            hello,
+            policy,
+            attempt: 0,
+            retry_at: None,
+        };
+
+        self.servers.insert(server.hello.destination, server);
+    }
+
+    pub fn heard_from(&mut self, addr: SocketAddr) {
+        if let Some(server) = self.servers.get_mut(&addr) {
+            server.attempt = 0;
+            server.retry_at = None;
+        }
+    }
+
+    /// Schedules the next attempt for a server whose connection timed out. Returns `None` if the
+    /// address isn't a server we're retrying.
+    pub fn timed_out(&mut self, addr: SocketAddr, now: Instant) -> Option<Outcome> {
+        let server = self.servers.get_mut(&addr)?;
+
+        server.attempt += 1;
+        if server
+            .policy
+            .max_attempts
+            .is_some_and(|max| server.attempt > max)
+        {
+            let server = self.servers.remove(&addr)?;
+            return Some(Outcome::GaveUp(server.hello));
+        }
+
+        server.retry_at = Some(now + server.policy.delay(server.attempt));
+        Some(Outcome::Retrying(server.attempt))
+    }
+
+    /// The introductions that are due to be sent again
+    pub fn due(&mut self, now: Instant) -> Vec<Message> {
+        let mut due = Vec::new();
+
+        for server in self.servers.values_mut() {
+            if server.retry_at.is_some_and(|at| at <= now) {
+                server.retry_at = None;
+                due.push(server.hello.clone());
+            }
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageId, NetworkDelivery, SendPriority, SocketHandle};
+    use bytes::Bytes;
+
+    #[test]
+    fn attempts_back_off_until_the_server_answers() {
+        let server = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let now = Instant::now();
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(3),
+            max_attempts: Some(3),
+        };
+
+        let mut reconnector = Reconnector::new();
+        reconnector.add(
+            Message {
+                id: MessageId(7),
+                message: Bytes::from_static(b"hello"),
+                delivery: NetworkDelivery::ReliableUnordered,
+                socket_handle: SocketHandle::new(),
+                destination: server,
+                track_delivery: false,
+                priority: SendPriority::High,
+            },
+            policy,
+        );
+
+        let other = SocketAddr::from(([127, 0, 0, 1], 4001));
+        assert!(reconnector.timed_out(other, now).is_none());
+
+        assert!(matches!(
+            reconnector.timed_out(server, now),
+            Some(Outcome::Retrying(1))
+        ));
+        assert!(reconnector.due(now).is_empty());
+        assert_eq!(reconnector.due(now + Duration::from_secs(1)).len(), 1);
+        assert!(reconnector.due(now + Duration::from_secs(1)).is_empty());
+
+        assert!(matches!(
+            reconnector.timed_out(server, now),
+            Some(Outcome::Retrying(2))
+        ));
+        assert!(reconnector.due(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(reconnector.due(now + Duration::from_secs(2)).len(), 1);
+
+        // hearing from the server starts the count over
+        reconnector.heard_from(server);
+        assert!(matches!(
+            reconnector.timed_out(server, now),
+            Some(Outcome::Retrying(1))
+        ));
+        assert!(matches!(
+            reconnector.timed_out(server, now),
+            Some(Outcome::Retrying(2))
+        ));
+        assert!(matches!(
+            reconnector.timed_out(server, now),
+            Some(Outcome::Retrying(3))
+        ));
+        assert_eq!(policy.delay(3), Duration::from_secs(3));
+
+        match reconnector.timed_out(server, now) {
+            Some(Outcome::GaveUp(hello)) => assert_eq!(hello.id, MessageId(7)),
+            other => panic!("expected to give up, got {:?}", other),
+        }
+        assert!(reconnector.timed_out(server, now).is_none());
+    }
+}
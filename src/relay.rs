@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::protocol;
+use super::punch::{PunchKey, Rendezvous, REGISTRATION_LIFETIME};
+
+// how often a peer registers again until it's paired
+const REGISTER_INTERVAL: Duration = Duration::from_millis(500);
+
+// The relay server's side. Peers are paired by key, the same way a rendezvous server introduces
+// them, and from then on packets are only forwarded between the two peers of a pair. A pair that
+// forwards nothing for as long as a registration lasts is dropped along with its registration.
+pub(crate) struct RelayServer {
+    rendezvous: Rendezvous,
+    // both directions of each pair, keyed by sender and receiver
+    pairs: HashMap<(SocketAddr, SocketAddr), Pair>,
+}
+
+struct Pair {
+    key: PunchKey,
+    used_at: Instant,
+}
+
+impl RelayServer {
+    pub fn new() -> Self {
+        RelayServer {
+            rendezvous: Rendezvous::new(),
+            pairs: HashMap::new(),
+        }
+    }
+
+    /// Registers the address under the key, returning its peer once both have registered
+    pub fn register(
+        &mut self,
+        addr: SocketAddr,
+        key: PunchKey,
+        now: Instant,
+    ) -> Option<SocketAddr> {
+        let peer = self.rendezvous.register(addr, key, now)?;
+
+        self.pairs.insert((addr, peer), Pair { key, used_at: now });
+        self.pairs.insert((peer, addr), Pair { key, used_at: now });
+        Some(peer)
+    }
+
+    /// Whether packets from one address can be forwarded to the other, which keeps their pair
+    /// alive
+    pub fn may_forward(&mut self, from: SocketAddr, to: SocketAddr, now: Instant) -> bool {
+        if !self.pairs.contains_key(&(from, to)) {
+            return false;
+        }
+
+        for direction in &[(from, to), (to, from)] {
+            if let Some(pair) = self.pairs.get_mut(direction) {
+                pair.used_at = now;
+            }
+        }
+        true
+    }
+
+    /// Drops the pairs that have gone unused for as long as a registration lasts, returning each
+    /// address that was paired along with the peer it was paired with
+    pub fn expire(&mut self, now: Instant) -> Vec<(SocketAddr, SocketAddr)> {
+        let expired: Vec<((SocketAddr, SocketAddr), PunchKey)> = self
+            .pairs
+            .iter()
+            .filter(|(_, pair)| {
+                now.saturating_duration_since(pair.used_at) >= REGISTRATION_LIFETIME
+            })
+            .map(|(direction, pair)| (*direction, pair.key))
+            .collect();
+
+        for (direction, key) in expired.iter() {
+            self.pairs.remove(direction);
+            self.rendezvous.remove(key);
+        }
+
+        expired
+            .into_iter()
+            .map(|(direction, _)| direction)
+            .collect()
+    }
+
+    /// Forgets the address, returning the peers it was paired with
+    pub fn remove_connection(&mut self, addr: SocketAddr) -> Vec<SocketAddr> {
+        let paired: Vec<(SocketAddr, PunchKey)> = self
+            .pairs
+            .iter()
+            .filter(|((from, _), _)| *from == addr)
+            .map(|((_, to), pair)| (*to, pair.key))
+            .collect();
+
+        // the key can be used again, rather than pairing with an address that's gone
+        for (_, key) in paired.iter() {
+            self.rendezvous.remove(key);
+        }
+        self.pairs
+            .retain(|(from, to), _| *from != addr && *to != addr);
+
+        paired.into_iter().map(|(peer, _)| peer).collect()
+    }
+}
+
+// A peer's side. Peers that have been paired through a relay are reached by wrapping every packet
+// for them in a relay frame, which makes them look like any other address to the rest of the
+// worker.
+pub(crate) struct RelayClient {
+    pending: Vec<Pending>,
+    // the relayed peers, and the relay each is reached through
+    peers: HashMap<SocketAddr, SocketAddr>,
+}
+
+struct Pending {
+    relay: SocketAddr,
+    key: PunchKey,
+    sent_at: Option<Instant>,
+    expires_at: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RelayPoll {
+    /// Send a packet to the relay
+    Register(SocketAddr, Vec<u8>),
+    /// The relay didn't pair us with a peer in time
+    Failed(SocketAddr),
+}
+
+impl RelayClient {
+    pub fn new() -> Self {
+        RelayClient {
+            pending: Vec::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn start(&mut self, relay: SocketAddr, key: PunchKey, timeout: Duration, now: Instant) {
+        self.pending.push(Pending {
+            relay,
+            key,
+            sent_at: None,
+            expires_at: now + timeout,
+        });
+    }
+
+    /// The relay paired us with a peer, under the key we registered with. Returns true if the peer
+    /// wasn't relayed before.
+    pub fn paired(&mut self, relay: SocketAddr, peer: SocketAddr, key: &[u8]) -> bool {
+        let found = self
+            .pending
+            .iter()
+            .position(|p| p.relay == relay && p.key[..] == *key);
+        let pending = match found {
+            Some(idx) => self.pending.remove(idx),
+            None => return false,
+        };
+
+        self.peers.insert(peer, pending.relay).is_none()
+    }
+
+    /// The relay the address is reached through, if it's a relayed peer
+    pub fn relay_for(&self, peer: SocketAddr) -> Option<SocketAddr> {
+        self.peers.get(&peer).copied()
+    }
+
+    /// Forgets a peer the relay told us has gone. Returns true if it was reached through the relay.
+    pub fn remove_peer(&mut self, relay: SocketAddr, peer: SocketAddr) -> bool {
+        match self.relay_for(peer) == Some(relay) {
+            true => self.peers.remove(&peer).is_some(),
+            false => false,
+        }
+    }
+
+    /// Forgets the relay, returning the peers that were reached through it
+    pub fn remove_relay(&mut self, relay: SocketAddr) -> Vec<SocketAddr> {
+        self.pending.retain(|p| p.relay != relay);
+
+        let peers: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, r)| **r == relay)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &peers {
+            self.peers.remove(peer);
+        }
+
+        peers
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Vec<RelayPoll> {
+        let mut polled = Vec::new();
+
+        self.pending
+            .retain(|pending| match now < pending.expires_at {
+                true => true,
+                false => {
+                    polled.push(RelayPoll::Failed(pending.relay));
+                    false
+                }
+            });
+
+        for pending in self.pending.iter_mut() {
+            if let Some(sent_at) = pending.sent_at {
+                if now.saturating_duration_since(sent_at) < REGISTER_INTERVAL {
+                    continue;
+                }
+            }
+
+            pending.sent_at = Some(now);
+            let register = protocol::encode_relay_register(&pending.key).to_vec();
+            polled.push(RelayPoll::Register(pending.relay, register));
+        }
+
+        polled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::punch::punch_key;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn only_paired_peers_are_forwarded() {
+        let now = Instant::now();
+        let key = punch_key(b"lobby 7");
+        let mut server = RelayServer::new();
+
+        assert_eq!(server.register(addr(5000), key, now), None);
+        assert!(!server.may_forward(addr(5000), addr(6000), now));
+        assert_eq!(server.register(addr(6000), key, now), Some(addr(5000)));
+        assert!(server.may_forward(addr(5000), addr(6000), now));
+        assert!(server.may_forward(addr(6000), addr(5000), now));
+        assert!(!server.may_forward(addr(7000), addr(6000), now));
+
+        // the key is free again once the pair is gone
+        assert_eq!(server.remove_connection(addr(6000)), vec![addr(5000)]);
+        assert!(!server.may_forward(addr(5000), addr(6000), now));
+        assert_eq!(server.register(addr(6000), key, now), None);
+    }
+
+    #[test]
+    fn idle_pairs_expire_with_their_registration() {
+        let now = Instant::now();
+        let key = punch_key(b"lobby 7");
+        let mut server = RelayServer::new();
+        server.register(addr(5000), key, now);
+        server.register(addr(6000), key, now);
+
+        // forwarding keeps the pair alive
+        let later = now + REGISTRATION_LIFETIME / 2;
+        assert!(server.may_forward(addr(5000), addr(6000), later));
+        assert!(server.expire(now + REGISTRATION_LIFETIME).is_empty());
+
+        let mut expired = server.expire(later + REGISTRATION_LIFETIME);
+        expired.sort();
+        assert_eq!(
+            expired,
+            vec![(addr(5000), addr(6000)), (addr(6000), addr(5000))]
+        );
+        assert!(!server.may_forward(addr(5000), addr(6000), later));
+        assert_eq!(server.register(addr(6000), key, later), None);
+    }
+
+    #[test]
+    fn clients_register_until_paired() {
+        let now = Instant::now();
+        let key = punch_key(b"lobby 7");
+        let mut client = RelayClient::new();
+        client.start(addr(4000), key, Duration::from_secs(5), now);
+
+        let register = protocol::encode_relay_register(&key).to_vec();
+        assert_eq!(
+            client.poll(now),
+            vec![RelayPoll::Register(addr(4000), register)]
+        );
+        assert!(client.poll(now).is_empty());
+
+        // only the relay we registered with can pair us, and only under our key
+        assert!(!client.paired(addr(9999), addr(6000), &key));
+        assert!(!client.paired(addr(4000), addr(6000), &punch_key(b"lobby 8")));
+        assert!(client.paired(addr(4000), addr(6000), &key));
+        assert_eq!(client.relay_for(addr(6000)), Some(addr(4000)));
+        assert!(client.poll(now + REGISTER_INTERVAL).is_empty());
+        assert!(!client.remove_peer(addr(9999), addr(6000)));
+
+        assert_eq!(client.remove_relay(addr(4000)), vec![addr(6000)]);
+        assert_eq!(client.relay_for(addr(6000)), None);
+
+        client.start(addr(4000), key, Duration::from_secs(5), now);
+        let polled = client.poll(now + Duration::from_secs(5));
+        assert_eq!(polled, vec![RelayPoll::Failed(addr(4000))]);
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// how often a session is tagged on an otherwise quiet connection, so the peer notices a new
+// address even when nothing else is being sent
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Tracks the session id of each connection on a socket with `migrate_connections`.
+//
+// Every packet is tagged with its connection's session id, which is picked at random by whichever
+// end sends first. When a packet with a known id arrives from a new address, the connection has
+// moved there, such as after a NAT rebinding or a network change.
+pub(crate) struct SessionIds {
+    ids: HashMap<SocketAddr, u64>,
+    addrs: HashMap<u64, SocketAddr>,
+    // the sessions the peer has sent something on
+    heard: HashSet<u64>,
+    // addresses that connections have moved away from, and where they went
+    moved: HashMap<SocketAddr, SocketAddr>,
+    last_sent: HashMap<SocketAddr, Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Arrival {
+    /// The first packet from the peer on this session
+    New,
+    Known,
+    /// The session belongs to a connection on another address
+    Moved(SocketAddr),
+    /// A late packet from an address the connection has moved away from
+    Stale,
+}
+
+impl SessionIds {
+    pub fn new() -> Self {
+        SessionIds {
+            ids: HashMap::new(),
+            addrs: HashMap::new(),
+            heard: HashSet::new(),
+            moved: HashMap::new(),
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// The id to tag a packet to the address with, starting a session if there isn't one yet
+    pub fn tag(&mut self, addr: SocketAddr, now: Instant) -> u64 {
+        self.last_sent.insert(addr, now);
+
+        if let Some(id) = self.ids.get(&addr) {
+            return *id;
+        }
+
+        let id = Uuid::new_v4().as_u128() as u64;
+        self.ids.insert(addr, id);
+        self.addrs.insert(id, addr);
+        id
+    }
+
+    /// Where a message for the address should be sent, since its connection may have moved
+    pub fn destination(&self, addr: SocketAddr) -> SocketAddr {
+        self.moved.get(&addr).copied().unwrap_or(addr)
+    }
+
+    pub fn has_session(&self, id: u64) -> bool {
+        self.addrs.contains_key(&id)
+    }
+
+    pub fn receive(&mut self, addr: SocketAddr, id: u64) -> Arrival {
+        if self.moved.contains_key(&addr) {
+            return Arrival::Stale;
+        }
+
+        match self.addrs.get(&id) {
+            Some(known) if *known != addr => return Arrival::Moved(*known),
+            Some(_) => {}
+            // the peer started a session, or started over with a new one
+            None => {
+                if let Some(previous) = self.ids.insert(addr, id) {
+                    self.addrs.remove(&previous);
+                    self.heard.remove(&previous);
+                }
+                self.addrs.insert(id, addr);
+            }
+        }
+
+        match self.heard.insert(id) {
+            true => Arrival::New,
+            false => Arrival::Known,
+        }
+    }
+
+    pub fn migrate(&mut self, old: SocketAddr, new: SocketAddr) {
+        if let Some(id) = self.ids.remove(&old) {
+            if let Some(replaced) = self.ids.insert(new, id) {
+                self.addrs.remove(&replaced);
+                self.heard.remove(&replaced);
+            }
+            self.addrs.insert(id, new);
+        }
+        self.last_sent.remove(&old);
+
+        // messages for any of the connection's earlier addresses follow it too
+        for to in self.moved.values_mut() {
+            if *to == old {
+                *to = new;
+            }
+        }
+        self.moved.remove(&new);
+        self.moved.insert(old, new);
+    }
+
+    /// The connections that haven't sent anything for a while
+    pub fn quiet(&self, now: Instant) -> Vec<SocketAddr> {
+        self.ids
+            .keys()
+            .filter(|addr| {
+                self.last_sent
+                    .get(addr)
+                    .is_none_or(|sent| now.saturating_duration_since(*sent) >= KEEPALIVE_INTERVAL)
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Forgets the address. Returns false if its connection had moved somewhere else, and is
+    /// still alive.
+    pub fn remove_connection(&mut self, addr: SocketAddr) -> bool {
+        if self.moved.remove(&addr).is_some() {
+            return false;
+        }
+
+        if let Some(id) = self.ids.remove(&addr) {
+            self.addrs.remove(&id);
+            self.heard.remove(&id);
+        }
+        self.last_sent.remove(&addr);
+        self.moved.retain(|_, to| *to != addr);
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn sessions_follow_their_connection() {
+        let now = Instant::now();
+        let mut client = SessionIds::new();
+        let mut server = SessionIds::new();
+
+        let id = client.tag(addr(4000), now);
+        assert_eq!(client.tag(addr(4000), now), id);
+        assert_eq!(server.receive(addr(5000), id), Arrival::New);
+        assert_eq!(server.receive(addr(5000), id), Arrival::Known);
+        assert_eq!(client.receive(addr(4000), id), Arrival::New);
+
+        // the client's address changes twice
+        assert_eq!(server.receive(addr(5001), id), Arrival::Moved(addr(5000)));
+        server.migrate(addr(5000), addr(5001));
+        assert_eq!(server.receive(addr(5001), id), Arrival::Known);
+        assert_eq!(server.receive(addr(5002), id), Arrival::Moved(addr(5001)));
+        server.migrate(addr(5001), addr(5002));
+        assert_eq!(server.receive(addr(5000), id), Arrival::Stale);
+
+        assert_eq!(server.destination(addr(5000)), addr(5002));
+        assert_eq!(server.destination(addr(5001)), addr(5002));
+        assert_eq!(server.tag(addr(5002), now), id);
+
+        // the old addresses time out quietly, and the new one for real
+        assert!(!server.remove_connection(addr(5000)));
+        assert!(!server.remove_connection(addr(5001)));
+        assert!(server.remove_connection(addr(5002)));
+        assert!(server.quiet(now).is_empty());
+
+        assert_eq!(client.quiet(now + KEEPALIVE_INTERVAL), vec![addr(4000)]);
+    }
+}
@@ -0,0 +1,138 @@
+use bevy::prelude::*;
+use std::time::Duration;
+
+use super::NetworkError;
+
+/// Runs network systems at a fixed rate, independent of the frame rate. The plugin advances the
+/// tick before `stage::UPDATE`, so a send system only has to check `ticked()`:
+///
+/// ```ignore
+/// fn send_positions(tick: Res<NetworkTick>, net: Res<NetworkResource>) {
+///     if !tick.ticked() {
+///         return;
+///     }
+///     // ...
+/// }
+/// ```
+///
+/// Add a `NetworkTick` resource before the `NetworkingPlugin` to change the rate, which defaults
+/// to 20Hz. If a frame is long enough to cover several ticks, they are merged into one, so the
+/// tick number counts the ticks that systems actually saw.
+#[derive(Debug, Clone)]
+pub struct NetworkTick {
+    interval: Duration,
+    accumulated: Duration,
+    tick: u64,
+    ticked: bool,
+}
+
+impl NetworkTick {
+    /// A tick at the given rate, which has to be a positive, finite number of ticks per second
+    pub fn new(hz: f64) -> Result<Self, NetworkError> {
+        Ok(NetworkTick {
+            interval: interval(hz)?,
+            accumulated: Duration::from_secs(0),
+            tick: 0,
+            ticked: false,
+        })
+    }
+
+    /// The number of network ticks so far, starting from 1 on the first tick
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// True during the frames where the network tick advanced
+    pub fn ticked(&self) -> bool {
+        self.ticked
+    }
+
+    pub fn rate(&self) -> f64 {
+        1.0 / self.interval.as_secs_f64()
+    }
+
+    /// Changes the rate, leaving it as it was if the new one isn't valid
+    pub fn set_rate(&mut self, hz: f64) -> Result<(), NetworkError> {
+        self.interval = interval(hz)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn advance(&mut self, delta: Duration) {
+        self.accumulated += delta;
+        self.ticked = self.accumulated >= self.interval;
+
+        if self.ticked {
+            self.tick += 1;
+            self.accumulated -= self.interval;
+
+            // a backlog of whole ticks is dropped, rather than running extra ticks to catch up
+            if self.accumulated >= self.interval {
+                self.accumulated = Duration::from_secs(0);
+            }
+        }
+    }
+}
+
+impl Default for NetworkTick {
+    fn default() -> Self {
+        NetworkTick::new(20.0).expect("20Hz is a valid tick rate")
+    }
+}
+
+fn interval(hz: f64) -> Result<Duration, NetworkError> {
+    let secs = 1.0 / hz;
+
+    // a rate so low that its interval doesn't fit in a `Duration` is rejected too
+    if !(hz > 0.0 && hz.is_finite() && secs < u64::MAX as f64) {
+        return Err(NetworkError::InvalidConfig {
+            field: "tick_rate",
+            reason: "must be a positive, finite number of ticks per second".to_string(),
+        });
+    }
+
+    Ok(Duration::from_secs_f64(secs))
+}
+
+pub(crate) fn network_tick_system(time: Res<Time>, mut tick: ResMut<NetworkTick>) {
+    tick.advance(time.delta);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_at_a_fixed_rate() {
+        let mut tick = NetworkTick::new(20.0).unwrap();
+        let frame = Duration::from_millis(20);
+
+        let mut ticked = Vec::new();
+        for _ in 0..6 {
+            tick.advance(frame);
+            ticked.push(tick.ticked());
+        }
+
+        assert_eq!(ticked, vec![false, false, true, false, true, false]);
+        assert_eq!(tick.tick(), 2);
+
+        // a long frame only produces a single tick
+        tick.advance(Duration::from_secs(1));
+        assert!(tick.ticked());
+        assert_eq!(tick.tick(), 3);
+
+        tick.advance(frame);
+        assert!(!tick.ticked());
+    }
+
+    #[test]
+    fn invalid_rates_are_rejected() {
+        for hz in &[0.0, -5.0, f64::NAN, f64::INFINITY, 1e-300] {
+            assert!(NetworkTick::new(*hz).is_err());
+        }
+
+        let mut tick = NetworkTick::default();
+        assert!(tick.set_rate(0.0).is_err());
+        assert!((tick.rate() - 20.0).abs() < 1e-9);
+    }
+}
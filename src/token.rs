@@ -0,0 +1,339 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::NetworkError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TOKEN_VERSION: u8 = 1;
+const TAG_SIZE: usize = 32;
+
+/// The most user data a connect token can carry
+pub const MAX_TOKEN_USER_DATA: usize = 256;
+
+/// Who a connection was authenticated as, from the connect token it presented
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAuth {
+    pub client_id: u64,
+    pub user_data: Bytes,
+}
+
+// the contents of a connect token
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConnectToken {
+    pub client_id: u64,
+    pub expires_at: SystemTime,
+    /// The server the token can be used on
+    pub server_addr: SocketAddr,
+    /// Anything the matchmaker wants to pass on to the server, such as a player name or team
+    pub user_data: Bytes,
+}
+
+/// Issues connect tokens for the servers that share its key through
+/// `LaminarConfig::connect_token_key`. A matchmaker would normally issue the tokens and hand them
+/// to its clients, which present them with `NetworkResource::connect_with_token`, but an issuer
+/// can also be used in-process, such as in tests.
+///
+/// Tokens are signed with HMAC-SHA256, but aren't encrypted, so the user data shouldn't contain
+/// anything the client isn't allowed to read.
+pub struct TokenIssuer {
+    key: [u8; 32],
+}
+
+impl TokenIssuer {
+    pub fn new(key: [u8; 32]) -> Self {
+        TokenIssuer { key }
+    }
+
+    /// Signs a token that lets the client connect to the server until it expires
+    pub fn issue(
+        &self,
+        client_id: u64,
+        server_addr: SocketAddr,
+        valid_for: Duration,
+        user_data: &[u8],
+    ) -> Result<Bytes, NetworkError> {
+        if user_data.len() > MAX_TOKEN_USER_DATA {
+            return Err(NetworkError::PayloadTooLarge {
+                size: user_data.len(),
+                max: MAX_TOKEN_USER_DATA,
+            });
+        }
+
+        let expires_at = SystemTime::now() + valid_for;
+        let expires_at = expires_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut buf = BytesMut::with_capacity(64 + user_data.len());
+        buf.put_u8(TOKEN_VERSION);
+        buf.put_u64(client_id);
+        buf.put_u64(expires_at);
+        match server_addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.put_u8(4);
+                buf.put_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.put_u8(6);
+                buf.put_slice(&ip.octets());
+            }
+        }
+        buf.put_u16(server_addr.port());
+        buf.put_u16(user_data.len() as u16);
+        buf.put_slice(user_data);
+
+        let tag = sign(&self.key, &buf);
+        buf.put_slice(&tag);
+
+        Ok(buf.freeze())
+    }
+}
+
+/// Why a connect token was rejected
+#[derive(Debug, PartialEq)]
+pub(crate) enum Rejection {
+    Malformed,
+    BadSignature,
+    Expired,
+    WrongServer,
+    /// The token has already been used from another address
+    Reused,
+}
+
+// Checks the connect tokens presented to a server socket, and remembers who each connection was
+// authenticated as
+pub(crate) struct TokenVerifier {
+    key: [u8; 32],
+    local_addrs: Vec<SocketAddr>,
+    authenticated: HashMap<SocketAddr, ClientAuth>,
+    // the signatures of the tokens that have been accepted, so they can't be replayed from
+    // another address while they're still valid
+    used: HashMap<[u8; TAG_SIZE], (SocketAddr, SystemTime)>,
+}
+
+impl TokenVerifier {
+    pub fn new(key: [u8; 32], local_addrs: Vec<SocketAddr>) -> Self {
+        TokenVerifier {
+            key,
+            local_addrs,
+            authenticated: HashMap::new(),
+            used: HashMap::new(),
+        }
+    }
+
+    pub fn is_authenticated(&self, addr: SocketAddr) -> bool {
+        self.authenticated.contains_key(&addr)
+    }
+
+    pub fn verify(
+        &mut self,
+        addr: SocketAddr,
+        token: &[u8],
+        now: SystemTime,
+    ) -> Result<ClientAuth, Rejection> {
+        if token.len() < TAG_SIZE {
+            return Err(Rejection::Malformed);
+        }
+
+        let (signed, tag) = token.split_at(token.len() - TAG_SIZE);
+        let mut mac = HmacSha256::new_varkey(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(signed);
+        mac.verify(tag).map_err(|_| Rejection::BadSignature)?;
+
+        let token = decode(signed).ok_or(Rejection::Malformed)?;
+
+        if token.expires_at <= now {
+            return Err(Rejection::Expired);
+        }
+
+        if !self.serves(token.server_addr) {
+            return Err(Rejection::WrongServer);
+        }
+
+        self.used.retain(|_, (_, expires_at)| *expires_at > now);
+        let tag: [u8; TAG_SIZE] = tag.try_into().map_err(|_| Rejection::Malformed)?;
+        match self.used.get(&tag) {
+            Some((used_by, _)) if *used_by != addr => return Err(Rejection::Reused),
+            _ => {
+                self.used.insert(tag, (addr, token.expires_at));
+            }
+        }
+
+        let auth = ClientAuth {
+            client_id: token.client_id,
+            user_data: token.user_data,
+        };
+        self.authenticated.insert(addr, auth.clone());
+
+        Ok(auth)
+    }
+
+    pub fn migrate(&mut self, old: SocketAddr, new: SocketAddr) {
+        if let Some(auth) = self.authenticated.remove(&old) {
+            self.authenticated.insert(new, auth);
+        }
+
+        for (used_by, _) in self.used.values_mut() {
+            if *used_by == old {
+                *used_by = new;
+            }
+        }
+    }
+
+    pub fn remove_connection(&mut self, addr: SocketAddr) {
+        self.authenticated.remove(&addr);
+    }
+
+    // a socket bound to an unspecified address accepts tokens for any address on its port, since
+    // it can't know which of the machine's addresses the client was given
+    fn serves(&self, server_addr: SocketAddr) -> bool {
+        self.local_addrs.iter().any(|local| {
+            *local == server_addr
+                || (local.ip().is_unspecified() && local.port() == server_addr.port())
+        })
+    }
+}
+
+fn sign(key: &[u8], data: &[u8]) -> [u8; TAG_SIZE] {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+
+    let mut tag = [0; TAG_SIZE];
+    tag.copy_from_slice(&mac.finalize().into_bytes());
+    tag
+}
+
+fn decode(mut signed: &[u8]) -> Option<ConnectToken> {
+    let version = take(&mut signed, 1)?[0];
+    if version != TOKEN_VERSION {
+        return None;
+    }
+
+    let client_id = u64::from_be_bytes(take(&mut signed, 8)?.try_into().ok()?);
+    let expires_at = u64::from_be_bytes(take(&mut signed, 8)?.try_into().ok()?);
+
+    let ip = match take(&mut signed, 1)?[0] {
+        4 => {
+            let octets: [u8; 4] = take(&mut signed, 4)?.try_into().ok()?;
+            IpAddr::from(octets)
+        }
+        6 => {
+            let octets: [u8; 16] = take(&mut signed, 16)?.try_into().ok()?;
+            IpAddr::from(octets)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(take(&mut signed, 2)?.try_into().ok()?);
+
+    let len = u16::from_be_bytes(take(&mut signed, 2)?.try_into().ok()?) as usize;
+    let user_data = take(&mut signed, len)?;
+    if !signed.is_empty() {
+        return None;
+    }
+
+    Some(ConnectToken {
+        client_id,
+        expires_at: UNIX_EPOCH + Duration::from_secs(expires_at),
+        server_addr: SocketAddr::new(ip, port),
+        user_data: Bytes::copy_from_slice(user_data),
+    })
+}
+
+pub(crate) fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if bytes.len() < len {
+        return None;
+    }
+
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Some(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn valid_tokens_are_accepted_once() {
+        let server = addr(4000);
+        let issuer = TokenIssuer::new(KEY);
+        let token = issuer
+            .issue(42, server, Duration::from_secs(30), b"blue team")
+            .unwrap();
+
+        let mut verifier = TokenVerifier::new(KEY, vec![server]);
+        let auth = verifier
+            .verify(addr(5000), &token, SystemTime::now())
+            .unwrap();
+        assert_eq!(auth.client_id, 42);
+        assert_eq!(&auth.user_data[..], b"blue team");
+        assert!(verifier.is_authenticated(addr(5000)));
+
+        // the same client can present it again, but nobody else can
+        assert!(verifier
+            .verify(addr(5000), &token, SystemTime::now())
+            .is_ok());
+        assert_eq!(
+            verifier.verify(addr(5001), &token, SystemTime::now()),
+            Err(Rejection::Reused)
+        );
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        let server = addr(4000);
+        let now = SystemTime::now();
+        let token = TokenIssuer::new(KEY)
+            .issue(42, server, Duration::from_secs(30), b"")
+            .unwrap();
+        let mut verifier = TokenVerifier::new(KEY, vec![server]);
+
+        let mut tampered = token.to_vec();
+        tampered[1] ^= 1;
+        assert_eq!(
+            verifier.verify(addr(5000), &tampered, now),
+            Err(Rejection::BadSignature)
+        );
+
+        let forged = TokenIssuer::new([8; 32])
+            .issue(42, server, Duration::from_secs(30), b"")
+            .unwrap();
+        assert_eq!(
+            verifier.verify(addr(5000), &forged, now),
+            Err(Rejection::BadSignature)
+        );
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            verifier.verify(addr(5000), &token, later),
+            Err(Rejection::Expired)
+        );
+
+        let mut elsewhere = TokenVerifier::new(KEY, vec![addr(4001)]);
+        assert_eq!(
+            elsewhere.verify(addr(5000), &token, now),
+            Err(Rejection::WrongServer)
+        );
+        let mut unspecified = TokenVerifier::new(KEY, vec!["0.0.0.0:4000".parse().unwrap()]);
+        assert!(unspecified.verify(addr(5000), &token, now).is_ok());
+
+        assert_eq!(
+            verifier.verify(addr(5000), &[1, 2, 3], now),
+            Err(Rejection::Malformed)
+        );
+        assert!(!verifier.is_authenticated(addr(5000)));
+    }
+}
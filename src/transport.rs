@@ -1,33 +1,600 @@
 use laminar::Config;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
+use super::encryption::ENCRYPTION_OVERHEAD;
+use super::protocol::{CHUNK_HEADER_SIZE, FRAME_HEADER_SIZE, SESSION_HEADER_SIZE};
+use super::{Compression, Encryption, NetworkDelivery, NetworkError, RateLimit};
+
+// the most header bytes laminar puts in front of a fragment: its standard, fragment, acked and
+// ordering headers
+const LAMINAR_HEADER_SIZE: usize = 5 + 4 + 8 + 3;
+
 pub enum Transport {
     Laminar(LaminarConfig),
 }
 
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Laminar(LaminarConfig::default())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct LaminarConfig {
+    #[serde(with = "crate::config::millis")]
     pub idle_connection_timeout: Duration,
+    #[serde(with = "crate::config::millis::option")]
     pub heartbeat_interval: Option<Duration>,
     pub max_packets_in_flight: u16,
+    /// Make the underlying UDP socket block when receiving. Every socket is polled from the same
+    /// worker thread, so a blocking socket needs a `socket_polling_timeout`.
+    pub blocking_mode: bool,
+    /// The largest packet laminar will send or accept, including fragmented packets
+    pub max_packet_size: usize,
+    /// The most fragments a reliable packet can be split into. Laminar can't carry a packet
+    /// larger than 65535 bytes, so `fragment_size * max_fragments` can be at most that.
+    pub max_fragments: u8,
+    /// The size of a single fragment, which is also the largest unreliable payload
+    pub fragment_size: u16,
+    /// The number of fragmented packets that can be reassembled at once
+    pub fragment_reassembly_buffer_size: u16,
+    /// The size of the buffer that incoming datagrams are read into, which has to fit a fragment
+    /// and up to 20 bytes of laminar headers
+    pub receive_buffer_max_size: usize,
+    /// How much each new round trip sample contributes to the smoothed RTT, between 0 and 1
+    pub rtt_smoothing_factor: f32,
+    /// The highest round trip time, in milliseconds, that is still considered acceptable
+    pub rtt_max_value: u16,
+    /// The number of socket events that can be buffered before laminar drops them
+    pub socket_event_buffer_size: usize,
+    /// How long a blocking socket waits for data before the worker moves on
+    #[serde(with = "crate::config::millis::option")]
+    pub socket_polling_timeout: Option<Duration>,
+    /// Inbound limits applied to each connection individually
+    pub connection_rate_limit: Option<RateLimit>,
+    /// Inbound limits applied to the socket as a whole
+    pub socket_rate_limit: Option<RateLimit>,
+    /// Outbound limits applied to each connection individually. Messages over the budget are
+    /// held back until it refills, along with the messages after them on the same ordered or
+    /// sequenced stream, except for low priority unreliable messages, which are dropped. Once 1024
+    /// messages are held back for a connection, any more fail with
+    /// `NetworkError::SendBudgetExceeded`.
+    pub connection_send_budget: Option<RateLimit>,
+    /// Outbound limits applied to the socket as a whole
+    pub socket_send_budget: Option<RateLimit>,
+    /// The largest message that will be reassembled from a `send_large` transfer. Each
+    /// connection can have at most 4 transfers in flight, and a transfer that goes 30 seconds
+    /// without a chunk is dropped.
+    pub max_transfer_size: usize,
+    /// How long to wait for the ack of an unreliable message sent with
+    /// `SendConfig::track_delivery` before it's reported as `NetworkEvent::Lost`
+    #[serde(with = "crate::config::millis")]
+    pub delivery_timeout: Duration,
+    /// Pack small messages sent to the same connection with the same delivery into a single
+    /// packet, up to the `fragment_size`. Both ends of a connection have to be on a version that
+    /// understands batches.
+    pub coalesce_messages: bool,
+    /// The codec outgoing packets are compressed with
+    pub compression: Compression,
+    /// Packets smaller than this many bytes are never compressed
+    pub compression_threshold: usize,
+    /// Make every peer prove it can receive at its address before the socket keeps any state for
+    /// it or sends it anything larger than it sent. Both ends of a connection have to use it.
+    /// Laminar itself still tracks a bare connection for each address it hears from, until the
+    /// connection times out.
+    pub challenge_connections: bool,
+    /// Encrypt everything sent on the socket. Connections to peers without the same setting will
+    /// never be established. Requires `challenge_connections`.
+    pub encryption: Option<Encryption>,
+    /// Tag every packet with a session id, so a connection can move to a new address, such as
+    /// after a NAT rebinding, and be reported with `NetworkEvent::Reconnected`. Both ends of a
+    /// connection have to use it. Laminar keeps its reliability state per address, so reliable
+    /// messages that were in flight during the move can be lost, and messages on an ordered
+    /// stream won't arrive after the move.
+    pub migrate_connections: bool,
+    /// Only accept connections that present a connect token signed with this key, see
+    /// `TokenIssuer`
+    pub connect_token_key: Option<[u8; 32]>,
+}
+
+/// Builds a `LaminarConfig`, starting from the defaults and validating the result
+#[derive(Debug, Clone, Default)]
+pub struct LaminarConfigBuilder {
+    config: LaminarConfig,
+}
+
+/// The largest payloads that can be sent on a socket in a single message
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PayloadLimits {
+    pub reliable: usize,
+    pub unreliable: usize,
 }
 
 impl Default for LaminarConfig {
     fn default() -> Self {
+        let laminar = Config::default();
+
         LaminarConfig {
             idle_connection_timeout: Duration::from_millis(5000),
             heartbeat_interval: Some(Duration::from_millis(1000)),
             max_packets_in_flight: 1024,
+            blocking_mode: false,
+            max_packet_size: laminar.max_packet_size,
+            max_fragments: laminar.max_fragments,
+            fragment_size: laminar.fragment_size,
+            fragment_reassembly_buffer_size: laminar.fragment_reassembly_buffer_size,
+            receive_buffer_max_size: laminar.receive_buffer_max_size,
+            rtt_smoothing_factor: laminar.rtt_smoothing_factor,
+            rtt_max_value: laminar.rtt_max_value,
+            socket_event_buffer_size: laminar.socket_event_buffer_size,
+            socket_polling_timeout: laminar.socket_polling_timeout,
+            connection_rate_limit: None,
+            socket_rate_limit: None,
+            connection_send_budget: None,
+            socket_send_budget: None,
+            max_transfer_size: 16 * 1024 * 1024,
+            delivery_timeout: Duration::from_millis(1000),
+            coalesce_messages: false,
+            compression: Compression::None,
+            compression_threshold: 128,
+            challenge_connections: false,
+            encryption: None,
+            migrate_connections: false,
+            connect_token_key: None,
         }
     }
 }
 
+impl LaminarConfig {
+    pub fn builder() -> LaminarConfigBuilder {
+        LaminarConfigBuilder::default()
+    }
+
+    pub fn validate(&self) -> Result<(), NetworkError> {
+        let fragment_size = self.fragment_size as usize;
+
+        if self.idle_connection_timeout == Duration::from_secs(0) {
+            return invalid("idle_connection_timeout", "must be greater than zero");
+        }
+
+        if let Some(heartbeat) = self.heartbeat_interval {
+            if heartbeat >= self.idle_connection_timeout {
+                return invalid(
+                    "heartbeat_interval",
+                    "must be shorter than the idle_connection_timeout",
+                );
+            }
+        }
+
+        if self.max_packets_in_flight == 0 {
+            return invalid("max_packets_in_flight", "must be greater than zero");
+        }
+
+        if self.blocking_mode && self.socket_polling_timeout.is_none() {
+            return invalid(
+                "socket_polling_timeout",
+                "must be set when blocking_mode is enabled",
+            );
+        }
+
+        if fragment_size <= FRAME_HEADER_SIZE + CHUNK_HEADER_SIZE {
+            return invalid(
+                "fragment_size",
+                format!(
+                    "must be greater than {} bytes",
+                    FRAME_HEADER_SIZE + CHUNK_HEADER_SIZE
+                ),
+            );
+        }
+
+        if self.max_fragments == 0 {
+            return invalid("max_fragments", "must be greater than zero");
+        }
+
+        if fragment_size * self.max_fragments as usize > u16::MAX as usize {
+            return invalid(
+                "max_fragments",
+                format!(
+                    "fragment_size * max_fragments must be at most {} bytes",
+                    u16::MAX
+                ),
+            );
+        }
+
+        if self.max_packet_size < fragment_size {
+            return invalid("max_packet_size", "must be at least the fragment_size");
+        }
+
+        if self.fragment_reassembly_buffer_size == 0 {
+            return invalid(
+                "fragment_reassembly_buffer_size",
+                "must be greater than zero",
+            );
+        }
+
+        if self.receive_buffer_max_size < fragment_size + LAMINAR_HEADER_SIZE {
+            return invalid(
+                "receive_buffer_max_size",
+                format!(
+                    "must be at least the fragment_size plus {} bytes of laminar headers",
+                    LAMINAR_HEADER_SIZE
+                ),
+            );
+        }
+
+        if !(self.rtt_smoothing_factor > 0.0 && self.rtt_smoothing_factor <= 1.0) {
+            return invalid(
+                "rtt_smoothing_factor",
+                "must be greater than 0 and at most 1",
+            );
+        }
+
+        if self.rtt_max_value == 0 {
+            return invalid("rtt_max_value", "must be greater than zero");
+        }
+
+        if self.socket_event_buffer_size == 0 {
+            return invalid("socket_event_buffer_size", "must be greater than zero");
+        }
+
+        let limits = [
+            ("connection_rate_limit", self.connection_rate_limit),
+            ("socket_rate_limit", self.socket_rate_limit),
+            ("connection_send_budget", self.connection_send_budget),
+            ("socket_send_budget", self.socket_send_budget),
+        ];
+        for &(field, limit) in limits.iter() {
+            if let Some(limit) = limit {
+                // a bucket that holds nothing would drop every packet without a word
+                if limit.packets_per_second == Some(0) || limit.bytes_per_second == Some(0) {
+                    return invalid(field, "must be greater than zero, or None to not limit");
+                }
+            }
+        }
+
+        if self.max_transfer_size == 0 {
+            return invalid("max_transfer_size", "must be greater than zero");
+        }
+
+        if self.delivery_timeout == Duration::from_secs(0) {
+            return invalid("delivery_timeout", "must be greater than zero");
+        }
+
+        #[cfg(not(feature = "encryption"))]
+        {
+            if self.encryption.is_some() {
+                return invalid("encryption", "requires the `encryption` feature");
+            }
+        }
+
+        if self.encryption.is_some() && !self.challenge_connections {
+            return invalid("encryption", "requires challenge_connections");
+        }
+
+        #[cfg(feature = "zstd")]
+        {
+            if let Compression::Zstd { level } = self.compression {
+                if !(1..=22).contains(&level) {
+                    return invalid("compression", "the zstd level must be between 1 and 22");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // the rate limits, send budgets, transfer size, delivery timeout, coalescing, compression,
+    // challenges, encryption, connect tokens and migration are handled by the worker, so they can
+    // change without touching the laminar socket
+    pub(crate) fn requires_rebind(&self, other: &LaminarConfig) -> bool {
+        let mut laminar_only = other.clone();
+        laminar_only.connection_rate_limit = self.connection_rate_limit;
+        laminar_only.socket_rate_limit = self.socket_rate_limit;
+        laminar_only.connection_send_budget = self.connection_send_budget;
+        laminar_only.socket_send_budget = self.socket_send_budget;
+        laminar_only.max_transfer_size = self.max_transfer_size;
+        laminar_only.delivery_timeout = self.delivery_timeout;
+        laminar_only.coalesce_messages = self.coalesce_messages;
+        laminar_only.compression = self.compression;
+        laminar_only.compression_threshold = self.compression_threshold;
+        laminar_only.challenge_connections = self.challenge_connections;
+        laminar_only.encryption = self.encryption.clone();
+        laminar_only.connect_token_key = self.connect_token_key;
+        laminar_only.migrate_connections = self.migrate_connections;
+
+        *self != laminar_only
+    }
+}
+
+fn invalid<R: Into<String>>(field: &'static str, reason: R) -> Result<(), NetworkError> {
+    Err(NetworkError::InvalidConfig {
+        field,
+        reason: reason.into(),
+    })
+}
+
+impl LaminarConfigBuilder {
+    pub fn idle_connection_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_connection_timeout = timeout;
+        self
+    }
+
+    pub fn heartbeat_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.heartbeat_interval = interval;
+        self
+    }
+
+    pub fn max_packets_in_flight(mut self, max: u16) -> Self {
+        self.config.max_packets_in_flight = max;
+        self
+    }
+
+    pub fn blocking_mode(mut self, blocking: bool) -> Self {
+        self.config.blocking_mode = blocking;
+        self
+    }
+
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.config.max_packet_size = size;
+        self
+    }
+
+    pub fn max_fragments(mut self, max: u8) -> Self {
+        self.config.max_fragments = max;
+        self
+    }
+
+    pub fn fragment_size(mut self, size: u16) -> Self {
+        self.config.fragment_size = size;
+        self
+    }
+
+    pub fn fragment_reassembly_buffer_size(mut self, size: u16) -> Self {
+        self.config.fragment_reassembly_buffer_size = size;
+        self
+    }
+
+    pub fn receive_buffer_max_size(mut self, size: usize) -> Self {
+        self.config.receive_buffer_max_size = size;
+        self
+    }
+
+    pub fn rtt_smoothing_factor(mut self, factor: f32) -> Self {
+        self.config.rtt_smoothing_factor = factor;
+        self
+    }
+
+    pub fn rtt_max_value(mut self, millis: u16) -> Self {
+        self.config.rtt_max_value = millis;
+        self
+    }
+
+    pub fn socket_event_buffer_size(mut self, size: usize) -> Self {
+        self.config.socket_event_buffer_size = size;
+        self
+    }
+
+    pub fn socket_polling_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.socket_polling_timeout = timeout;
+        self
+    }
+
+    pub fn connection_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.config.connection_rate_limit = limit;
+        self
+    }
+
+    pub fn socket_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.config.socket_rate_limit = limit;
+        self
+    }
+
+    pub fn connection_send_budget(mut self, budget: Option<RateLimit>) -> Self {
+        self.config.connection_send_budget = budget;
+        self
+    }
+
+    pub fn socket_send_budget(mut self, budget: Option<RateLimit>) -> Self {
+        self.config.socket_send_budget = budget;
+        self
+    }
+
+    pub fn max_transfer_size(mut self, size: usize) -> Self {
+        self.config.max_transfer_size = size;
+        self
+    }
+
+    pub fn delivery_timeout(mut self, timeout: Duration) -> Self {
+        self.config.delivery_timeout = timeout;
+        self
+    }
+
+    pub fn coalesce_messages(mut self, coalesce: bool) -> Self {
+        self.config.coalesce_messages = coalesce;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.config.compression = compression;
+        self
+    }
+
+    pub fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.config.compression_threshold = threshold;
+        self
+    }
+
+    pub fn challenge_connections(mut self, challenge: bool) -> Self {
+        self.config.challenge_connections = challenge;
+        self
+    }
+
+    pub fn encryption(mut self, encryption: Option<Encryption>) -> Self {
+        self.config.encryption = encryption;
+        self
+    }
+
+    pub fn migrate_connections(mut self, migrate: bool) -> Self {
+        self.config.migrate_connections = migrate;
+        self
+    }
+
+    pub fn connect_token_key(mut self, key: Option<[u8; 32]>) -> Self {
+        self.config.connect_token_key = key;
+        self
+    }
+
+    pub fn build(self) -> Result<LaminarConfig, NetworkError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
 impl From<LaminarConfig> for Config {
     fn from(cfg: LaminarConfig) -> Self {
         Config {
+            blocking_mode: cfg.blocking_mode,
             idle_connection_timeout: cfg.idle_connection_timeout,
             heartbeat_interval: cfg.heartbeat_interval,
+            max_packet_size: cfg.max_packet_size,
+            max_fragments: cfg.max_fragments,
+            fragment_size: cfg.fragment_size,
+            fragment_reassembly_buffer_size: cfg.fragment_reassembly_buffer_size,
+            receive_buffer_max_size: cfg.receive_buffer_max_size,
+            rtt_smoothing_factor: cfg.rtt_smoothing_factor,
+            rtt_max_value: cfg.rtt_max_value,
+            socket_event_buffer_size: cfg.socket_event_buffer_size,
+            socket_polling_timeout: cfg.socket_polling_timeout,
             max_packets_in_flight: cfg.max_packets_in_flight,
-            ..Default::default()
         }
     }
 }
+
+impl PayloadLimits {
+    pub(crate) fn from_config(cfg: &LaminarConfig) -> Self {
+        // laminar can fragment reliable packets, but unreliable packets have to fit in one fragment.
+        // Laminar stores a packet's length in a u16 and truncates anything longer, so that's as
+        // much as a reliable packet can carry however many fragments are allowed.
+        let fragmented = cfg.fragment_size as usize * cfg.max_fragments as usize;
+        let reliable = fragmented.min(cfg.max_packet_size).min(u16::MAX as usize);
+        let unreliable = (cfg.fragment_size as usize).min(cfg.max_packet_size);
+        let mut overhead = FRAME_HEADER_SIZE;
+        if cfg.encryption.is_some() {
+            overhead += ENCRYPTION_OVERHEAD;
+        }
+        if cfg.migrate_connections {
+            overhead += SESSION_HEADER_SIZE;
+        }
+
+        PayloadLimits {
+            reliable: reliable.saturating_sub(overhead),
+            unreliable: unreliable.saturating_sub(overhead),
+        }
+    }
+
+    pub fn for_delivery(&self, delivery: NetworkDelivery) -> usize {
+        match delivery {
+            NetworkDelivery::UnreliableUnordered | NetworkDelivery::UnreliableSequenced(_) => {
+                self.unreliable
+            }
+            NetworkDelivery::ReliableUnordered
+            | NetworkDelivery::ReliableSequenced(_)
+            | NetworkDelivery::ReliableOrdered(_) => self.reliable,
+        }
+    }
+
+    pub(crate) fn chunk_size(&self) -> usize {
+        self.reliable.saturating_sub(CHUNK_HEADER_SIZE).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(LaminarConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn builder_rejects_invalid_values() {
+        let result = LaminarConfig::builder().rtt_smoothing_factor(1.5).build();
+        assert!(matches!(
+            result,
+            Err(NetworkError::InvalidConfig {
+                field: "rtt_smoothing_factor",
+                ..
+            })
+        ));
+
+        let result = LaminarConfig::builder()
+            .idle_connection_timeout(Duration::from_millis(500))
+            .heartbeat_interval(Some(Duration::from_millis(1000)))
+            .build();
+        assert!(result.is_err());
+
+        let result = LaminarConfig::builder()
+            .fragment_size(4096)
+            .max_fragments(15)
+            .max_packet_size(60 * 1024)
+            .receive_buffer_max_size(8192)
+            .build();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn limits_laminar_would_break_are_rejected() {
+        let result = LaminarConfig::builder()
+            .fragment_size(4096)
+            .max_fragments(16)
+            .max_packet_size(64 * 1024)
+            .receive_buffer_max_size(8192)
+            .build();
+        assert!(result.is_err());
+
+        // the receive buffer also has to hold laminar's headers
+        let result = LaminarConfig::builder()
+            .fragment_size(1024)
+            .receive_buffer_max_size(1024)
+            .build();
+        assert!(result.is_err());
+
+        let result = LaminarConfig::builder()
+            .socket_send_budget(Some(RateLimit {
+                packets_per_second: Some(0),
+                bytes_per_second: None,
+            }))
+            .build();
+        assert!(matches!(
+            result,
+            Err(NetworkError::InvalidConfig {
+                field: "socket_send_budget",
+                ..
+            })
+        ));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encryption_requires_the_challenge() {
+        let builder = LaminarConfig::builder().encryption(Some(Encryption::default()));
+        assert!(builder.clone().build().is_err());
+        assert!(builder.challenge_connections(true).build().is_ok());
+    }
+
+    #[test]
+    fn reliable_limit_fits_in_a_laminar_packet() {
+        let cfg = LaminarConfig {
+            fragment_size: 4096,
+            max_fragments: 255,
+            max_packet_size: 1024 * 1024,
+            ..Default::default()
+        };
+
+        let limits = PayloadLimits::from_config(&cfg);
+        assert_eq!(limits.reliable, u16::MAX as usize - FRAME_HEADER_SIZE);
+    }
+}
@@ -1,14 +1,42 @@
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use std::sync::Mutex;
+use std::cmp::Reverse;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use bytes::Bytes;
 
-use laminar::{Packet, Socket, SocketEvent};
+use laminar::{DeliveryGuarantee, OrderingGuarantee, Packet, Socket, SocketEvent};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 
+use super::challenge::Challenger;
+use super::chunk::{Reassembler, Reassembly};
+use super::compression::{self, SharedCompressionStats};
+use super::delivery::DeliveryTracker;
+use super::discovery::{Advertiser, Prober};
+use super::encryption::Sessions;
 use super::error::NetworkError;
-use super::{Connection, Message, NetworkEvent, NetworkResource, SocketHandle, WorkerInstructions};
+use super::multicast::Multicast;
+use super::protocol::{self, Frame, BATCH_ENTRY_HEADER_SIZE, FRAME_HEADER_SIZE, PUNCH_KEY_SIZE};
+use super::punch::{Punch, Puncher, Rendezvous};
+use super::rate_limit::{RateLimiter, Verdict};
+use super::reconnect::{Outcome, Reconnector};
+use super::relay::{RelayClient, RelayPoll, RelayServer};
+use super::session::{Arrival, SessionIds};
+use super::token::TokenVerifier;
+use super::{
+    Connection, LaminarConfig, Message, MessageId, NetworkDelivery, NetworkEvent, NetworkResource,
+    PayloadLimits, SendPriority, SocketHandle, TransferId, WorkerInstructions,
+};
+
+// how many messages can be held back for a connection that's over its send budget, before more
+// fail instead
+const MAX_DEFERRED_PER_CONNECTION: usize = 1024;
+// how often a peer that hasn't answered is pinged again
+const PING_INTERVAL: Duration = Duration::from_millis(250);
 
 const SEND_EXPECT: &str =
     "The networking worker thread is no longer able to send messages back to the receiver.";
@@ -24,6 +52,7 @@ pub fn start_worker_thread() -> NetworkResource {
     let mut sockets = TrackedSockets {
         sockets: Vec::new(),
     };
+    let mut probes: Vec<Prober> = Vec::new();
 
     let sleep_time = Duration::from_millis(1);
 
@@ -31,6 +60,8 @@ pub fn start_worker_thread() -> NetworkResource {
         default_socket: None,
         bound_sockets: Vec::new(),
         connections: Vec::new(),
+        next_transfer_id: AtomicU32::new(0),
+        next_message_id: AtomicU64::new(0),
         message_tx: Mutex::new(message_tx),
         event_rx: Mutex::new(event_rx),
         instruction_tx: Mutex::new(instruction_tx),
@@ -51,13 +82,15 @@ pub fn start_worker_thread() -> NetworkResource {
 
         start = std::time::Instant::now();
 
-        let should_terminate = handle_instructions(&mut sockets, &instruction_rx);
+        let should_terminate =
+            handle_instructions(&mut sockets, &mut probes, &instruction_rx, &event_tx);
         if should_terminate {
             break;
         }
         poll_sockets(&mut sockets);
         send_messages(&mut sockets, &message_rx, &event_tx);
         receive_messages(&mut sockets, &event_tx);
+        discover(&mut sockets, &mut probes, &event_tx);
 
         end = std::time::Instant::now();
 
@@ -70,16 +103,142 @@ pub fn start_worker_thread() -> NetworkResource {
 
 fn handle_instructions(
     sockets: &mut TrackedSockets,
+    probes: &mut Vec<Prober>,
     instruction_rx: &Receiver<WorkerInstructions>,
+    event_tx: &Sender<NetworkEvent>,
 ) -> bool {
     while let Ok(instruction) = instruction_rx.try_recv() {
         match instruction {
-            WorkerInstructions::AddSocket(handle, socket) => {
-                sockets.add_socket(handle, socket);
-            } // future work: allow manual closing of sockets
-            // WorkerInstructions::CloseSocket(handle) => {
-            //     sockets.close_socket(handle);
-            // }
+            WorkerInstructions::AddSocket(tracked) => {
+                sockets.add_socket(*tracked);
+            }
+            WorkerInstructions::Reconfigure(handle, config) => {
+                let limits = PayloadLimits::from_config(&config);
+                let result = sockets
+                    .get_mut(handle)
+                    .and_then(|tracked| tracked.reconfigure(config));
+
+                match result {
+                    Ok(()) => event_tx
+                        .send(NetworkEvent::Reconfigured(handle, limits))
+                        .expect(SEND_EXPECT),
+                    Err(e) => {
+                        event_tx
+                            .send(NetworkEvent::SocketError(handle, e))
+                            .expect(SEND_EXPECT);
+
+                        // a socket that couldn't get its address back is no use to anyone
+                        let stranded = sockets.get_mut(handle).is_ok_and(|tracked| {
+                            tracked.endpoints.iter().any(Endpoint::is_stranded)
+                        });
+                        if stranded {
+                            sockets.close_socket(handle);
+                            event_tx
+                                .send(NetworkEvent::SocketClosed(handle))
+                                .expect(SEND_EXPECT);
+                        }
+                    }
+                }
+            }
+            WorkerInstructions::RetryConnection(hello, policy) => {
+                match sockets.get_mut(hello.socket_handle) {
+                    Ok(tracked) => {
+                        tracked.state.reconnect.add(hello.clone(), policy);
+                        tracked.state.queue.push(hello);
+                    }
+                    Err(error) => send_failed(event_tx, &hello, error),
+                }
+            }
+            WorkerInstructions::Advertise(handle, advertiser) => {
+                if let Ok(tracked) = sockets.get_mut(handle) {
+                    tracked.advertiser = Some(advertiser);
+                }
+            }
+            WorkerInstructions::AdvertisedInfo(handle, info) => {
+                if let Ok(tracked) = sockets.get_mut(handle) {
+                    if let Some(advertiser) = &mut tracked.advertiser {
+                        advertiser.set_info(info);
+                    }
+                }
+            }
+            WorkerInstructions::StopAdvertising(handle) => {
+                if let Ok(tracked) = sockets.get_mut(handle) {
+                    tracked.advertiser = None;
+                }
+            }
+            WorkerInstructions::Discover(prober) => probes.push(prober),
+            WorkerInstructions::ServeRendezvous(handle) => {
+                if let Ok(tracked) = sockets.get_mut(handle) {
+                    tracked.state.rendezvous = Some(Rendezvous::new());
+                }
+            }
+            WorkerInstructions::Punch {
+                socket,
+                rendezvous,
+                key,
+                timeout,
+            } => match sockets.get_mut(socket) {
+                Ok(tracked) => {
+                    tracked
+                        .state
+                        .puncher
+                        .start(rendezvous, key, timeout, Instant::now())
+                }
+                Err(error) => event_tx
+                    .send(NetworkEvent::SocketError(socket, error))
+                    .expect(SEND_EXPECT),
+            },
+            WorkerInstructions::ServeRelay(handle) => {
+                if let Ok(tracked) = sockets.get_mut(handle) {
+                    tracked.state.relay = Some(RelayServer::new());
+                }
+            }
+            WorkerInstructions::Relay {
+                socket,
+                relay,
+                key,
+                timeout,
+            } => match sockets.get_mut(socket) {
+                Ok(tracked) => tracked
+                    .state
+                    .relayed
+                    .start(relay, key, timeout, Instant::now()),
+                Err(error) => event_tx
+                    .send(NetworkEvent::SocketError(socket, error))
+                    .expect(SEND_EXPECT),
+            },
+            WorkerInstructions::JoinMulticast(handle, group) => {
+                if let Ok(tracked) = sockets.get_mut(handle) {
+                    tracked.multicast.add(group);
+                }
+            }
+            WorkerInstructions::LeaveMulticast(handle, group) => {
+                if let Ok(tracked) = sockets.get_mut(handle) {
+                    tracked.multicast.leave(group);
+                }
+            }
+            WorkerInstructions::SendMulticast {
+                socket,
+                group,
+                message,
+            } => {
+                let result = sockets
+                    .get_mut(socket)
+                    .and_then(|tracked| tracked.multicast.send(group, &message));
+
+                if let Err(e) = result {
+                    event_tx
+                        .send(NetworkEvent::SocketError(socket, e))
+                        .expect(SEND_EXPECT);
+                }
+            }
+            WorkerInstructions::CloseSocket(handle) => {
+                sockets.close_socket(handle);
+
+                event_tx
+                    .send(NetworkEvent::SocketClosed(handle))
+                    .expect(SEND_EXPECT);
+            }
             WorkerInstructions::Terminate => return true,
         }
     }
@@ -88,8 +247,10 @@ fn handle_instructions(
 }
 
 fn poll_sockets(sockets: &mut TrackedSockets) {
-    for (_, socket) in sockets.iter_mut() {
-        socket.manual_poll(Instant::now());
+    for tracked in sockets.iter_mut() {
+        for endpoint in tracked.endpoints.iter_mut() {
+            endpoint.socket.manual_poll(Instant::now());
+        }
     }
 }
 
@@ -99,66 +260,1329 @@ fn send_messages(
     event_tx: &Sender<NetworkEvent>,
 ) {
     while let Ok(message) = message_rx.try_recv() {
-        let handle = message.socket_handle;
-
-        sockets
-            .get_socket_mut(handle)
-            .and_then(|socket| {
-                socket
-                    .send(Packet::reliable_unordered(
-                        message.destination,
-                        message.message.to_vec(),
-                    ))
-                    .map_err(|e| e.into())
-            })
-            .or_else(|err| event_tx.send(NetworkEvent::SendError(err)))
+        match sockets.get_mut(message.socket_handle) {
+            Ok(tracked) => tracked.state.queue.push(message),
+            Err(error) => send_failed(event_tx, &message, error),
+        }
+    }
+
+    let now = Instant::now();
+    for tracked in sockets.iter_mut() {
+        for (message, error) in tracked.send_queued(now) {
+            send_failed(event_tx, &message, error);
+        }
+    }
+}
+
+fn send_failed(event_tx: &Sender<NetworkEvent>, message: &Message, error: NetworkError) {
+    event_tx
+        .send(NetworkEvent::SendFailed {
+            id: message.id,
+            connection: Connection {
+                addr: message.destination,
+                socket: message.socket_handle,
+                multicast: None,
+            },
+            error,
+        })
+        // this expect() is OK, since our only way of communicating errors back to the callers through this event channel. If
+        // we can no longer push events back through this channel, it's time to panic.
+        .expect(SEND_EXPECT);
+}
+
+fn packet(addr: SocketAddr, delivery: NetworkDelivery, payload: Vec<u8>) -> Packet {
+    match delivery {
+        NetworkDelivery::UnreliableUnordered => Packet::unreliable(addr, payload),
+        NetworkDelivery::UnreliableSequenced(stream) => {
+            Packet::unreliable_sequenced(addr, payload, stream)
+        }
+        NetworkDelivery::ReliableUnordered => Packet::reliable_unordered(addr, payload),
+        NetworkDelivery::ReliableSequenced(stream) => {
+            Packet::reliable_sequenced(addr, payload, stream)
+        }
+        NetworkDelivery::ReliableOrdered(stream) => Packet::reliable_ordered(addr, payload, stream),
+    }
+}
+
+// The destination and stream a message is ordered or sequenced on, if its delivery has one
+fn ordered_stream(message: &Message) -> Option<(SocketAddr, NetworkDelivery)> {
+    match message.delivery {
+        NetworkDelivery::UnreliableUnordered | NetworkDelivery::ReliableUnordered => None,
+        delivery => Some((message.destination, delivery)),
+    }
+}
+
+// The delivery a packet was sent with, so a relay can forward it with the same one
+fn delivery_of(packet: &Packet) -> NetworkDelivery {
+    match (packet.delivery_guarantee(), packet.order_guarantee()) {
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::None) => {
+            NetworkDelivery::UnreliableUnordered
+        }
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(stream))
+        | (DeliveryGuarantee::Unreliable, OrderingGuarantee::Ordered(stream)) => {
+            NetworkDelivery::UnreliableSequenced(stream)
+        }
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::None) => {
+            NetworkDelivery::ReliableUnordered
+        }
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(stream)) => {
+            NetworkDelivery::ReliableSequenced(stream)
+        }
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::Ordered(stream)) => {
+            NetworkDelivery::ReliableOrdered(stream)
+        }
+    }
+}
+
+fn receive_messages(sockets: &mut TrackedSockets, event_tx: &Sender<NetworkEvent>) {
+    for tracked in sockets.iter_mut() {
+        let mut events = Vec::new();
+
+        for endpoint in tracked.endpoints.iter_mut() {
+            while let Some(event) = endpoint.socket.recv() {
+                tracked.state.handle_event(event, &mut events);
+            }
+        }
+        for (group, from, data) in tracked.multicast.receive() {
+            tracked
+                .state
+                .receive_multicast(group, from, data, &mut events);
+        }
+
+        tracked.state.send_keepalives(Instant::now());
+        tracked.state.punch_holes(Instant::now(), &mut events);
+        tracked.state.register_relays(Instant::now(), &mut events);
+        tracked.flush_outbox();
+        tracked.state.expire_deliveries(Instant::now(), &mut events);
+        tracked.state.reassembler.expire(Instant::now());
+
+        for e in events {
             // this expect() is OK, since our only way of communicating errors back to the callers through this event channel. If
             // we can no longer push events back through this channel, it's time to panic.
-            .expect(SEND_EXPECT);
+            event_tx.send(e).expect(SEND_EXPECT);
+        }
     }
 }
 
-fn receive_messages(sockets: &mut TrackedSockets, event_tx: &Sender<NetworkEvent>) {
-    for (socket_handle, socket) in sockets.iter_mut() {
-        while let Some(event) = socket.recv() {
-            let e = match event {
-                SocketEvent::Connect(addr) => Some(NetworkEvent::Connected(Connection {
-                    addr,
-                    socket: *socket_handle,
-                })),
-                SocketEvent::Timeout(addr) => Some(NetworkEvent::Disconnected(Connection {
-                    addr,
-                    socket: *socket_handle,
-                })),
-                SocketEvent::Packet(packet) => Some(NetworkEvent::Message(
-                    Connection {
-                        addr: packet.addr(),
-                        socket: *socket_handle,
-                    },
-                    Bytes::copy_from_slice(packet.payload()),
-                )),
-            };
+// Answers discovery probes for the sockets that are advertised, and reports the servers that
+// answer our own probes
+fn discover(
+    sockets: &mut TrackedSockets,
+    probes: &mut Vec<Prober>,
+    event_tx: &Sender<NetworkEvent>,
+) {
+    let mut events = Vec::new();
+
+    for tracked in sockets.iter_mut() {
+        let game_port = tracked.endpoints[0].local_addr.port();
 
-            if let Some(e) = e {
-                // this expect() is OK, since our only way of communicating errors back to the callers through this event channel. If
-                // we can no longer push events back through this channel, it's time to panic.
-                event_tx.send(e).expect(SEND_EXPECT);
+        if let Some(advertiser) = &mut tracked.advertiser {
+            if let Err(e) = advertiser.answer_probes(game_port) {
+                events.push(NetworkEvent::SocketError(tracked.state.handle, e));
             }
         }
     }
+
+    let now = Instant::now();
+    for prober in probes.iter_mut() {
+        prober.receive_answers(&mut events);
+    }
+    probes.retain(|p| !p.is_expired(now));
+
+    for e in events {
+        event_tx.send(e).expect(SEND_EXPECT);
+    }
 }
 
 struct TrackedSockets {
-    sockets: Vec<(SocketHandle, Socket)>,
+    sockets: Vec<TrackedSocket>,
+}
+
+pub(crate) struct TrackedSocket {
+    // one laminar socket per address family, so a dual-stack socket has two endpoints
+    endpoints: Vec<Endpoint>,
+    state: SocketState,
+    advertiser: Option<Advertiser>,
+    multicast: Multicast,
+}
+
+struct Endpoint {
+    local_addr: SocketAddr,
+    socket: Socket,
+}
+
+struct SocketState {
+    handle: SocketHandle,
+    config: LaminarConfig,
+    limiter: RateLimiter,
+    send_budget: RateLimiter,
+    // messages waiting for the send budget, in the order they were sent
+    queue: Vec<Message>,
+    reassembler: Reassembler,
+    deliveries: DeliveryTracker,
+    compression_stats: Arc<SharedCompressionStats>,
+    // whether the socket challenges connections, which the `NetworkResource` can read
+    challenged: Arc<AtomicBool>,
+    challenge: Option<Challenger>,
+    encryption: Option<Sessions>,
+    tokens: Option<TokenVerifier>,
+    migration: Option<SessionIds>,
+    reconnect: Reconnector,
+    rendezvous: Option<Rendezvous>,
+    puncher: Puncher,
+    relay: Option<RelayServer>,
+    relayed: RelayClient,
+    // the peers we've pinged and when, until they've sent us something
+    pinged: HashMap<SocketAddr, Instant>,
+    heard: HashSet<SocketAddr>,
+    // packets the worker sends on its own behalf, such as acks
+    outbox: Vec<Packet>,
+}
+
+impl TrackedSocket {
+    pub fn bind<A: ToSocketAddrs>(
+        handle: SocketHandle,
+        addr: A,
+        config: LaminarConfig,
+    ) -> Result<Self, NetworkError> {
+        let endpoints = vec![Endpoint::bind(addr, &config)?];
+        let state = SocketState::new(handle, config, &endpoints);
+
+        Ok(TrackedSocket {
+            endpoints,
+            state,
+            advertiser: None,
+            multicast: Multicast::new(),
+        })
+    }
+
+    pub fn bind_dual_stack(
+        handle: SocketHandle,
+        v4: SocketAddr,
+        v6: SocketAddr,
+        config: LaminarConfig,
+    ) -> Result<Self, NetworkError> {
+        let endpoints = vec![Endpoint::bind(v4, &config)?, Endpoint::bind(v6, &config)?];
+        let state = SocketState::new(handle, config, &endpoints);
+
+        Ok(TrackedSocket {
+            endpoints,
+            state,
+            advertiser: None,
+            multicast: Multicast::new(),
+        })
+    }
+
+    fn handle(&self) -> SocketHandle {
+        self.state.handle
+    }
+
+    pub fn compression_stats(&self) -> Arc<SharedCompressionStats> {
+        self.state.compression_stats.clone()
+    }
+
+    pub fn challenged(&self) -> Arc<AtomicBool> {
+        self.state.challenged.clone()
+    }
+
+    fn socket_for(&mut self, addr: SocketAddr) -> Result<&mut Socket, NetworkError> {
+        // a single socket is left to laminar, since an IPv6 socket may still reach IPv4-mapped
+        // addresses
+        if self.endpoints.len() == 1 {
+            return Ok(&mut self.endpoints[0].socket);
+        }
+
+        self.endpoints
+            .iter_mut()
+            .find(|e| e.local_addr.is_ipv6() == addr.is_ipv6())
+            .map(|e| &mut e.socket)
+            .ok_or(NetworkError::UnsupportedAddress(addr))
+    }
+
+    // Sends as much of the queue as the send budget allows, returning the messages that failed
+    fn send_queued(&mut self, now: Instant) -> Vec<(Message, NetworkError)> {
+        let mut queue = std::mem::take(&mut self.state.queue);
+        let mut failed = Vec::new();
+
+        // an introduction still waiting on its handshake isn't queued twice
+        for hello in self.state.reconnect.due(now) {
+            if !queue.iter().any(|m| m.id == hello.id) {
+                queue.push(hello);
+            }
+        }
+
+        // messages for a connection that has moved follow it to its new address
+        if let Some(sessions) = &self.state.migration {
+            for message in queue.iter_mut() {
+                message.destination = sessions.destination(message.destination);
+            }
+        }
+
+        // the sort is stable, so messages of the same priority and reliability keep their order
+        queue.sort_by_key(|m| (Reverse(m.priority), !m.delivery.is_reliable()));
+
+        let mut ready = Vec::new();
+        // once a message on an ordered or sequenced stream is held back, everything after it on
+        // the stream is too, so the stream can't overtake it
+        let mut held_streams: HashSet<(SocketAddr, NetworkDelivery)> = HashSet::new();
+        let mut deferred: HashMap<SocketAddr, usize> = HashMap::new();
+
+        for message in queue {
+            // messages wait for the handshake, so they don't count against the budget yet
+            if !self.state.ready_to_send(message.destination, now) {
+                self.state.queue.push(message);
+                continue;
+            }
+            if matches!(message.delivery, NetworkDelivery::ReliableOrdered(_))
+                && !self.state.heard_from(message.destination, now)
+            {
+                self.state.queue.push(message);
+                continue;
+            }
+
+            let stream = ordered_stream(&message);
+            let held = stream.is_some_and(|s| held_streams.contains(&s));
+            let verdict = match held {
+                true => Verdict::Drop,
+                false => {
+                    self.state
+                        .send_budget
+                        .check(message.destination, message.message.len(), now)
+                }
+            };
+            let droppable =
+                message.priority == SendPriority::Low && !message.delivery.is_reliable();
+
+            match verdict {
+                Verdict::Allow => ready.push(message),
+                _ if droppable => failed.push((message, NetworkError::SendBudgetExceeded)),
+                _ => {
+                    let count = deferred.entry(message.destination).or_insert(0);
+                    if *count == MAX_DEFERRED_PER_CONNECTION {
+                        failed.push((message, NetworkError::SendBudgetExceeded));
+                        continue;
+                    }
+
+                    *count += 1;
+                    if let Some(stream) = stream {
+                        held_streams.insert(stream);
+                    }
+                    self.state.queue.push(message);
+                }
+            }
+        }
+
+        match self.state.config.coalesce_messages {
+            true => self.send_batched(ready, &mut failed),
+            false => {
+                for message in ready {
+                    if let Err(e) = self.send(&message) {
+                        failed.push((message, e));
+                    }
+                }
+            }
+        }
+
+        failed
+    }
+
+    fn send(&mut self, message: &Message) -> Result<(), NetworkError> {
+        let payload = self
+            .state
+            .encode(message.destination, message.message.to_vec())?;
+
+        self.transmit(packet(message.destination, message.delivery, payload))?;
+        self.state.track(message);
+
+        Ok(())
+    }
+
+    // Packs messages with the same destination and delivery into as few packets as possible. A
+    // message only joins the latest batch for its destination and delivery, so messages on the
+    // same stream keep their order.
+    fn send_batched(&mut self, messages: Vec<Message>, failed: &mut Vec<(Message, NetworkError)>) {
+        let max = PayloadLimits::from_config(&self.state.config).unreliable + FRAME_HEADER_SIZE;
+
+        let mut batches: Vec<Vec<Message>> = Vec::new();
+        let mut latest: HashMap<(SocketAddr, NetworkDelivery), (usize, usize)> = HashMap::new();
+
+        for message in messages {
+            let key = (message.destination, message.delivery);
+            let entry_len = BATCH_ENTRY_HEADER_SIZE + message.message.len();
+
+            match latest.get_mut(&key) {
+                Some((idx, len)) if *len + entry_len <= max => {
+                    *len += entry_len;
+                    batches[*idx].push(message);
+                }
+                _ => {
+                    latest.insert(key, (batches.len(), FRAME_HEADER_SIZE + entry_len));
+                    batches.push(vec![message]);
+                }
+            }
+        }
+
+        for batch in batches {
+            if batch.len() == 1 {
+                let message = batch.into_iter().next().unwrap();
+                if let Err(e) = self.send(&message) {
+                    failed.push((message, e));
+                }
+                continue;
+            }
+
+            let frames: Vec<&Bytes> = batch.iter().map(|m| &m.message).collect();
+            let (destination, delivery) = (batch[0].destination, batch[0].delivery);
+
+            let result = self
+                .state
+                .encode(destination, protocol::encode_batch(&frames).to_vec())
+                .and_then(|payload| self.transmit(packet(destination, delivery, payload)));
+
+            match result {
+                Ok(()) => {
+                    for message in &batch {
+                        self.state.track(message);
+                    }
+                }
+                // the messages are sent one at a time instead, so each gets its own error
+                Err(_) => {
+                    for message in batch {
+                        if let Err(e) = self.send(&message) {
+                            failed.push((message, e));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn flush_outbox(&mut self) {
+        let outbox = std::mem::take(&mut self.state.outbox);
+
+        for packet in outbox {
+            // a failed ack is indistinguishable from a lost one, so the sender will find out
+            let _ = self.transmit(packet);
+        }
+    }
+
+    fn transmit(&mut self, packet: Packet) -> Result<(), NetworkError> {
+        let packet = self.state.via_relay(packet);
+        self.socket_for(packet.addr())?.send(packet)?;
+
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, config: LaminarConfig) -> Result<(), NetworkError> {
+        if self.state.config.requires_rebind(&config) {
+            let previous = &self.state.config;
+
+            for idx in 0..self.endpoints.len() {
+                if let Err(e) = self.endpoints[idx].rebind(&config, previous) {
+                    // a dual-stack socket shouldn't end up with a different config on each
+                    // address, so the endpoints that were already rebound go back to the old one
+                    for endpoint in self.endpoints[..idx].iter_mut() {
+                        let _ = endpoint.rebind(previous, &config);
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
+
+        self.state.limiter =
+            RateLimiter::new(config.connection_rate_limit, config.socket_rate_limit);
+        self.state.send_budget =
+            RateLimiter::new(config.connection_send_budget, config.socket_send_budget);
+        self.state
+            .reassembler
+            .set_max_transfer_size(config.max_transfer_size);
+        self.state.deliveries.set_timeout(config.delivery_timeout);
+
+        // every connection has to handshake again under the new settings
+        if config.challenge_connections != self.state.config.challenge_connections {
+            self.state.challenge = challenger(&config);
+            self.state
+                .challenged
+                .store(config.challenge_connections, Ordering::Relaxed);
+        }
+        if config.encryption != self.state.config.encryption {
+            self.state.encryption = config.encryption.as_ref().map(Sessions::new);
+        }
+        if config.connect_token_key != self.state.config.connect_token_key {
+            self.state.tokens = token_verifier(&config, &self.endpoints);
+        }
+        if config.migrate_connections != self.state.config.migrate_connections {
+            self.state.migration = session_ids(&config);
+        }
+
+        self.state.config = config;
+
+        Ok(())
+    }
+}
+
+fn challenger(config: &LaminarConfig) -> Option<Challenger> {
+    match config.challenge_connections {
+        true => Some(Challenger::new()),
+        false => None,
+    }
+}
+
+fn session_ids(config: &LaminarConfig) -> Option<SessionIds> {
+    match config.migrate_connections {
+        true => Some(SessionIds::new()),
+        false => None,
+    }
+}
+
+fn token_verifier(config: &LaminarConfig, endpoints: &[Endpoint]) -> Option<TokenVerifier> {
+    let local_addrs = endpoints.iter().map(|e| e.local_addr).collect();
+
+    config
+        .connect_token_key
+        .map(|key| TokenVerifier::new(key, local_addrs))
+}
+
+impl Endpoint {
+    fn bind<A: ToSocketAddrs>(addr: A, config: &LaminarConfig) -> Result<Self, NetworkError> {
+        let socket = Socket::bind_with_config(addr, config.clone().into())?;
+        let local_addr = socket.local_addr()?;
+
+        Ok(Endpoint { local_addr, socket })
+    }
+
+    fn rebind(
+        &mut self,
+        config: &LaminarConfig,
+        previous: &LaminarConfig,
+    ) -> Result<(), NetworkError> {
+        let addr = self.local_addr;
+
+        // the old socket has to be closed before the address can be bound again, so we briefly
+        // park on an ephemeral port. If the address can't be bound again, not even with the
+        // previous config, the endpoint is left parked and `is_stranded`.
+        let parked =
+            Socket::bind_with_config(SocketAddr::new(addr.ip(), 0), previous.clone().into())?;
+        drop(std::mem::replace(&mut self.socket, parked));
+
+        match Socket::bind_with_config(addr, config.clone().into()) {
+            Ok(socket) => {
+                self.socket = socket;
+                Ok(())
+            }
+            Err(e) => {
+                if let Ok(socket) = Socket::bind_with_config(addr, previous.clone().into()) {
+                    self.socket = socket;
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    fn is_stranded(&self) -> bool {
+        self.socket
+            .local_addr()
+            .map_or(true, |addr| addr != self.local_addr)
+    }
+}
+
+impl SocketState {
+    fn new(handle: SocketHandle, config: LaminarConfig, endpoints: &[Endpoint]) -> Self {
+        SocketState {
+            handle,
+            limiter: RateLimiter::new(config.connection_rate_limit, config.socket_rate_limit),
+            send_budget: RateLimiter::new(config.connection_send_budget, config.socket_send_budget),
+            queue: Vec::new(),
+            reassembler: Reassembler::new(config.max_transfer_size),
+            deliveries: DeliveryTracker::new(config.delivery_timeout),
+            compression_stats: Arc::new(SharedCompressionStats::default()),
+            challenged: Arc::new(AtomicBool::new(config.challenge_connections)),
+            challenge: challenger(&config),
+            encryption: config.encryption.as_ref().map(Sessions::new),
+            tokens: token_verifier(&config, endpoints),
+            migration: session_ids(&config),
+            reconnect: Reconnector::new(),
+            rendezvous: None,
+            puncher: Puncher::new(),
+            relay: None,
+            relayed: RelayClient::new(),
+            pinged: HashMap::new(),
+            heard: HashSet::new(),
+            outbox: Vec::new(),
+            config,
+        }
+    }
+
+    fn handle_event(&mut self, event: SocketEvent, events: &mut Vec<NetworkEvent>) {
+        match event {
+            // connections with a handshake of our own are reported once it completes
+            SocketEvent::Connect(_) if self.has_handshake() => {}
+            SocketEvent::Connect(addr) => {
+                events.push(NetworkEvent::Connected(self.connection(addr), None))
+            }
+            SocketEvent::Timeout(addr) => {
+                // the peers reached through a relay go with it, and a relay tells the peers of
+                // one that's gone
+                for peer in self.relayed.remove_relay(addr) {
+                    self.handle_event(SocketEvent::Timeout(peer), events);
+                }
+                if let Some(relay) = &mut self.relay {
+                    for peer in relay.remove_connection(addr) {
+                        let closed = protocol::encode_relay_closed(addr).to_vec();
+                        self.outbox.push(Packet::reliable_unordered(peer, closed));
+                    }
+                }
+
+                // laminar forgets its ordered streams with the address either way
+                self.pinged.remove(&addr);
+                self.heard.remove(&addr);
+
+                // only laminar's record of the address timed out, since its connection moved
+                if let Some(sessions) = &mut self.migration {
+                    if !sessions.remove_connection(addr) {
+                        return;
+                    }
+                }
+
+                let connection = self.connection(addr);
+                let outcome = self.reconnect.timed_out(addr, Instant::now());
+                let retrying = matches!(outcome, Some(Outcome::Retrying(_)));
+
+                self.limiter.remove_connection(addr);
+                self.send_budget.remove_connection(addr);
+                self.reassembler.remove_connection(addr);
+                if let Some(tokens) = &mut self.tokens {
+                    tokens.remove_connection(addr);
+                }
+
+                // messages still waiting on a handshake with the peer are never going out, unless
+                // we're about to try connecting again
+                if (self.challenge.is_some() || self.encryption.is_some()) && !retrying {
+                    let verified = self.challenge.as_ref().is_none_or(|c| c.is_verified(addr));
+                    let (failed, queue): (Vec<Message>, Vec<Message>) =
+                        std::mem::take(&mut self.queue)
+                            .into_iter()
+                            .partition(|m: &Message| m.destination == addr);
+                    self.queue = queue;
+
+                    for message in failed {
+                        let error = match verified {
+                            true => NetworkError::NoSession(addr),
+                            false => NetworkError::ChallengeFailed(addr),
+                        };
+
+                        events.push(NetworkEvent::SendFailed {
+                            id: message.id,
+                            connection,
+                            error,
+                        });
+                    }
+                }
+                if let Some(challenge) = &mut self.challenge {
+                    challenge.remove_connection(addr);
+                }
+                if let Some(sessions) = &mut self.encryption {
+                    sessions.remove_connection(addr);
+                }
+
+                // nothing still waiting on an ack from a dead connection is going to get one
+                for id in self.deliveries.remove_connection(addr) {
+                    events.push(NetworkEvent::Lost { id, connection });
+                }
+
+                match outcome {
+                    Some(Outcome::Retrying(attempt)) => events.push(NetworkEvent::Reconnecting {
+                        connection,
+                        attempt,
+                    }),
+                    Some(Outcome::GaveUp(hello)) => {
+                        events.push(NetworkEvent::SendFailed {
+                            id: hello.id,
+                            connection,
+                            error: NetworkError::ConnectFailed(addr),
+                        });
+                        events.push(NetworkEvent::Disconnected(connection));
+                    }
+                    None => events.push(NetworkEvent::Disconnected(connection)),
+                }
+            }
+            SocketEvent::Packet(packet) => {
+                let connection = self.connection(packet.addr());
+
+                if self.receive_punch(connection, packet.payload(), events)
+                    || self.receive_relay(connection, packet.payload(), events)
+                {
+                    return;
+                }
+
+                let payload = match self.receive_session(connection, packet.payload(), events) {
+                    Some(payload) => payload,
+                    None => return,
+                };
+
+                // nothing is kept for an address until it has answered a challenge, so this comes
+                // before the rate limiter
+                if !self.receive_challenge(connection, payload, events) {
+                    return;
+                }
+
+                let verdict = self
+                    .limiter
+                    .check(packet.addr(), payload.len(), Instant::now());
+
+                match verdict {
+                    Verdict::Allow => {
+                        self.reconnect.heard_from(connection.addr);
+                        if self.pinged.remove(&connection.addr).is_some() {
+                            self.heard.insert(connection.addr);
+                        }
+                        self.receive_packet(connection, payload, events)
+                    }
+                    Verdict::Drop => {}
+                    Verdict::DropAndReport => events.push(NetworkEvent::RateLimited(connection)),
+                }
+            }
+        }
+    }
+
+    fn expire_deliveries(&mut self, now: Instant, events: &mut Vec<NetworkEvent>) {
+        for (addr, id) in self.deliveries.expire(now) {
+            let connection = self.connection(addr);
+            events.push(NetworkEvent::Lost { id, connection });
+        }
+    }
+
+    fn has_handshake(&self) -> bool {
+        self.challenge.is_some()
+            || self.encryption.is_some()
+            || self.tokens.is_some()
+            || self.migration.is_some()
+    }
+
+    // Tags the connections that haven't sent anything for a while, so the peer hears from them
+    // if their address changes
+    fn send_keepalives(&mut self, now: Instant) {
+        let quiet = match &self.migration {
+            Some(sessions) => sessions.quiet(now),
+            None => return,
+        };
+
+        for addr in quiet {
+            if let Ok(keepalive) = self.encode(addr, Vec::new()) {
+                self.outbox.push(Packet::unreliable(addr, keepalive));
+            }
+        }
+    }
+
+    // Sends the registrations and probes for NAT punch-through that are due
+    fn punch_holes(&mut self, now: Instant, events: &mut Vec<NetworkEvent>) {
+        for punch in self.puncher.poll(now) {
+            match punch {
+                // the rendezvous server only introduces peers that have answered its challenge
+                Punch::Register(rendezvous, packet) => {
+                    if self.challenged(rendezvous, now) {
+                        self.outbox.push(Packet::unreliable(rendezvous, packet));
+                    }
+                }
+                Punch::Probe(peer, packet) => self.outbox.push(Packet::unreliable(peer, packet)),
+                Punch::Failed { rendezvous, peer } => events.push(NetworkEvent::PunchFailed {
+                    socket: self.handle,
+                    rendezvous,
+                    peer,
+                }),
+            }
+        }
+    }
+
+    // Multicast packets can't go through the handshakes, since a group has no one to handshake
+    // with, so they're reported as messages from the sender tagged with the group, without a
+    // connection of their own. They still count against the socket's rate limit.
+    fn receive_multicast(
+        &mut self,
+        group: SocketAddr,
+        from: SocketAddr,
+        data: Bytes,
+        events: &mut Vec<NetworkEvent>,
+    ) {
+        if self.limiter.check(from, data.len(), Instant::now()) == Verdict::Allow {
+            let connection = Connection {
+                multicast: Some(group),
+                ..self.connection(from)
+            };
+            events.push(NetworkEvent::Message(connection, data));
+        }
+    }
+
+    // Wraps a packet for a relayed peer, so it's sent to the relay instead. The relay forwards it
+    // with the same delivery, so it's as reliable and ordered end to end as if it were sent
+    // directly.
+    fn via_relay(&self, packet: Packet) -> Packet {
+        match self.relayed.relay_for(packet.addr()) {
+            Some(relay) => {
+                let delivery = delivery_of(&packet);
+                let relayed = protocol::encode_relayed(packet.addr(), delivery, packet.payload());
+                self::packet(relay, delivery, relayed.to_vec())
+            }
+            None => packet,
+        }
+    }
+
+    // Sends the relay registrations that are due
+    fn register_relays(&mut self, now: Instant, events: &mut Vec<NetworkEvent>) {
+        if let Some(relay) = &mut self.relay {
+            for (addr, peer) in relay.expire(now) {
+                let closed = protocol::encode_relay_closed(peer).to_vec();
+                self.outbox.push(Packet::reliable_unordered(addr, closed));
+            }
+        }
+
+        for polled in self.relayed.poll(now) {
+            match polled {
+                // the relay only pairs peers that have answered its challenge
+                RelayPoll::Register(relay, packet) => {
+                    if self.challenged(relay, now) {
+                        self.outbox.push(Packet::unreliable(relay, packet));
+                    }
+                }
+                RelayPoll::Failed(relay) => events.push(NetworkEvent::RelayFailed {
+                    socket: self.handle,
+                    relay,
+                }),
+            }
+        }
+    }
+
+    // Handles the rendezvous and punch-through frames, which are sent in the clear before there's
+    // a connection to handshake with. Returns true if the packet was one of them.
+    fn receive_punch(
+        &mut self,
+        connection: Connection,
+        payload: &[u8],
+        events: &mut Vec<NetworkEvent>,
+    ) -> bool {
+        let addr = connection.addr;
+
+        match protocol::decode(payload) {
+            Some(Frame::RendezvousRegister(key)) => {
+                // only peers that have proven they can receive at their address are introduced,
+                // so spoofed registrations can't fill the rendezvous or hijack an introduction
+                let verified = self.challenge.as_ref().is_some_and(|c| c.is_verified(addr));
+                let rendezvous = match &mut self.rendezvous {
+                    Some(rendezvous) if verified => rendezvous,
+                    _ => return true,
+                };
+
+                let mut hashed = [0; protocol::PUNCH_KEY_SIZE];
+                hashed.copy_from_slice(key);
+                if let Some(peer) = rendezvous.register(addr, hashed, Instant::now()) {
+                    let to_peer = protocol::encode_introduce(addr).to_vec();
+                    let to_addr = protocol::encode_introduce(peer).to_vec();
+                    self.outbox.push(Packet::unreliable(peer, to_peer));
+                    self.outbox.push(Packet::unreliable(addr, to_addr));
+                }
+            }
+            Some(Frame::Introduce(peer)) => self.puncher.introduced(addr, peer),
+            Some(Frame::Punch(key)) => {
+                if let Some((ack, reached)) = self.puncher.probed(addr, key) {
+                    self.outbox.push(Packet::unreliable(addr, ack));
+                    if reached {
+                        events.push(NetworkEvent::PunchSucceeded(connection));
+                    }
+                }
+            }
+            Some(Frame::PunchAck(key)) => {
+                if self.puncher.acked(addr, key) {
+                    events.push(NetworkEvent::PunchSucceeded(connection));
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    // Handles the relay frames, which are sent in the clear like the punch-through ones. A packet
+    // relayed from a peer is handled as if it had arrived from the peer itself, so the peer looks
+    // like any other connection. Returns true if the packet was one of them.
+    fn receive_relay(
+        &mut self,
+        connection: Connection,
+        payload: &[u8],
+        events: &mut Vec<NetworkEvent>,
+    ) -> bool {
+        let addr = connection.addr;
+        let now = Instant::now();
+
+        match protocol::decode(payload) {
+            Some(Frame::RelayRegister(key)) => {
+                // only peers that have proven they can receive at their address are paired, so a
+                // spoofed registration can't point the relay at someone else
+                let verified = self.challenge.as_ref().is_some_and(|c| c.is_verified(addr));
+                let relay = match &mut self.relay {
+                    Some(relay) if verified => relay,
+                    _ => return true,
+                };
+
+                let mut hashed = [0; PUNCH_KEY_SIZE];
+                hashed.copy_from_slice(key);
+                if let Some(peer) = relay.register(addr, hashed, now) {
+                    let to_peer = protocol::encode_relay_paired(addr, key).to_vec();
+                    let to_addr = protocol::encode_relay_paired(peer, key).to_vec();
+                    self.outbox.push(Packet::unreliable(peer, to_peer));
+                    self.outbox.push(Packet::unreliable(addr, to_addr));
+                }
+            }
+            Some(Frame::RelayPaired(peer, key)) => {
+                if self.relayed.paired(addr, peer, key) {
+                    // like any other connection, one with a handshake is reported once it
+                    // completes, so it's started right away
+                    match self.has_handshake() {
+                        true => {
+                            self.ready_to_send(peer, now);
+                        }
+                        false => events.push(NetworkEvent::Connected(self.connection(peer), None)),
+                    }
+                }
+            }
+            Some(Frame::RelayClosed(peer)) => {
+                if self.relayed.remove_peer(addr, peer) {
+                    self.handle_event(SocketEvent::Timeout(peer), events);
+                }
+            }
+            Some(Frame::Relayed(peer, delivery, inner)) => {
+                let forward = self
+                    .relay
+                    .as_mut()
+                    .is_some_and(|relay| relay.may_forward(addr, peer, now));
+
+                if forward {
+                    // forwarded packets count against the sender's rate limit
+                    match self.limiter.check(addr, payload.len(), now) {
+                        Verdict::Allow => {
+                            let relayed = protocol::encode_relayed(addr, delivery, inner).to_vec();
+                            self.outbox.push(packet(peer, delivery, relayed));
+                        }
+                        Verdict::Drop => {}
+                        Verdict::DropAndReport => {
+                            events.push(NetworkEvent::RateLimited(connection))
+                        }
+                    }
+                } else if self.relayed.relay_for(peer) == Some(addr) {
+                    let unwrapped = packet(peer, delivery, inner.to_vec());
+                    self.handle_event(SocketEvent::Packet(unwrapped), events);
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    // Moves everything the socket knows about a connection to its new address
+    fn migrate(&mut self, old: SocketAddr, new: SocketAddr) {
+        if let Some(sessions) = &mut self.migration {
+            sessions.migrate(old, new);
+        }
+
+        // the rate limits start over, and so do any large transfers
+        self.limiter.remove_connection(old);
+        self.send_budget.remove_connection(old);
+        self.reassembler.remove_connection(old);
+        self.deliveries.migrate(old, new);
+
+        if let Some(challenge) = &mut self.challenge {
+            challenge.migrate(old, new);
+        }
+        if let Some(sessions) = &mut self.encryption {
+            sessions.migrate(old, new);
+        }
+        if let Some(tokens) = &mut self.tokens {
+            tokens.migrate(old, new);
+        }
+
+        for message in self.queue.iter_mut().filter(|m| m.destination == old) {
+            message.destination = new;
+        }
+    }
+
+    // Starts a handshake with the address if it needs one
+    fn ready_to_send(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if !self.challenged(addr, now) {
+            return false;
+        }
+
+        let sessions = match &mut self.encryption {
+            Some(sessions) => sessions,
+            None => return true,
+        };
+
+        if sessions.established(addr) {
+            return true;
+        }
+
+        if let Some(init) = sessions.start_handshake(addr) {
+            self.outbox.push(Packet::reliable_unordered(addr, init));
+        }
+
+        false
+    }
+
+    // Laminar only keeps the place in an ordered stream for an address it has sent to, so an
+    // ordered message that arrives before the peer has sent us anything is delivered, but the rest
+    // of the stream then waits for it again forever. Ordered messages wait until we've heard from
+    // the peer, which is pinged until then. A challenge or a handshake has already heard from it.
+    fn heard_from(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if self.challenge.is_some() || self.encryption.is_some() || self.heard.contains(&addr) {
+            return true;
+        }
+
+        let due = self
+            .pinged
+            .get(&addr)
+            .is_none_or(|at| now.saturating_duration_since(*at) >= PING_INTERVAL);
+        if due {
+            if let Ok(ping) = self.encode(addr, protocol::encode_ping().to_vec()) {
+                self.outbox.push(Packet::unreliable(addr, ping));
+            }
+            self.pinged.insert(addr, now);
+        }
+        false
+    }
+
+    // Asks the address for a challenge until it has accepted our answer, if the socket challenges
+    // connections
+    fn challenged(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        let challenger = match &mut self.challenge {
+            Some(challenger) => challenger,
+            None => return true,
+        };
+
+        if challenger.is_verified(addr) {
+            return true;
+        }
+
+        if let Some(request) = challenger.request(addr, now) {
+            self.outbox.push(Packet::unreliable(addr, request));
+        }
+        false
+    }
+
+    // Compresses, encrypts, and then tags a frame to send
+    fn encode(&mut self, addr: SocketAddr, frame: Vec<u8>) -> Result<Vec<u8>, NetworkError> {
+        let payload = compression::compress(
+            self.config.compression,
+            self.config.compression_threshold,
+            frame,
+            &self.compression_stats,
+        );
+
+        let payload = match &mut self.encryption {
+            Some(sessions) => sessions
+                .seal(addr, &payload)
+                .ok_or(NetworkError::NoSession(addr))?,
+            None => payload,
+        };
+
+        match &mut self.migration {
+            Some(sessions) => {
+                let id = sessions.tag(addr, Instant::now());
+                Ok(protocol::encode_session(id, &payload).to_vec())
+            }
+            None => Ok(payload),
+        }
+    }
+
+    fn track(&mut self, message: &Message) {
+        if message.track_delivery {
+            self.deliveries.sent(
+                message.destination,
+                message.id,
+                message.delivery.is_reliable(),
+                Instant::now(),
+            );
+        }
+    }
+
+    // Strips the session id from a packet, and moves its connection if it arrived from a new
+    // address. Returns the rest of the packet, unless there's nothing more to do with it.
+    fn receive_session<'a>(
+        &mut self,
+        connection: Connection,
+        payload: &'a [u8],
+        events: &mut Vec<NetworkEvent>,
+    ) -> Option<&'a [u8]> {
+        let addr = connection.addr;
+        let sessions = match &mut self.migration {
+            Some(sessions) => sessions,
+            None => return Some(payload),
+        };
+
+        // challenges and handshakes aren't tagged
+        let (id, packet) = match protocol::decode(payload) {
+            Some(Frame::Session(id, packet)) => (id, packet),
+            _ => return Some(payload),
+        };
+
+        // an address that hasn't answered a challenge can only move an existing session
+        let verified = self.challenge.as_ref().is_none_or(|c| c.is_verified(addr));
+        if !verified && !sessions.has_session(id) {
+            return None;
+        }
+
+        match sessions.receive(addr, id) {
+            Arrival::New => {
+                if self.challenge.is_none() && self.encryption.is_none() && self.tokens.is_none() {
+                    events.push(NetworkEvent::Connected(connection, None));
+                }
+            }
+            Arrival::Known => {}
+            Arrival::Moved(old) => {
+                // anyone who has seen the session id could claim it, so an encrypted connection
+                // only moves for a packet that was sealed by its peer
+                let authentic = self
+                    .encryption
+                    .as_ref()
+                    .is_none_or(|sessions| sessions.authentic(old, packet));
+                if !authentic {
+                    return None;
+                }
+
+                self.migrate(old, addr);
+                events.push(NetworkEvent::Reconnected {
+                    old: self.connection(old),
+                    new: connection,
+                });
+            }
+            Arrival::Stale => return None,
+        }
+
+        // keepalives are empty
+        match packet.is_empty() {
+            true => None,
+            false => Some(packet),
+        }
+    }
+
+    // Handles the challenge frames, which are sent in the clear. Returns false if the packet
+    // shouldn't go any further, because it was part of a challenge or the sender hasn't answered
+    // one yet.
+    fn receive_challenge(
+        &mut self,
+        connection: Connection,
+        payload: &[u8],
+        events: &mut Vec<NetworkEvent>,
+    ) -> bool {
+        let addr = connection.addr;
+        let challenger = match &mut self.challenge {
+            Some(challenger) => challenger,
+            None => return true,
+        };
+
+        // the replies are never larger than what prompted them
+        let verified = match protocol::decode(payload) {
+            Some(Frame::ChallengeRequest) => {
+                let challenge = challenger.challenge(addr, SystemTime::now());
+                self.outbox.push(Packet::unreliable(addr, challenge));
+                false
+            }
+            Some(Frame::Challenge(cookie)) => {
+                if let Some(response) = challenger.answer(addr, cookie, Instant::now()) {
+                    self.outbox.push(Packet::unreliable(addr, response));
+                }
+                false
+            }
+            Some(Frame::ChallengeResponse(cookie)) => {
+                match challenger.verify(addr, cookie, SystemTime::now()) {
+                    Some(verified) => {
+                        let accepted = protocol::encode_challenge_accepted(cookie).to_vec();
+                        self.outbox.push(Packet::unreliable(addr, accepted));
+                        verified
+                    }
+                    None => false,
+                }
+            }
+            Some(Frame::ChallengeAccepted(cookie)) => challenger.accepted(addr, cookie),
+            _ => return challenger.is_verified(addr),
+        };
+
+        if verified && self.encryption.is_none() && self.tokens.is_none() {
+            events.push(NetworkEvent::Connected(connection, None));
+        }
+
+        false
+    }
+
+    fn receive_packet(
+        &mut self,
+        connection: Connection,
+        payload: &[u8],
+        events: &mut Vec<NetworkEvent>,
+    ) {
+        let payload = match &mut self.encryption {
+            Some(sessions) => {
+                let addr = connection.addr;
+
+                // handshakes are the only thing sent in the clear
+                if let Some(frame @ Frame::HandshakeInit(_))
+                | Some(frame @ Frame::HandshakeReply(..)) = protocol::decode(payload)
+                {
+                    let handshake = sessions.handshake(addr, frame);
+                    for reply in handshake.replies {
+                        self.outbox.push(Packet::reliable_unordered(addr, reply));
+                    }
+                    if handshake.established && self.tokens.is_none() {
+                        events.push(NetworkEvent::Connected(connection, None));
+                    }
+                    return;
+                }
+
+                // tampered, replayed and unencrypted packets are all dropped here
+                let opened = match sessions.open(addr, payload) {
+                    Some(opened) => opened,
+                    None => return,
+                };
+                if opened.established && self.tokens.is_none() {
+                    events.push(NetworkEvent::Connected(connection, None));
+                }
+
+                Cow::Owned(opened.plaintext)
+            }
+            None => Cow::Borrowed(payload),
+        };
+
+        // anything we can't decode didn't come from this crate, so it's dropped
+        let payload = match compression::decompress(&payload, self.config.max_packet_size) {
+            Some(payload) => payload,
+            None => return,
+        };
+
+        match protocol::decode(&payload) {
+            Some(Frame::Batch(frames)) => {
+                for frame in frames.into_iter().filter_map(protocol::decode) {
+                    events.extend(self.receive_frame(connection, frame));
+                }
+            }
+            Some(frame) => events.extend(self.receive_frame(connection, frame)),
+            None => {}
+        }
+    }
+
+    fn receive_frame(&mut self, connection: Connection, frame: Frame) -> Option<NetworkEvent> {
+        // until a client presents a valid connect token, everything else it sends is ignored
+        if let Some(tokens) = &mut self.tokens {
+            if !tokens.is_authenticated(connection.addr) {
+                let auth = match frame {
+                    Frame::ConnectToken(token) => tokens
+                        .verify(connection.addr, token, SystemTime::now())
+                        .ok()?,
+                    _ => return None,
+                };
+
+                // the client hears from the server for the first time once it's accepted
+                let accepted = protocol::encode_connect_accepted().to_vec();
+                if let Ok(accepted) = self.encode(connection.addr, accepted) {
+                    self.outbox
+                        .push(Packet::reliable_unordered(connection.addr, accepted));
+                }
+
+                return Some(NetworkEvent::Connected(connection, Some(auth)));
+            }
+        }
+
+        match frame {
+            Frame::Ping => {
+                if let Ok(pong) = self.encode(connection.addr, protocol::encode_pong().to_vec()) {
+                    self.outbox.push(Packet::unreliable(connection.addr, pong));
+                }
+                None
+            }
+            // the peer has been heard from by now
+            Frame::Pong => None,
+            Frame::Message(message) => Some(NetworkEvent::Message(
+                connection,
+                Bytes::copy_from_slice(message),
+            )),
+            Frame::Chunk(header, data) => {
+                match self
+                    .reassembler
+                    .push(connection.addr, header, data, Instant::now())
+                {
+                    Reassembly::Progress { received, total } => {
+                        Some(NetworkEvent::TransferProgress {
+                            connection,
+                            transfer: TransferId(header.transfer),
+                            received,
+                            total,
+                        })
+                    }
+                    Reassembly::Complete(message) => {
+                        Some(NetworkEvent::Message(connection, message))
+                    }
+                    Reassembly::Rejected => None,
+                }
+            }
+            Frame::Tracked(id, message) => {
+                // acks are always sent reliably, since a lost ack would turn a delivered message
+                // into a lost one
+                let ack = protocol::encode_ack(id).to_vec();
+                if let Ok(ack) = self.encode(connection.addr, ack) {
+                    self.outbox
+                        .push(Packet::reliable_unordered(connection.addr, ack));
+                }
+
+                Some(NetworkEvent::Message(
+                    connection,
+                    Bytes::copy_from_slice(message),
+                ))
+            }
+            Frame::Ack(id) => {
+                let id = MessageId(id);
+
+                match self.deliveries.acknowledge(connection.addr, id) {
+                    true => Some(NetworkEvent::Delivered { id, connection }),
+                    false => None,
+                }
+            }
+            // batches can't be nested, and handshakes are handled before frames are decoded
+            Frame::Batch(_) | Frame::HandshakeInit(_) | Frame::HandshakeReply(..) => None,
+            // the client has already been accepted, or this socket doesn't check tokens
+            Frame::ConnectToken(_) | Frame::ConnectAccepted => None,
+            // challenges are handled before the rate limiter
+            Frame::ChallengeRequest
+            | Frame::Challenge(_)
+            | Frame::ChallengeResponse(_)
+            | Frame::ChallengeAccepted(_) => None,
+            // sessions are unwrapped, and punch-through and relaying are handled, as soon as a
+            // packet arrives
+            Frame::Session(..)
+            | Frame::RendezvousRegister(_)
+            | Frame::Introduce(_)
+            | Frame::Punch(_)
+            | Frame::PunchAck(_)
+            | Frame::RelayRegister(_)
+            | Frame::RelayPaired(..)
+            | Frame::RelayClosed(_)
+            | Frame::Relayed(..) => None,
+        }
+    }
+
+    fn connection(&self, addr: SocketAddr) -> Connection {
+        Connection {
+            addr,
+            socket: self.handle,
+            multicast: None,
+        }
+    }
 }
 
 impl TrackedSockets {
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<(SocketHandle, Socket)> {
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, TrackedSocket> {
         self.sockets.iter_mut()
     }
 
-    pub fn add_socket(&mut self, handle: SocketHandle, socket: Socket) {
-        if self.has_socket(handle) {
+    pub fn add_socket(&mut self, tracked: TrackedSocket) {
+        if self.has_socket(tracked.handle()) {
             // todo: communicate socket error back
             println!(
                 "Warning: attempted to add socket with an existing handle, dropping the new socket"
@@ -166,39 +1590,440 @@ impl TrackedSockets {
             return;
         }
 
-        self.sockets.push((handle, socket));
+        self.sockets.push(tracked);
     }
 
-    // pub fn close_socket(&mut self, handle: SocketHandle) {
-    //     let sock = self.sockets.iter().position(|(h, _)| *h == handle);
+    pub fn close_socket(&mut self, handle: SocketHandle) {
+        let sock = self.sockets.iter().position(|s| s.handle() == handle);
 
-    //     match sock {
-    //         Some(idx) => {
-    //             self.sockets.remove(idx);
-    //         }
-    //         None => {
-    //             println!("Warning: attempting to close a socket that doesn't exist.");
-    //         }
-    //     }
-    // }
+        match sock {
+            Some(idx) => {
+                self.sockets.remove(idx);
+            }
+            None => {
+                println!("Warning: attempting to close a socket that doesn't exist.");
+            }
+        }
+    }
 
     pub fn has_socket(&self, handle: SocketHandle) -> bool {
-        self.get_socket(handle).is_ok()
+        self.sockets.iter().any(|s| handle == s.handle())
     }
 
-    pub fn get_socket(&self, handle: SocketHandle) -> Result<&Socket, NetworkError> {
+    pub fn get_mut(&mut self, handle: SocketHandle) -> Result<&mut TrackedSocket, NetworkError> {
         self.sockets
-            .iter()
-            .find(|(h, _)| handle == *h)
-            .map(|(_, s)| s)
+            .iter_mut()
+            .find(|s| handle == s.handle())
             .ok_or(NetworkError::NoSocket(handle))
     }
+}
 
-    pub fn get_socket_mut(&mut self, handle: SocketHandle) -> Result<&mut Socket, NetworkError> {
-        self.sockets
-            .iter_mut()
-            .find(|(h, _)| handle == *h)
-            .map(|(_, s)| s)
-            .ok_or(NetworkError::NoSocket(handle))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::COOKIE_SIZE;
+    use crate::punch::punch_key;
+    use crate::RetryPolicy;
+
+    fn challenged() -> SocketState {
+        let config = LaminarConfig::builder()
+            .challenge_connections(true)
+            .build()
+            .unwrap();
+
+        SocketState::new(SocketHandle::new(), config, &[])
+    }
+
+    // hands a packet straight to the socket's state, as if laminar had received it from the address
+    fn receive(state: &mut SocketState, from: SocketAddr, payload: &[u8]) -> Vec<NetworkEvent> {
+        let mut events = Vec::new();
+        let packet = Packet::unreliable(from, payload.to_vec());
+        state.handle_event(SocketEvent::Packet(packet), &mut events);
+        events
+    }
+
+    fn deliver_outbox(
+        from: &mut SocketState,
+        addr: SocketAddr,
+        to: &mut SocketState,
+    ) -> Vec<NetworkEvent> {
+        std::mem::take(&mut from.outbox)
+            .into_iter()
+            .flat_map(|packet| receive(to, addr, packet.payload()))
+            .collect()
+    }
+
+    #[test]
+    fn spoofed_senders_are_ignored() {
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let victim = SocketAddr::from(([127, 0, 0, 1], 6000));
+        let message = protocol::encode_message(b"hello");
+
+        let mut server = challenged();
+        let mut client = challenged();
+
+        // nothing gets through before a challenge has been answered
+        assert!(receive(&mut server, victim, &message).is_empty());
+        assert!(server.outbox.is_empty());
+
+        // a spoofed request only sends the victim as much as the attacker sent
+        let request = protocol::encode_challenge_request();
+        assert!(receive(&mut server, victim, &request).is_empty());
+        let replies = std::mem::take(&mut server.outbox);
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].payload().len() <= request.len());
+
+        // and the cookie can't be guessed
+        let guess = protocol::encode_challenge_response(&[0; COOKIE_SIZE]);
+        assert!(receive(&mut server, victim, &guess).is_empty());
+        assert!(server.outbox.is_empty());
+        assert!(!server.challenge.as_ref().unwrap().is_verified(victim));
+
+        // a client that can receive at its address gets through
+        assert!(!client.ready_to_send(server_addr, Instant::now()));
+        assert!(deliver_outbox(&mut client, client_addr, &mut server).is_empty());
+        assert!(deliver_outbox(&mut server, server_addr, &mut client).is_empty());
+
+        // the server's acceptance has to echo the client's answer
+        let forged = protocol::encode_challenge_accepted(&[0; COOKIE_SIZE]);
+        assert!(receive(&mut client, server_addr, &forged).is_empty());
+
+        let events = deliver_outbox(&mut client, client_addr, &mut server);
+        assert!(matches!(&events[..], [NetworkEvent::Connected(c, None)] if c.addr == client_addr));
+        let events = deliver_outbox(&mut server, server_addr, &mut client);
+        assert!(matches!(&events[..], [NetworkEvent::Connected(_, None)]));
+        assert!(client.ready_to_send(server_addr, Instant::now()));
+
+        let events = receive(&mut server, client_addr, &message);
+        assert!(matches!(&events[..], [NetworkEvent::Message(_, msg)] if &msg[..] == b"hello"));
+    }
+
+    #[test]
+    fn retried_servers_reconnect_instead_of_disconnecting() {
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let mut client = SocketState::new(SocketHandle::new(), LaminarConfig::default(), &[]);
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_secs(0),
+            max_delay: Duration::from_secs(0),
+            max_attempts: Some(1),
+        };
+        let hello = Message {
+            id: MessageId(3),
+            message: protocol::encode_message(b"hello"),
+            delivery: NetworkDelivery::ReliableUnordered,
+            socket_handle: client.handle,
+            destination: server_addr,
+            track_delivery: false,
+            priority: SendPriority::High,
+        };
+        client.reconnect.add(hello, policy);
+
+        let mut events = Vec::new();
+        client.handle_event(SocketEvent::Timeout(server_addr), &mut events);
+        assert!(matches!(
+            &events[..],
+            [NetworkEvent::Reconnecting { attempt: 1, .. }]
+        ));
+        assert_eq!(client.reconnect.due(Instant::now()).len(), 1);
+
+        // the retry didn't get an answer either
+        let mut events = Vec::new();
+        client.handle_event(SocketEvent::Timeout(server_addr), &mut events);
+        assert!(matches!(
+            &events[..],
+            [
+                NetworkEvent::SendFailed {
+                    id: MessageId(3),
+                    error: NetworkError::ConnectFailed(_),
+                    ..
+                },
+                NetworkEvent::Disconnected(_)
+            ]
+        ));
+    }
+
+    struct Host {
+        state: SocketState,
+        public: SocketAddr,
+        // the addresses its NAT lets packets in from, or `None` if it isn't behind a NAT
+        opened: Option<HashSet<SocketAddr>>,
+    }
+
+    fn host(public: SocketAddr, behind_nat: bool) -> Host {
+        Host {
+            state: SocketState::new(SocketHandle::new(), LaminarConfig::default(), &[]),
+            public,
+            opened: match behind_nat {
+                true => Some(HashSet::new()),
+                false => None,
+            },
+        }
+    }
+
+    // Delivers the hosts' outboxes until they're all empty, returning how many packets the NATs
+    // dropped. A NAT only lets a packet in from an address its host has sent something to.
+    fn route(hosts: &mut [Host], events: &mut Vec<NetworkEvent>) -> usize {
+        let mut dropped = 0;
+
+        loop {
+            let mut sent = Vec::new();
+            for host in hosts.iter_mut() {
+                for packet in std::mem::take(&mut host.state.outbox) {
+                    let packet = host.state.via_relay(packet);
+                    if let Some(opened) = &mut host.opened {
+                        opened.insert(packet.addr());
+                    }
+                    sent.push((host.public, packet));
+                }
+            }
+
+            if sent.is_empty() {
+                return dropped;
+            }
+
+            for (from, packet) in sent {
+                let to = match hosts.iter_mut().find(|h| h.public == packet.addr()) {
+                    Some(to) => to,
+                    None => continue,
+                };
+                if to.opened.as_ref().is_some_and(|o| !o.contains(&from)) {
+                    dropped += 1;
+                    continue;
+                }
+
+                let packet = Packet::unreliable(from, packet.payload().to_vec());
+                to.state.handle_event(SocketEvent::Packet(packet), events);
+            }
+        }
+    }
+
+    #[test]
+    fn peers_punch_through_their_nats() {
+        let rendezvous_addr = SocketAddr::from(([203, 0, 113, 1], 4000));
+        let a_addr = SocketAddr::from(([198, 51, 100, 1], 5000));
+        let b_addr = SocketAddr::from(([198, 51, 100, 2], 6000));
+
+        // the rendezvous only introduces peers that have answered its challenge
+        let mut hosts = vec![
+            Host {
+                state: challenged(),
+                ..host(rendezvous_addr, false)
+            },
+            Host {
+                state: challenged(),
+                ..host(a_addr, true)
+            },
+            Host {
+                state: challenged(),
+                ..host(b_addr, true)
+            },
+        ];
+        hosts[0].state.rendezvous = Some(Rendezvous::new());
+        let (rendezvous, a, b) = (
+            hosts[0].state.handle,
+            hosts[1].state.handle,
+            hosts[2].state.handle,
+        );
+
+        // the peers can't reach each other directly
+        let mut events = Vec::new();
+        let hello = protocol::encode_message(b"hello").to_vec();
+        hosts[1]
+            .state
+            .outbox
+            .push(Packet::unreliable(b_addr, hello));
+        assert_eq!(route(&mut hosts, &mut events), 1);
+        assert!(events.is_empty());
+
+        let start = Instant::now();
+        let key = punch_key(b"lobby 7");
+        for host in hosts[1..].iter_mut() {
+            host.state
+                .puncher
+                .start(rendezvous_addr, key, Duration::from_secs(5), start);
+        }
+
+        // a registration before the challenge has been answered is ignored
+        let register = protocol::encode_rendezvous_register(&key);
+        assert!(receive(&mut hosts[0].state, a_addr, &register).is_empty());
+        assert!(hosts[0].state.outbox.is_empty());
+
+        for step in 0..5 {
+            let now = start + Duration::from_millis(600 * step);
+            for host in hosts.iter_mut() {
+                host.state.punch_holes(now, &mut events);
+            }
+            route(&mut hosts, &mut events);
+        }
+
+        // the rendezvous and the peers report each other as connections once they're challenged
+        let mut punched: Vec<(SocketHandle, SocketAddr)> = events
+            .iter()
+            .filter(|e| match e {
+                NetworkEvent::Connected(c, None) => {
+                    c.socket != rendezvous && c.addr != rendezvous_addr
+                }
+                _ => true,
+            })
+            .map(|e| match e {
+                NetworkEvent::PunchSucceeded(conn) => (conn.socket, conn.addr),
+                other => panic!("expected a punch-through, got {:?}", other),
+            })
+            .collect();
+        punched.sort_by_key(|(_, addr)| *addr);
+        assert_eq!(punched, vec![(b, a_addr), (a, b_addr)]);
+
+        // the hole stays open for the peers' own challenge, and then the game's packets
+        let now = start + Duration::from_secs(3);
+        assert!(!hosts[1].state.challenged(b_addr, now));
+        let mut events = Vec::new();
+        assert_eq!(route(&mut hosts, &mut events), 0);
+        assert!(hosts[1].state.challenged(b_addr, now));
+
+        let hello = protocol::encode_message(b"hello").to_vec();
+        hosts[1]
+            .state
+            .outbox
+            .push(Packet::unreliable(b_addr, hello));
+        let mut events = Vec::new();
+        assert_eq!(route(&mut hosts, &mut events), 0);
+        assert!(matches!(&events[..], [NetworkEvent::Message(c, _)] if c.addr == a_addr));
+    }
+
+    #[test]
+    fn relayed_peers_look_like_ordinary_connections() {
+        let relay_addr = SocketAddr::from(([203, 0, 113, 1], 4000));
+        let a_addr = SocketAddr::from(([198, 51, 100, 1], 5000));
+        let b_addr = SocketAddr::from(([198, 51, 100, 2], 6000));
+
+        // the relay only pairs peers that have answered its challenge
+        let mut hosts = vec![
+            Host {
+                state: challenged(),
+                ..host(relay_addr, false)
+            },
+            Host {
+                state: challenged(),
+                ..host(a_addr, true)
+            },
+            Host {
+                state: challenged(),
+                ..host(b_addr, true)
+            },
+        ];
+        hosts[0].state.relay = Some(RelayServer::new());
+        let (relay, a, b) = (
+            hosts[0].state.handle,
+            hosts[1].state.handle,
+            hosts[2].state.handle,
+        );
+
+        let start = Instant::now();
+        let key = punch_key(b"lobby 7");
+        for host in hosts[1..].iter_mut() {
+            host.state
+                .relayed
+                .start(relay_addr, key, Duration::from_secs(5), start);
+        }
+
+        // a registration before the challenge has been answered is ignored
+        let register = protocol::encode_relay_register(&key);
+        assert!(receive(&mut hosts[0].state, a_addr, &register).is_empty());
+        assert!(hosts[0].state.outbox.is_empty());
+
+        let mut events = Vec::new();
+        for step in 0..3 {
+            let now = start + Duration::from_millis(600 * step);
+            for host in hosts.iter_mut() {
+                host.state.register_relays(now, &mut events);
+            }
+            route(&mut hosts, &mut events);
+        }
+
+        let mut connected: Vec<(SocketHandle, SocketAddr)> = events
+            .iter()
+            .map(|e| match e {
+                NetworkEvent::Connected(conn, None) => (conn.socket, conn.addr),
+                other => panic!("expected a connection, got {:?}", other),
+            })
+            .filter(|(socket, addr)| *socket != relay && *addr != relay_addr)
+            .collect();
+        connected.sort_by_key(|(_, addr)| *addr);
+        assert_eq!(connected, vec![(b, a_addr), (a, b_addr)]);
+
+        // packets for the peer go to the relay, and it forwards them with the same delivery
+        let delivery = NetworkDelivery::ReliableOrdered(Some(2));
+        let hello = protocol::encode_message(b"hello").to_vec();
+        let wrapped = hosts[1].state.via_relay(packet(b_addr, delivery, hello));
+        assert_eq!(wrapped.addr(), relay_addr);
+        assert_eq!(delivery_of(&wrapped), delivery);
+
+        assert!(receive(&mut hosts[0].state, a_addr, wrapped.payload()).is_empty());
+        let forwarded = &hosts[0].state.outbox[0];
+        assert_eq!(forwarded.addr(), b_addr);
+        assert_eq!(delivery_of(forwarded), delivery);
+
+        let mut events = Vec::new();
+        assert_eq!(route(&mut hosts, &mut events), 0);
+        assert!(matches!(
+            &events[..],
+            [NetworkEvent::Message(c, msg)] if c.addr == a_addr && &msg[..] == b"hello"
+        ));
+
+        // the relay can't be used to reach anyone else
+        let stray = protocol::encode_relayed(relay_addr, delivery, b"stray");
+        assert!(receive(&mut hosts[0].state, a_addr, &stray).is_empty());
+        assert!(hosts[0].state.outbox.is_empty());
+
+        // when one peer times out at the relay, the other is disconnected from it
+        let mut events = Vec::new();
+        hosts[0]
+            .state
+            .handle_event(SocketEvent::Timeout(b_addr), &mut Vec::new());
+        route(&mut hosts, &mut events);
+        assert!(matches!(
+            &events[..],
+            [NetworkEvent::Disconnected(c)] if c.socket == a && c.addr == b_addr
+        ));
+        assert_eq!(hosts[1].state.relayed.relay_for(b_addr), None);
+    }
+
+    #[test]
+    fn connections_move_with_their_session() {
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let before = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let after = SocketAddr::from(([127, 0, 0, 1], 5001));
+
+        let config = LaminarConfig::builder()
+            .migrate_connections(true)
+            .build()
+            .unwrap();
+        let mut server = SocketState::new(SocketHandle::new(), config.clone(), &[]);
+        let mut client = SocketState::new(SocketHandle::new(), config, &[]);
+
+        let hello = protocol::encode_message(b"hello").to_vec();
+        let hello = client.encode(server_addr, hello).unwrap();
+        let events = receive(&mut server, before, &hello);
+        assert!(matches!(
+            &events[..],
+            [NetworkEvent::Connected(c, None), NetworkEvent::Message(..)] if c.addr == before
+        ));
+
+        // the client's NAT gives it a new address
+        let again = protocol::encode_message(b"again").to_vec();
+        let again = client.encode(server_addr, again).unwrap();
+        let events = receive(&mut server, after, &again);
+        assert!(matches!(
+            &events[..],
+            [NetworkEvent::Reconnected { old, new }, NetworkEvent::Message(c, _)]
+                if old.addr == before && new.addr == after && c.addr == after
+        ));
+
+        // late packets from the old address are dropped, and its timeout isn't a disconnection
+        assert!(receive(&mut server, before, &hello).is_empty());
+        let mut events = Vec::new();
+        server.handle_event(SocketEvent::Timeout(before), &mut events);
+        assert!(events.is_empty());
     }
 }